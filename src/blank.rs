@@ -0,0 +1,142 @@
+//! Checking that a device was really erased (--verify-blank).
+//!
+//! After an external secure erase or sanitize, every block of a device
+//! should read back blank. Some drives claim to have erased themselves
+//! without doing so, so this reads the whole device and reports where
+//! data is left. A block counts as blank if it's all zeroes, or all 0xff
+//! bytes like erased flash often reads. This never writes to the device.
+
+use crate::{
+    device_error::{DeviceIoError, Operation},
+    events::ProgressEvents,
+    target::Target,
+    PROGRESS_STYLE,
+};
+use anyhow::Context;
+use serde::Serialize;
+use std::{fs::OpenOptions, path::Path};
+use tracing::{info_span, warn};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+/// How many regions of residual data get listed; the rest are only counted.
+const MAX_REGIONS: usize = 1000;
+
+/// The data that was left on a device that should be blank.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct ResidualData {
+    /// How many bytes of the device are in blocks that aren't blank.
+    pub bytes: u64,
+    /// How many blocks aren't blank.
+    pub blocks: u64,
+    /// The first [MAX_REGIONS] runs of non-blank blocks, as offsets and
+    /// lengths in bytes.
+    pub regions: Vec<(u64, u64)>,
+    /// More regions were found than are listed.
+    pub regions_truncated: bool,
+}
+
+impl ResidualData {
+    fn add(&mut self, offset: u64, len: u64) {
+        self.bytes += len;
+        self.blocks += 1;
+        if let Some((start, length)) = self.regions.last_mut() {
+            if *start + *length == offset {
+                *length += len;
+                return;
+            }
+        }
+        if self.regions.len() < MAX_REGIONS {
+            self.regions.push((offset, len));
+        } else {
+            self.regions_truncated = true;
+        }
+    }
+}
+
+/// Reads the first `capacity` bytes of the device (or all of it), and
+/// finds the blocks that aren't blank. Returns the number of bytes read.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity), fields(device = ?dev_path))]
+pub(crate) fn verify_blank(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+) -> anyhow::Result<(u64, ResidualData)> {
+    let blockdev = OpenOptions::new()
+        .read(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for reading", dev_path))?;
+    verify_blank_in(&blockdev, buffer_size, capacity)
+}
+
+/// Like [verify_blank], but on any [Target].
+pub(crate) fn verify_blank_in(
+    target: &dyn Target,
+    buffer_size: usize,
+    capacity: Option<u64>,
+) -> anyhow::Result<(u64, ResidualData)> {
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => target.len()?,
+    };
+
+    let bar_span = info_span!("verifying blank");
+    bar_span.pb_set_style(&PROGRESS_STYLE);
+    bar_span.pb_set_length(capacity);
+    let _bar_span_handle = bar_span.enter();
+
+    let events = ProgressEvents::new("read", capacity, 0);
+    let mut residual = ResidualData::default();
+    let mut buf = vec![0; buffer_size];
+    let mut offset = 0;
+    while offset < capacity {
+        let len = (capacity - offset).min(buffer_size as u64) as usize;
+        let read = target
+            .read_at(&mut buf[..len], offset)
+            .map_err(|e| DeviceIoError::new(Operation::Read, offset, e))?;
+        if read == 0 {
+            anyhow::bail!(
+                "The device ended after {} bytes, before the capacity of {} bytes could be verified",
+                offset,
+                capacity
+            );
+        }
+        let block = &buf[..read];
+        if !(block.iter().all(|&b| b == 0) || block.iter().all(|&b| b == 0xff)) {
+            if residual.blocks == 0 {
+                warn!(offset, "Found data on a device that should be blank");
+            }
+            residual.add(offset, read as u64);
+        }
+        offset += read as u64;
+        events.inc(read as u64);
+    }
+    Ok((offset, residual))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::target::MemoryTarget;
+    use tracing_test::traced_test;
+
+    #[traced_test]
+    #[test]
+    fn finds_residual_data() {
+        let target = MemoryTarget::new(4096 * 16);
+        target.with_data(|data| data[4096..8192].fill(0xff));
+        let (read, residual) = verify_blank_in(&target, 4096, None).expect("No io errors");
+        assert_eq!(read, 4096 * 16);
+        assert_eq!(residual, ResidualData::default());
+
+        target.with_data(|data| {
+            data[8192 + 1] = 1;
+            data[4096 * 3 + 10] = 7;
+            data[4096 * 10] = 0xaa;
+        });
+        let (_, residual) = verify_blank_in(&target, 4096, None).expect("No io errors");
+        assert_eq!(residual.bytes, 4096 * 3);
+        assert_eq!(residual.blocks, 3);
+        assert_eq!(residual.regions, vec![(8192, 8192), (4096 * 10, 4096)]);
+        assert!(logs_contain("should be blank"));
+    }
+}
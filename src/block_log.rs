@@ -0,0 +1,252 @@
+//! Logging the outcome of every block to a file (--block-log).
+//!
+//! The final report only lists the bad blocks, and only once the test is
+//! done. With `--block-log FILE`, every block of the sequential write and
+//! of the read-back (including --reverse-read and the second reads of
+//! --verify-twice) gets a line of CSV in FILE as soon as it's done, so the
+//! data can be graphed or diffed between runs, and a crash still leaves
+//! everything up to the crash on disk.
+//!
+//! The rest of the I/O isn't logged: --random-write-order, --churn,
+//! --layout-spec, --verify-blank, --sanity-reads, and the probes of
+//! --intermediate-verify sample, --probe-initial-state and
+//! --estimate-only. The columns are:
+//!
+//! * `device`: the path of the device.
+//! * `phase`: `write` or `read`.
+//! * `offset` and `length` of the block, in bytes. Reads are logged in
+//!   blocks of --buffer-size, writes as they were issued.
+//! * `result`: `ok`, `bad` (it didn't read back as written) or `error`
+//!   (the I/O failed, which ends the test).
+//! * `latency_us`: how long the I/O for the block took, in microseconds.
+//!   Reads are shared out among the blocks they returned.
+//! * `retries`: 0, or 1 when a bad block was read again with
+//!   --verify-twice, which gets it a second line.
+//!
+//! Lines from several devices are interleaved, in the order they happen.
+
+use anyhow::Context as _;
+use std::{
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tracing::{field::Field, span, Event, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// The target of the per-block events, which only go to the block log.
+pub(crate) const BLOCK_TARGET: &str = "disk_spinner::block";
+
+const HEADER: &str = "device,phase,offset,length,result,latency_us,retries\n";
+
+/// Whether a block log was created, so that the hot loops can skip timing
+/// and logging every block when there is none.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Logs the outcome of a block, if there is a block log.
+pub(crate) fn record(
+    phase: &'static str,
+    offset: u64,
+    length: u64,
+    result: &'static str,
+    latency: Duration,
+    retries: u64,
+) {
+    if !enabled() {
+        return;
+    }
+    tracing::info!(
+        target: BLOCK_TARGET,
+        phase,
+        offset,
+        length,
+        result,
+        latency_us = latency.as_micros() as u64,
+        retries
+    );
+}
+
+/// A [Layer] that writes the per-block events as lines of CSV.
+#[derive(Debug)]
+pub(crate) struct BlockLogLayer {
+    out: Mutex<BufWriter<File>>,
+}
+
+impl BlockLogLayer {
+    /// Creates (or truncates) the block log at `path`, and writes its header.
+    pub(crate) fn create(path: &Path) -> anyhow::Result<Self> {
+        let mut file =
+            File::create(path).with_context(|| format!("Creating the block log {:?}", path))?;
+        file.write_all(HEADER.as_bytes())
+            .with_context(|| format!("Writing to the block log {:?}", path))?;
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(Self {
+            out: Mutex::new(BufWriter::new(file)),
+        })
+    }
+}
+
+/// The `device` field of a span.
+#[derive(Debug)]
+struct DeviceField(String);
+
+/// The fields of a per-block event, or the `device` field of a span.
+#[derive(Debug, Default)]
+struct BlockFields {
+    device: Option<String>,
+    phase: String,
+    offset: u64,
+    length: u64,
+    result: String,
+    latency_us: u64,
+    retries: u64,
+}
+
+impl tracing::field::Visit for BlockFields {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "offset" => self.offset = value,
+            "length" => self.length = value,
+            "latency_us" => self.latency_us = value,
+            "retries" => self.retries = value,
+            _ => {}
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "phase" => self.phase = value.to_string(),
+            "result" => self.result = value.to_string(),
+            "device" => self.device = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "device" {
+            // Paths are logged with `?`, which quotes them:
+            let value = format!("{:?}", value);
+            let value = serde_json::from_str::<String>(&value).unwrap_or(value);
+            self.device = Some(value);
+        }
+    }
+}
+
+impl<S> Layer<S> for BlockLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = BlockFields::default();
+        attrs.record(&mut fields);
+        if let (Some(device), Some(span)) = (fields.device, ctx.span(id)) {
+            span.extensions_mut().insert(DeviceField(device));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if event.metadata().target() != BLOCK_TARGET {
+            return;
+        }
+        let mut fields = BlockFields::default();
+        event.record(&mut fields);
+        let device = ctx
+            .event_scope(event)
+            .and_then(|scope| {
+                scope
+                    .filter_map(|span| span.extensions().get::<DeviceField>().map(|d| d.0.clone()))
+                    .next()
+            })
+            .unwrap_or_default();
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        // Like for events, there is nowhere to report write errors to.
+        let _ = writeln!(
+            out,
+            "{},{},{},{},{},{},{}",
+            device,
+            fields.phase,
+            fields.offset,
+            fields.length,
+            fields.result,
+            fields.latency_us,
+            fields.retries
+        );
+        // Flushing every line would cost a syscall per block, so only the
+        // notable ones are flushed right away; see also on_close.
+        if fields.result != "ok" {
+            let _ = out.flush();
+        }
+    }
+
+    fn on_close(&self, _id: span::Id, _ctx: Context<'_, S>) {
+        // Spans close at the end of each phase, and the subscriber is
+        // never dropped, so this is what gets the last lines to disk.
+        let _ = self.out.lock().unwrap_or_else(|e| e.into_inner()).flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        read_test::read_back_from, target::MemoryTarget, test_util::sparse_file,
+        write_test::write_to,
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn logs_blocks() {
+        let path = sparse_file("block-log", 0);
+        let subscriber = tracing_subscriber::registry().with(BlockLogLayer::create(&path).unwrap());
+        let target = MemoryTarget::new(4096 * 4);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("test_device", device = ?Path::new("/dev/sda"));
+            let _handle = span.enter();
+            write_to(&target, 4096, None, 1.into(), 0, None, None).expect("No io errors");
+            target.with_data(|data| data[4096 * 2 + 1] ^= 1);
+            let (_, result) = read_back_from(&target, 4096, None, 1.into(), None, None, None)
+                .expect("No io errors");
+            assert!(result.is_err());
+        });
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Vec<&str>> = contents
+            .lines()
+            .map(|line| line.split(',').collect())
+            .collect();
+        assert_eq!(lines[0][..3], ["device", "phase", "offset"]);
+        assert!(lines[1..].iter().all(|line| line[0] == "/dev/sda"));
+        // Writes are logged as they are issued, however large they are:
+        let writes: Vec<_> = lines.iter().filter(|line| line[1] == "write").collect();
+        let written: u64 = writes
+            .iter()
+            .map(|line| line[3].parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(written, 4096 * 4);
+        assert!(writes.iter().all(|line| line[4] == "ok"));
+        let reads: Vec<(&str, &str, &str)> = lines
+            .iter()
+            .filter(|line| line[1] == "read")
+            .map(|line| (line[2], line[4], line[6]))
+            .collect();
+        assert_eq!(
+            reads,
+            [
+                ("0", "ok", "0"),
+                ("4096", "ok", "0"),
+                ("8192", "bad", "0"),
+                ("12288", "ok", "0")
+            ]
+        );
+    }
+}
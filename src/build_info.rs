@@ -0,0 +1,47 @@
+//! What build of disk-spinner produced a report.
+//!
+//! Results can differ between versions, so every report records the
+//! version, the git commit it was built from (with a "-dirty" suffix if
+//! it had local changes), and the build features that matter to it.
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct BuildInfo {
+    pub version: &'static str,
+    /// The short git commit hash, or "unknown" outside of a git checkout.
+    pub git_commit: &'static str,
+    pub target: &'static str,
+    pub debug_build: bool,
+    /// AES-NI was enabled at compile time (e.g. with -C target-cpu=native).
+    pub aes_ni_compiled_in: bool,
+    /// AES-NI is available at runtime, where the AES implementation
+    /// detects and uses it even if it wasn't enabled at compile time.
+    pub aes_ni_available: bool,
+}
+
+impl BuildInfo {
+    pub(crate) fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("DISK_SPINNER_GIT_COMMIT"),
+            target: env!("DISK_SPINNER_TARGET"),
+            debug_build: cfg!(debug_assertions),
+            aes_ni_compiled_in: cfg!(all(
+                any(target_arch = "x86", target_arch = "x86_64"),
+                target_feature = "aes"
+            )),
+            aes_ni_available: aes_ni_available(),
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn aes_ni_available() -> bool {
+    std::arch::is_x86_feature_detected!("aes")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn aes_ni_available() -> bool {
+    false
+}
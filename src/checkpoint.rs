@@ -0,0 +1,260 @@
+//! Persisting how far the write test got, so it can be resumed.
+//!
+//! Checkpoints are small sidecar files that are written periodically
+//! during the write test. They are replaced atomically (by writing to a
+//! temporary file and renaming it), so even an unclean kill leaves a
+//! usable checkpoint behind.
+
+use crate::{block_log, crypto::Seed, target::Target};
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use tracing::debug;
+
+/// How many bytes get written between two checkpoints.
+pub(crate) const CHECKPOINT_INTERVAL: u64 = 4 * 1024 * 1024 * 1024;
+
+/// The path of a file in `dir` that belongs to a device, like its
+/// checkpoint, with the given extension.
+///
+/// Files are named after the device's serial number where one is known,
+/// so they stay valid when device paths change between boots.
+pub(crate) fn sidecar_path(
+    dir: &Path,
+    dev_path: &Path,
+    serial: Option<&str>,
+    partition: Option<u64>,
+    extension: &str,
+) -> PathBuf {
+    let name = sidecar_name(dev_path, serial, partition);
+    dir.join(format!("{}{}.{}", SIDECAR_PREFIX, name, extension))
+}
+
+/// What the names of all sidecar files start with.
+pub(crate) const SIDECAR_PREFIX: &str = "disk-spinner-";
+
+/// The part of a sidecar file name that identifies the device.
+pub(crate) fn sidecar_name(
+    dev_path: &Path,
+    serial: Option<&str>,
+    partition: Option<u64>,
+) -> String {
+    let mut name = match serial {
+        Some(serial) => serial.to_string(),
+        None => dev_path.to_string_lossy().into_owned(),
+    };
+    if let Some(partition) = partition {
+        name = format!("{}-part{}", name, partition);
+    }
+    name.trim_start_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// The state of a write test at some point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Checkpoint {
+    pub seed: Seed,
+    pub capacity: u64,
+    pub buffer_size: usize,
+    /// All bytes before this offset have been written and synced to the device.
+    pub offset: u64,
+    /// The size the device itself reported when the checkpoint was written,
+    /// if known. Older checkpoints don't record it.
+    pub device_capacity: Option<u64>,
+}
+
+impl Checkpoint {
+    /// The path of the checkpoint file for a device in `dir`.
+    ///
+    /// Checkpoints are named after the device's serial number where one
+    /// is known, so they stay valid when device paths change between boots.
+    pub(crate) fn path_for(
+        dir: &Path,
+        dev_path: &Path,
+        serial: Option<&str>,
+        partition: Option<u64>,
+    ) -> PathBuf {
+        sidecar_path(dir, dev_path, serial, partition, "checkpoint")
+    }
+
+    /// Loads a checkpoint, returning `None` if there is none.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Reading checkpoint {:?}", path)),
+        };
+        let fields: HashMap<&str, &str> = contents
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .collect();
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("Checkpoint {:?} has no {:?} field", path, name))
+        };
+        Ok(Some(Self {
+            seed: field("seed")?.parse()?,
+            capacity: field("capacity")?.parse()?,
+            buffer_size: field("buffer_size")?.parse()?,
+            offset: field("offset")?.parse()?,
+            device_capacity: fields
+                .get("device_capacity")
+                .map(|value| value.parse())
+                .transpose()?,
+        }))
+    }
+
+    /// Atomically replaces the checkpoint at `path`.
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = path.with_extension("checkpoint.tmp");
+        let mut contents = format!(
+            "seed={}\ncapacity={}\nbuffer_size={}\noffset={}\n",
+            self.seed, self.capacity, self.buffer_size, self.offset
+        );
+        if let Some(device_capacity) = self.device_capacity {
+            contents += &format!("device_capacity={}\n", device_capacity);
+        }
+        fs::write(&tmp_path, contents)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        debug!(?path, offset = self.offset, "saved checkpoint");
+        Ok(())
+    }
+}
+
+/// An [io::Write] for the device under test that saves a checkpoint
+/// every [CHECKPOINT_INTERVAL] bytes, if it has a path to save it to.
+#[derive(Debug)]
+pub(crate) struct CheckpointWriter<'a> {
+    out: &'a dyn Target,
+    path: Option<PathBuf>,
+    state: Checkpoint,
+    last_saved: u64,
+}
+
+impl<'a> CheckpointWriter<'a> {
+    pub(crate) fn new(out: &'a dyn Target, path: Option<PathBuf>, state: Checkpoint) -> Self {
+        let last_saved = state.offset;
+        Self {
+            out,
+            path,
+            state,
+            last_saved,
+        }
+    }
+
+    /// The offset on the device that the next write goes to.
+    pub(crate) fn offset(&self) -> u64 {
+        self.state.offset
+    }
+
+    /// Syncs all written data to the device and records the current offset.
+    pub(crate) fn save(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            self.out.sync()?;
+            self.state.save(path)?;
+            self.last_saved = self.state.offset;
+        }
+        Ok(())
+    }
+}
+
+impl io::Write for CheckpointWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let started = block_log::enabled().then(Instant::now);
+        let written = match self.out.write_at(buf, self.state.offset) {
+            Ok(written) => written,
+            // Running out of space is how the write test normally ends:
+            Err(e) if e.raw_os_error() == Some(28) || e.kind() == io::ErrorKind::WriteZero => {
+                return Err(e)
+            }
+            Err(e) => {
+                let latency = started.map(|s| s.elapsed()).unwrap_or_default();
+                block_log::record(
+                    "write",
+                    self.state.offset,
+                    buf.len() as u64,
+                    "error",
+                    latency,
+                    0,
+                );
+                return Err(e);
+            }
+        };
+        if let Some(started) = started {
+            block_log::record(
+                "write",
+                self.state.offset,
+                written as u64,
+                "ok",
+                started.elapsed(),
+                0,
+            );
+        }
+        self.state.offset += written as u64;
+        if self.state.offset - self.last_saved >= CHECKPOINT_INTERVAL {
+            self.save()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrips() {
+        let dir = std::env::temp_dir();
+        let path = Checkpoint::path_for(
+            &dir,
+            Path::new(&format!("/dev/test{}", std::process::id())),
+            None,
+            Some(2),
+        );
+        assert!(path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .ends_with("-part2.checkpoint"));
+        assert_eq!(Checkpoint::load(&path).unwrap(), None);
+
+        let checkpoint = Checkpoint {
+            seed: 42.into(),
+            capacity: 1 << 40,
+            buffer_size: 4096,
+            offset: 1 << 33,
+            device_capacity: Some(1 << 41),
+        };
+        checkpoint.save(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap(), Some(checkpoint));
+        fs::write(
+            &path,
+            "seed=42\ncapacity=4096\nbuffer_size=4096\noffset=0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Checkpoint::load(&path).unwrap().unwrap().device_capacity,
+            None
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+//! Writing the device with interleaved reads and overwrites (--churn).
+//!
+//! Instead of one sequential write, the device is written a window of
+//! [WINDOW_BLOCKS] blocks at a time. Each window goes through this
+//! sequence, verifying the whole window after every write:
+//!
+//! 1. write the garbage,
+//! 2. overwrite it with its bitwise inverse,
+//! 3. write the garbage again.
+//!
+//! Flipping every bit of freshly written blocks while they are likely
+//! still in the drive's cache, and then flipping them back, exercises the
+//! controller's caching and remapping much more than a clean write. The
+//! device ends up holding the same data as after a normal write, so the
+//! read-back test runs as usual afterwards.
+
+use crate::{
+    crypto::{GarbageGenerator, Seed},
+    device_error::{DeviceIoError, Operation},
+    events::ProgressEvents,
+    idle,
+    target::{Cursor, Target},
+    PROGRESS_STYLE,
+};
+use anyhow::Context;
+use std::{
+    fs::OpenOptions,
+    io::{Read, Write},
+    path::Path,
+};
+use tracing::{debug, info_span, warn};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+/// How many blocks are churned at a time.
+const WINDOW_BLOCKS: u64 = 16;
+
+/// The steps each window goes through, and what the window should hold
+/// after each of them.
+const STEPS: [(&str, bool); 3] = [
+    ("write", false),
+    ("overwrite with inverse", true),
+    ("rewrite", false),
+];
+
+/// Churns the first `capacity` bytes of the device (or all of it).
+///
+/// Returns the number of bytes churned, and the offsets of the blocks
+/// that didn't read back as expected after some step.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity, seed), fields(device = ?dev_path))]
+pub(crate) fn churn(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+) -> anyhow::Result<(u64, Vec<u64>)> {
+    let out = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for churning", dev_path))?;
+    churn_target(&out, buffer_size, capacity, seed)
+}
+
+/// Like [churn], but on any [Target].
+pub(crate) fn churn_target(
+    target: &dyn Target,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+) -> anyhow::Result<(u64, Vec<u64>)> {
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => target.len()?,
+    };
+    if capacity == 0 {
+        anyhow::bail!("Could not determine the capacity to churn - pass --capacity.");
+    }
+
+    let bar_span = info_span!("churning");
+    bar_span.pb_set_style(&PROGRESS_STYLE);
+    bar_span.pb_set_length(capacity);
+    let _bar_span_handle = bar_span.enter();
+
+    let events = ProgressEvents::new("churn", capacity, 0);
+    let _idle_watch = idle::Watch::start("churn");
+    let block_size = buffer_size as u64;
+    let window_size = block_size * WINDOW_BLOCKS;
+    let mut generator = GarbageGenerator::new(buffer_size, seed, |_| {});
+    let mut expected = vec![0; window_size as usize];
+    let mut actual = vec![0; window_size as usize];
+    let mut bad_offsets = Vec::new();
+    let mut offset = 0;
+    while offset < capacity {
+        let len = (capacity - offset).min(window_size) as usize;
+        let (expected, actual) = (&mut expected[..len], &mut actual[..len]);
+        for (i, (step, inverted)) in STEPS.into_iter().enumerate() {
+            generator.seek(offset);
+            generator.fill(expected);
+            if inverted {
+                expected.iter_mut().for_each(|b| *b = !*b);
+            }
+            Cursor::new(target, offset)
+                .write_all(expected)
+                .map_err(|e| DeviceIoError::new(Operation::Write, offset, e))?;
+            // Otherwise the read would be served from the page cache, which
+            // only drops pages once they are written back to the device:
+            target
+                .sync()
+                .map_err(|e| DeviceIoError::new(Operation::Write, offset, e))?;
+            if let Err(e) = target.drop_cache(offset, len as u64) {
+                debug!(offset, error = %e, "Could not drop the cache before reading the window");
+            }
+            Cursor::new(target, offset)
+                .read_exact(actual)
+                .map_err(|e| DeviceIoError::new(Operation::Read, offset, e))?;
+            for (block, (expected, actual)) in expected
+                .chunks(buffer_size)
+                .zip(actual.chunks(buffer_size))
+                .enumerate()
+            {
+                if expected == actual {
+                    continue;
+                }
+                let block_offset = offset + block as u64 * block_size;
+                warn!(
+                    event = "bad_block",
+                    offset = block_offset,
+                    step,
+                    sequence = STEPS[..=i]
+                        .iter()
+                        .map(|(step, _)| *step)
+                        .collect::<Vec<_>>()
+                        .join(", then "),
+                    "Found a block that didn't read back as written while churning"
+                );
+                bad_offsets.push(block_offset);
+            }
+        }
+        // A block that failed after several steps is still one bad block:
+        bad_offsets.sort_unstable();
+        bad_offsets.dedup();
+        offset += len as u64;
+        events.inc(len as u64);
+    }
+    target.sync().context("Syncing the churned device")?;
+    Ok((capacity, bad_offsets))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{read_test::read_back_from, target::MemoryTarget};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tracing_test::traced_test;
+
+    #[traced_test]
+    #[test]
+    fn churns() {
+        let target = MemoryTarget::new(4096 * 40);
+        let (bytes, bad) = churn_target(&target, 4096, None, 3.into()).expect("No io errors");
+        assert_eq!(bytes, 4096 * 40);
+        assert!(bad.is_empty());
+        let (_, result) =
+            read_back_from(&target, 4096, None, 3.into(), None, None, None).expect("No io errors");
+        assert!(result.is_ok());
+    }
+
+    /// A target with a stuck bit, which reads as 0 whatever is written.
+    #[derive(Debug)]
+    struct StuckBit(MemoryTarget, u64);
+
+    impl Target for StuckBit {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+            let n = self.0.read_at(buf, offset)?;
+            if (offset..offset + n as u64).contains(&self.1) {
+                buf[(self.1 - offset) as usize] &= 0xfe;
+            }
+            Ok(n)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+            self.0.write_at(buf, offset)
+        }
+
+        fn len(&self) -> std::io::Result<u64> {
+            self.0.len()
+        }
+
+        fn sync(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[traced_test]
+    #[test]
+    fn finds_stuck_bits() {
+        let target = StuckBit(MemoryTarget::new(4096 * 40), 4096 * 20 + 7);
+        let (_, bad) = churn_target(&target, 4096, None, 3.into()).expect("No io errors");
+        assert_eq!(bad, vec![4096 * 20]);
+        assert!(logs_contain("overwrite with inverse"));
+    }
+
+    /// A target that serves reads from a cache of what was written, until
+    /// the cache is dropped, like the page cache in front of a device.
+    #[derive(Debug)]
+    struct Cached(StuckBit, MemoryTarget, AtomicBool);
+
+    impl Target for Cached {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+            match self.2.load(Ordering::Relaxed) {
+                true => self.1.read_at(buf, offset),
+                false => self.0.read_at(buf, offset),
+            }
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+            self.2.store(true, Ordering::Relaxed);
+            self.1.write_at(buf, offset)?;
+            self.0.write_at(buf, offset)
+        }
+
+        fn len(&self) -> std::io::Result<u64> {
+            self.0.len()
+        }
+
+        fn sync(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn drop_cache(&self, _offset: u64, _len: u64) -> std::io::Result<()> {
+            self.2.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[traced_test]
+    #[test]
+    fn reads_past_the_cache() {
+        let disk = StuckBit(MemoryTarget::new(4096 * 40), 4096 * 20 + 7);
+        let target = Cached(disk, MemoryTarget::new(4096 * 40), AtomicBool::new(false));
+        let (_, bad) = churn_target(&target, 4096, None, 3.into()).expect("No io errors");
+        assert_eq!(bad, vec![4096 * 20]);
+    }
+}
@@ -0,0 +1,164 @@
+//! Listing and removing leftover state files (the `clean` subcommand).
+//!
+//! Checkpoints and --preserve images are sidecar files named after their
+//! device (see [checkpoint::sidecar_path]). They are normally removed
+//! once a test finishes, but an interrupted run leaves them behind, and a
+//! stale checkpoint makes a later --resume start in the wrong place.
+//!
+//! This only ever removes sidecar files, never anything on a device.
+//! Images are skipped unless asked for, as they may hold the only copy of
+//! a device's data.
+
+use crate::{checkpoint, ValidDevice};
+use anyhow::Context;
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+/// The extensions of the state files, and whether they are images.
+const STATE_FILES: [(&str, bool); 4] = [
+    ("checkpoint", false),
+    ("checkpoint.tmp", false),
+    ("img", true),
+    ("img.manifest", true),
+];
+
+/// Lists the state files in `dir` of the given devices (or of all
+/// devices), and removes them once confirmed.
+pub(crate) fn run(
+    dir: &Path,
+    devices: &[ValidDevice],
+    include_images: bool,
+    yes: bool,
+) -> anyhow::Result<()> {
+    let names: Vec<String> = devices
+        .iter()
+        .map(|d| {
+            let serial = d.device.as_ref().and_then(|d| d.serial_number.as_deref());
+            checkpoint::sidecar_name(&d.path, serial, d.partition)
+        })
+        .collect();
+    let files = find_state_files(dir, &names)
+        .with_context(|| format!("Listing the state files in {:?}", dir))?;
+    let (images, others): (Vec<_>, Vec<_>) = files.into_iter().partition(|(_, image)| *image);
+    let mut to_remove: Vec<PathBuf> = others.into_iter().map(|(path, _)| path).collect();
+    for (image, _) in images {
+        if include_images {
+            to_remove.push(image);
+        } else {
+            println!(
+                "Keeping {:?}: it may hold the only copy of a device's data (pass --include-images to remove it).",
+                image
+            );
+        }
+    }
+    if to_remove.is_empty() {
+        println!("No disk-spinner state files to remove in {:?}.", dir);
+        return Ok(());
+    }
+    for path in &to_remove {
+        println!("{}", path.display());
+    }
+    if !yes && !confirm(&format!("Remove these {} files?", to_remove.len()))? {
+        println!("Nothing removed.");
+        return Ok(());
+    }
+    for path in &to_remove {
+        fs::remove_file(path).with_context(|| format!("Removing {:?}", path))?;
+    }
+    println!("Removed {} files.", to_remove.len());
+    Ok(())
+}
+
+/// Finds the state files in `dir` that belong to the devices with the
+/// given sidecar names (or to any device if there are none), along with
+/// whether each of them is an image.
+fn find_state_files(dir: &Path, names: &[String]) -> io::Result<Vec<(PathBuf, bool)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(rest) = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix(checkpoint::SIDECAR_PREFIX))
+        else {
+            continue;
+        };
+        let Some((name, image)) = STATE_FILES.iter().find_map(|(extension, image)| {
+            let name = rest.strip_suffix(extension)?.strip_suffix('.')?;
+            Some((name, *image))
+        }) else {
+            continue;
+        };
+        // A disk's files include those of its partitions:
+        let belongs = names.is_empty()
+            || names.iter().any(|device| {
+                name == device
+                    || name
+                        .strip_prefix(device.as_str())
+                        .is_some_and(|rest| rest.starts_with("-part"))
+            });
+        if belongs && entry.file_type()?.is_file() {
+            files.push((entry.path(), image));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_state_files() {
+        let dir = crate::test_util::temp_dir("clean");
+        for name in [
+            "disk-spinner-ZL2ABC.checkpoint",
+            "disk-spinner-ZL2ABC-part1.checkpoint.tmp",
+            "disk-spinner-ZL2ABC.img",
+            "disk-spinner-ZL2ABC.img.manifest",
+            "disk-spinner-ZL2ABCD.checkpoint",
+            "disk-spinner-ZL2ABC.txt",
+            "unrelated.checkpoint",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let names = |files: Vec<(PathBuf, bool)>| -> Vec<(String, bool)> {
+            files
+                .into_iter()
+                .map(|(path, image)| {
+                    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                    (name, image)
+                })
+                .collect()
+        };
+        assert_eq!(
+            names(find_state_files(&dir, &["ZL2ABC".to_string()]).unwrap()),
+            [
+                (
+                    "disk-spinner-ZL2ABC-part1.checkpoint.tmp".to_string(),
+                    false
+                ),
+                ("disk-spinner-ZL2ABC.checkpoint".to_string(), false),
+                ("disk-spinner-ZL2ABC.img".to_string(), true),
+                ("disk-spinner-ZL2ABC.img.manifest".to_string(), true),
+            ]
+        );
+        assert_eq!(find_state_files(&dir, &[]).unwrap().len(), 5);
+    }
+}
@@ -1,12 +1,142 @@
 //! Routines for generating an infinite amount of deterministic garbage.
 
-use std::io;
-use aes::cipher::{KeyIvInit, StreamCipher};
+use aes::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
 use rand::prelude::*;
 use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{fmt, io, str::FromStr};
 
 type ActiveCipher = ctr::Ctr128LE<aes::Aes128>;
 
+/// The seed that all garbage, and the order it's written in, derive from.
+///
+/// Seeds are written as a decimal `u64`, or as hex with a `0x` prefix and
+/// up to 64 digits. Any seed that fits in a `u64` generates the same data
+/// however it's written, and the same data as earlier versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Seed {
+    Short(u64),
+    /// A full 256-bit ChaCha seed, that doesn't fit in a `u64`.
+    Long([u8; 32]),
+}
+
+impl Seed {
+    /// A random number generator seeded from this seed.
+    pub(crate) fn rng(&self) -> ChaCha8Rng {
+        match self {
+            Seed::Short(seed) => ChaCha8Rng::seed_from_u64(*seed),
+            Seed::Long(seed) => ChaCha8Rng::from_seed(*seed),
+        }
+    }
+}
+
+/// Draws a full 256-bit seed from the OS random number generator
+/// (--os-random).
+pub(crate) fn os_random_seed() -> anyhow::Result<Seed> {
+    loop {
+        let mut seed = [0; 32];
+        rand::rngs::OsRng
+            .try_fill_bytes(&mut seed)
+            .map_err(|e| anyhow::anyhow!("Reading the OS random number generator: {}", e))?;
+        // A seed that fits in a u64 would be written as, and read back
+        // as, a short one, which generates different data:
+        if seed[..24].iter().any(|&b| b != 0) {
+            return Ok(Seed::Long(seed));
+        }
+    }
+}
+
+/// Where the seed of a run came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SeedSource {
+    /// It was passed with --seed.
+    Given,
+    /// It was drawn from the OS random number generator, with --os-random.
+    OsRandom,
+    /// It was generated from the default thread-local generator.
+    Generated,
+    /// It was read from the checkpoint that the test resumed from.
+    Checkpoint,
+    /// It was derived from the seed of the run for the failing iteration
+    /// of --repeat-until-fail.
+    Iteration,
+}
+
+impl Serialize for Seed {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // As it's written for --seed, since long seeds aren't numbers:
+        serializer.collect_str(self)
+    }
+}
+
+impl From<u64> for Seed {
+    fn from(seed: u64) -> Self {
+        Seed::Short(seed)
+    }
+}
+
+impl Distribution<Seed> for rand::distributions::Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Seed {
+        Seed::Short(rng.gen())
+    }
+}
+
+impl fmt::Display for Seed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Seed::Short(seed) => write!(f, "{}", seed),
+            Seed::Long(seed) => {
+                write!(f, "0x")?;
+                seed.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+        }
+    }
+}
+
+impl FromStr for Seed {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) else {
+            return Ok(Seed::Short(s.parse()?));
+        };
+        if hex.is_empty() || hex.len() > 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("a hex seed must have between 1 and 64 hex digits after 0x");
+        }
+        let padded = format!("{:0>64}", hex);
+        let mut seed = [0; 32];
+        for (i, b) in seed.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&padded[2 * i..2 * i + 2], 16)?;
+        }
+        if seed[..24].iter().all(|&b| b == 0) {
+            return Ok(Seed::Short(u64::from_be_bytes(
+                seed[24..].try_into().unwrap(),
+            )));
+        }
+        Ok(Seed::Long(seed))
+    }
+}
+
+/// Derives a distinct seed for the `n`th independently-tested region
+/// (e.g. partition number) from a seed.
+pub(crate) fn derive_seed(seed: Seed, n: u64) -> Seed {
+    let mut rng = seed.rng();
+    rng.set_stream(n);
+    match seed {
+        Seed::Short(_) => Seed::Short(rng.next_u64()),
+        Seed::Long(_) => Seed::Long(rng.gen()),
+    }
+}
+
+/// Derives a distinct seed for a device from a seed and a key that
+/// identifies it, like its serial number.
+pub(crate) fn derive_seed_for_key(seed: Seed, key: &str) -> Seed {
+    let digest = Sha256::digest(key.as_bytes());
+    derive_seed(seed, u64::from_le_bytes(digest[..8].try_into().unwrap()))
+}
+
 /// A generator for deterministically random-looking garbage data.
 #[derive(Clone)]
 pub(crate) struct GarbageGenerator<P: Fn(u64)> {
@@ -17,18 +147,34 @@ pub(crate) struct GarbageGenerator<P: Fn(u64)> {
 
 impl<P: Fn(u64)> GarbageGenerator<P> {
     /// Generate a new garbage generator for a block size from a random seed.
-    pub(crate) fn new(block_size: usize, seed: u64, progress: P) -> Self {
-        let mut buf = Vec::new();
-        buf.resize(block_size, 0);
+    pub(crate) fn new(block_size: usize, seed: Seed, progress: P) -> Self {
+        let buf = vec![0; block_size];
 
-        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let mut rng = seed.rng();
         let mut key = [0; 16];
         let mut iv = [0; 16];
         rng.fill_bytes(&mut key);
         rng.fill_bytes(&mut iv);
         let cipher = ActiveCipher::new(&key.into(), &iv.into());
 
-        Self { buf, cipher, progress }
+        Self {
+            buf,
+            cipher,
+            progress,
+        }
+    }
+
+    /// Position the generator so that it continues with the garbage
+    /// that starts at `offset` bytes into the stream.
+    pub(crate) fn seek(&mut self, offset: u64) {
+        self.cipher.seek(offset);
+    }
+
+    /// Fill `buf` with the garbage at the generator's current position,
+    /// regardless of the block size, and advance past it.
+    pub(crate) fn fill(&mut self, buf: &mut [u8]) {
+        buf.fill(0);
+        self.cipher.apply_keystream(buf);
     }
 }
 
@@ -40,10 +186,49 @@ impl<P: Fn(u64)> io::Read for GarbageGenerator<P> {
         for chunk in buf.chunks_exact_mut(self.buf.len()) {
             self.cipher
                 .apply_keystream_b2b(&self.buf, chunk)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("crypto error {:?}", e)))?;
-            done+=chunk.len();
+                .map_err(|e| io::Error::other(format!("crypto error {:?}", e)))?;
+            done += chunk.len();
         }
         (self.progress)(done.try_into().unwrap());
         Ok(done)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{os_random_seed, Seed};
+
+    #[test]
+    fn parses_seeds() {
+        assert_eq!("42".parse::<Seed>().unwrap(), Seed::Short(42));
+        assert_eq!(
+            "0xdeadbeef".parse::<Seed>().unwrap(),
+            Seed::Short(0xdeadbeef)
+        );
+        assert_eq!(
+            format!("0x{:0>64}", "2a").parse::<Seed>().unwrap(),
+            Seed::Short(42)
+        );
+        let long = "0x10000000000000000".parse::<Seed>().unwrap();
+        let mut expected = [0; 32];
+        expected[23] = 1;
+        assert_eq!(long, Seed::Long(expected));
+        assert_eq!(long.to_string().parse::<Seed>().unwrap(), long);
+        assert_eq!(
+            long.to_string(),
+            "0x0000000000000000000000000000000000000000000000010000000000000000"
+        );
+        assert!("0x".parse::<Seed>().is_err());
+        assert!("0xg".parse::<Seed>().is_err());
+        assert!(format!("0x{}", "f".repeat(65)).parse::<Seed>().is_err());
+        assert!("-1".parse::<Seed>().is_err());
+    }
+
+    #[test]
+    fn draws_os_random_seeds() {
+        let seed = os_random_seed().unwrap();
+        assert!(matches!(seed, Seed::Long(_)));
+        assert_ne!(seed, os_random_seed().unwrap());
+        assert_eq!(seed.to_string().parse::<Seed>().unwrap(), seed);
+    }
+}
@@ -0,0 +1,135 @@
+//! A wall-clock budget for testing a device (--max-runtime-per-device).
+//!
+//! A read that takes minutes on a dying drive can't be interrupted, but the
+//! budget is checked before every read and write of the device under test,
+//! so a pathologically slow device stops at its first I/O after the budget
+//! runs out. Each device gets its own budget, which starts with its test,
+//! and the I/O that its test got through is kept to report how far it got.
+
+use serde::Serialize;
+use std::{
+    cell::Cell,
+    io,
+    time::{Duration, Instant},
+};
+
+thread_local! {
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    static PROGRESS: Cell<Progress> = const { Cell::new(Progress::NONE) };
+}
+
+/// How much I/O the current thread got through since its budget started.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct Progress {
+    pub bytes_written: u64,
+    /// This includes reading the data back to verify it.
+    pub bytes_read: u64,
+    /// The offset just past the last read or write.
+    pub offset_reached: u64,
+}
+
+impl Progress {
+    const NONE: Self = Self {
+        bytes_written: 0,
+        bytes_read: 0,
+        offset_reached: 0,
+    };
+}
+
+/// Applies a budget to the I/O of the current thread until dropped.
+#[derive(Debug)]
+pub(crate) struct Deadline {
+    previous: Option<Instant>,
+}
+
+impl Deadline {
+    pub(crate) fn start(budget: Duration) -> Self {
+        let previous = DEADLINE.replace(Some(Instant::now() + budget));
+        PROGRESS.set(Progress::NONE);
+        Self { previous }
+    }
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        DEADLINE.set(self.previous);
+    }
+}
+
+/// Whether the current thread's budget has run out.
+pub(crate) fn exceeded() -> bool {
+    DEADLINE
+        .get()
+        .is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// How much I/O the current thread got through since its budget started.
+pub(crate) fn progress() -> Progress {
+    PROGRESS.get()
+}
+
+/// Records a write of `len` bytes at `offset`.
+pub(crate) fn wrote(offset: u64, len: usize) {
+    let mut progress = PROGRESS.get();
+    progress.bytes_written += len as u64;
+    progress.offset_reached = offset + len as u64;
+    PROGRESS.set(progress);
+}
+
+/// Records a read of `len` bytes at `offset`.
+pub(crate) fn read(offset: u64, len: usize) {
+    let mut progress = PROGRESS.get();
+    progress.bytes_read += len as u64;
+    progress.offset_reached = offset + len as u64;
+    PROGRESS.set(progress);
+}
+
+/// Fails with [io::ErrorKind::TimedOut] if the budget has run out.
+pub(crate) fn check() -> io::Result<()> {
+    if exceeded() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the --max-runtime-per-device budget ran out",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runs_out() {
+        assert!(check().is_ok());
+        {
+            let _deadline = Deadline::start(Duration::ZERO);
+            assert!(exceeded());
+            assert_eq!(check().unwrap_err().kind(), io::ErrorKind::TimedOut);
+            {
+                let _inner = Deadline::start(Duration::from_secs(3600));
+                assert!(check().is_ok());
+            }
+            assert!(exceeded());
+        }
+        assert!(check().is_ok());
+    }
+
+    #[test]
+    fn tracks_progress() {
+        let _deadline = Deadline::start(Duration::from_secs(3600));
+        wrote(0, 4096);
+        wrote(4096, 4096);
+        read(0, 1024);
+        assert_eq!(
+            progress(),
+            Progress {
+                bytes_written: 8192,
+                bytes_read: 1024,
+                offset_reached: 1024,
+            }
+        );
+        let _restarted = Deadline::start(Duration::from_secs(3600));
+        assert_eq!(progress(), Progress::default());
+    }
+}
@@ -0,0 +1,191 @@
+//! Describing I/O failures on the device under test.
+
+use std::{fmt, io};
+
+/// What we were doing to the device when an I/O error happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Read => write!(f, "read"),
+            Operation::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// An I/O error on the device under test, along with where it happened.
+///
+/// This keeps the underlying OS error around, so that a media error
+/// (`EIO`, `EILSEQ`) can be told apart from e.g. a link timeout
+/// (`ETIMEDOUT`).
+#[derive(Debug)]
+pub(crate) struct DeviceIoError {
+    pub operation: Operation,
+    pub offset: u64,
+    pub source: io::Error,
+}
+
+impl DeviceIoError {
+    /// Wraps `source` and logs it with the errno as a structured field.
+    pub(crate) fn new(operation: Operation, offset: u64, source: io::Error) -> Self {
+        let err = Self {
+            operation,
+            offset,
+            source,
+        };
+        tracing::error!(
+            %operation,
+            offset,
+            errno = ?err.errno(),
+            errno_name = err.errno_name().unwrap_or("unknown"),
+            error = %err.source,
+            "I/O error on the device under test"
+        );
+        err
+    }
+
+    /// The raw OS error number, if the error came from the OS.
+    pub(crate) fn errno(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+
+    /// The symbolic name of the OS error, e.g. `"EIO"`.
+    pub(crate) fn errno_name(&self) -> Option<&'static str> {
+        errno_name(self.errno()?)
+    }
+}
+
+impl fmt::Display for DeviceIoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} error at offset {}: {}",
+            self.operation, self.offset, self.source
+        )?;
+        if let Some(name) = self.errno_name() {
+            write!(f, " [{}]", name)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DeviceIoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Returns whether an error (or anything that caused it) is the OS
+/// telling us that the device is read-only.
+pub(crate) fn is_write_protected(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::raw_os_error)
+            == Some(libc::EROFS)
+    })
+}
+
+/// Returns whether an error (or anything that caused it) is the OS
+/// telling us that the device went away, e.g. because it was unplugged
+/// or its link reset.
+pub(crate) fn is_disconnected(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let errno = cause
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::raw_os_error);
+        match errno {
+            Some(libc::ENODEV | libc::ENXIO) => true,
+            #[cfg(target_os = "linux")]
+            Some(libc::ENOMEDIUM) => true,
+            _ => false,
+        }
+    })
+}
+
+/// Names the errno values that a failing disk is likely to produce.
+fn errno_name(errno: i32) -> Option<&'static str> {
+    Some(match errno {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::EIO => "EIO",
+        libc::ENXIO => "ENXIO",
+        libc::EBADF => "EBADF",
+        libc::EAGAIN => "EAGAIN",
+        libc::ENOMEM => "ENOMEM",
+        libc::EACCES => "EACCES",
+        libc::EFAULT => "EFAULT",
+        libc::EBUSY => "EBUSY",
+        libc::ENODEV => "ENODEV",
+        libc::EINVAL => "EINVAL",
+        libc::EFBIG => "EFBIG",
+        libc::ENOSPC => "ENOSPC",
+        libc::ESPIPE => "ESPIPE",
+        libc::EROFS => "EROFS",
+        libc::EILSEQ => "EILSEQ",
+        libc::EOPNOTSUPP => "EOPNOTSUPP",
+        libc::ETIMEDOUT => "ETIMEDOUT",
+        libc::ECANCELED => "ECANCELED",
+        #[cfg(target_os = "linux")]
+        libc::EMEDIUMTYPE => "EMEDIUMTYPE",
+        #[cfg(target_os = "linux")]
+        libc::ENOMEDIUM => "ENOMEDIUM",
+        #[cfg(target_os = "linux")]
+        libc::EREMOTEIO => "EREMOTEIO",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names_errno() {
+        let err = DeviceIoError::new(
+            Operation::Read,
+            4096,
+            io::Error::from_raw_os_error(libc::EIO),
+        );
+        assert_eq!(err.errno(), Some(libc::EIO));
+        assert_eq!(err.errno_name(), Some("EIO"));
+        let message = err.to_string();
+        assert!(message.starts_with("read error at offset 4096: "));
+        assert!(message.ends_with(" [EIO]"));
+
+        let err = DeviceIoError::new(Operation::Write, 0, io::Error::other("not from the OS"));
+        assert_eq!(err.errno(), None);
+        assert_eq!(err.to_string(), "write error at offset 0: not from the OS");
+    }
+
+    #[test]
+    fn detects_write_protection() {
+        let erofs = io::Error::from_raw_os_error(libc::EROFS);
+        let err = anyhow::Error::new(DeviceIoError::new(Operation::Write, 0, erofs))
+            .context("During write test");
+        assert!(is_write_protected(&err));
+        let err = anyhow::Error::new(io::Error::from_raw_os_error(libc::EROFS))
+            .context("Opening the device");
+        assert!(is_write_protected(&err));
+        let eio = io::Error::from_raw_os_error(libc::EIO);
+        assert!(!is_write_protected(&anyhow::Error::new(eio)));
+    }
+
+    #[test]
+    fn detects_disconnection() {
+        let enodev = io::Error::from_raw_os_error(libc::ENODEV);
+        let err = anyhow::Error::new(DeviceIoError::new(Operation::Read, 0, enodev))
+            .context("During read test");
+        assert!(is_disconnected(&err));
+        let err = anyhow::Error::new(io::Error::from_raw_os_error(libc::ENXIO))
+            .context("Opening the device");
+        assert!(is_disconnected(&err));
+        let eio = io::Error::from_raw_os_error(libc::EIO);
+        assert!(!is_disconnected(&anyhow::Error::new(eio)));
+    }
+}
@@ -0,0 +1,307 @@
+//! Quickly checking whether a drive's claimed capacity is real
+//! (--estimate-only).
+//!
+//! A full write and read back is the only way to be sure, but it takes
+//! hours. Most counterfeit drives give themselves away much faster: this
+//! writes one block at each of [PROBES] offsets spread evenly over the
+//! claimed capacity, plus the very last block, then reads them all back.
+//!
+//! * A fake drive that drops writes past its real capacity reads back the
+//!   wrong data from every probe past that point.
+//! * A fake drive that wraps around returns the data of a later probe
+//!   from an earlier one, since both landed on the same real block.
+//!
+//! Each probe holds the random data of its own offset, so a probe that
+//! reads back as another one tells where the device wraps around. Only the
+//! probed blocks are overwritten, but their data IS destroyed.
+//!
+//! This can rule a capacity out, but never prove it: a drive that passes
+//! may still lose data between the probes, or hide a cache that serves the
+//! reads. The full test is still the only verification of the data.
+
+use crate::{
+    crypto::{GarbageGenerator, Seed},
+    device_error::{DeviceIoError, Operation},
+    target::{Cursor, Target},
+};
+use anyhow::Context;
+use serde::Serialize;
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{Read, Write},
+    path::Path,
+};
+use tracing::{debug, warn};
+
+/// How many probes are spread over the device, besides its last block.
+const PROBES: u64 = 64;
+
+/// What a probe read back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Probe {
+    /// The data that was written to it.
+    Ok,
+    /// The data written to the probe at another offset.
+    Aliased(u64),
+    /// Anything else.
+    Wrong,
+}
+
+/// How likely it is that a device claims more capacity than it has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Likelihood {
+    /// Every probe read back correctly.
+    Unlikely,
+    /// Some probes failed, but not the way fake drives do. The media may
+    /// just be bad.
+    Possible,
+    /// The probes failed from some offset to the end, or wrapped around.
+    Likely,
+}
+
+impl fmt::Display for Likelihood {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Likelihood::Unlikely => write!(f, "unlikely"),
+            Likelihood::Possible => write!(f, "possible"),
+            Likelihood::Likely => write!(f, "LIKELY"),
+        }
+    }
+}
+
+/// What the probes say about the capacity of a device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct CapacityEstimate {
+    pub claimed_capacity: u64,
+    pub probes: usize,
+    /// The offsets of the probes that didn't read back as written.
+    pub failed_offsets: Vec<u64>,
+    /// A probe that read back the data of a later one, and the offset of
+    /// that later probe, if the device appears to wrap around.
+    pub wraparound: Option<(u64, u64)>,
+    /// At most how many bytes of the device appear to be usable.
+    pub usable_capacity: u64,
+    pub fraud_likelihood: Likelihood,
+}
+
+impl CapacityEstimate {
+    /// Analyzes what the probes at the (sorted) `offsets` read back.
+    fn analyze(claimed_capacity: u64, offsets: &[u64], probes: &[Probe]) -> Self {
+        let failed: Vec<usize> = (0..probes.len())
+            .filter(|&i| probes[i] != Probe::Ok)
+            .collect();
+        // Two offsets map to the same real block when the later one wraps
+        // around onto the earlier one:
+        let wraparound = offsets
+            .iter()
+            .zip(probes)
+            .filter_map(|(&offset, probe)| match *probe {
+                Probe::Aliased(later) if later > offset => Some((offset, later)),
+                _ => None,
+            })
+            .min_by_key(|&(offset, later)| later - offset);
+        // Every probe from the first failure on failed, after some that didn't:
+        let dropped_tail = match failed.first() {
+            Some(&first) if first > 0 && failed.len() == probes.len() - first => {
+                Some(offsets[first])
+            }
+            _ => None,
+        };
+        let (usable_capacity, fraud_likelihood) = match (wraparound, dropped_tail) {
+            _ if failed.is_empty() => (claimed_capacity, Likelihood::Unlikely),
+            (Some((offset, later)), _) => (later - offset, Likelihood::Likely),
+            (None, Some(first_failed)) => (first_failed, Likelihood::Likely),
+            (None, None) => (claimed_capacity, Likelihood::Possible),
+        };
+        Self {
+            claimed_capacity,
+            probes: probes.len(),
+            failed_offsets: failed.into_iter().map(|i| offsets[i]).collect(),
+            wraparound,
+            usable_capacity,
+            fraud_likelihood,
+        }
+    }
+}
+
+/// The offsets of the probes on a device of `capacity` bytes: blocks of
+/// `block_size` bytes spread evenly from the start, and the last block.
+fn probe_offsets(capacity: u64, block_size: u64) -> Vec<u64> {
+    let blocks = capacity / block_size;
+    let mut offsets: Vec<u64> = (0..PROBES)
+        .map(|i| (i as u128 * blocks as u128 / PROBES as u128) as u64 * block_size)
+        .chain([(blocks - 1) * block_size])
+        .collect();
+    offsets.dedup();
+    offsets
+}
+
+/// Probes the first `capacity` bytes of the device (or all of it) with
+/// blocks of `buffer_size` bytes, overwriting them.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity, seed), fields(device = ?dev_path))]
+pub(crate) fn estimate(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+) -> anyhow::Result<CapacityEstimate> {
+    let blockdev = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for writing", dev_path))?;
+    estimate_on(&blockdev, buffer_size, capacity, seed)
+}
+
+/// Like [estimate], but on any [Target].
+pub(crate) fn estimate_on(
+    target: &dyn Target,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+) -> anyhow::Result<CapacityEstimate> {
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => target.len()?,
+    };
+    anyhow::ensure!(
+        capacity >= buffer_size as u64,
+        "The device is smaller than a single block"
+    );
+    let offsets = probe_offsets(capacity, buffer_size as u64);
+    let mut generator = GarbageGenerator::new(buffer_size, seed, |_| {});
+    let expected: Vec<Vec<u8>> = offsets
+        .iter()
+        .map(|&offset| {
+            let mut block = vec![0; buffer_size];
+            generator.seek(offset);
+            generator.fill(&mut block);
+            block
+        })
+        .collect();
+
+    // In order, so that a later probe overwrites an earlier one it wraps onto:
+    for (&offset, block) in offsets.iter().zip(&expected) {
+        Cursor::new(target, offset)
+            .write_all(block)
+            .map_err(|e| DeviceIoError::new(Operation::Write, offset, e))?;
+    }
+    target.sync().context("Syncing the probes")?;
+    let mut actual = vec![0; buffer_size];
+    let mut probes = Vec::with_capacity(offsets.len());
+    for (&offset, block) in offsets.iter().zip(&expected) {
+        if let Err(e) = target.drop_cache(offset, buffer_size as u64) {
+            debug!(offset, error = %e, "Could not drop the cache before reading the probe");
+        }
+        Cursor::new(target, offset)
+            .read_exact(&mut actual)
+            .map_err(|e| DeviceIoError::new(Operation::Read, offset, e))?;
+        let probe = match offsets.iter().zip(&expected).find(|(_, b)| **b == actual) {
+            _ if actual == *block => Probe::Ok,
+            Some((&other, _)) => Probe::Aliased(other),
+            None => Probe::Wrong,
+        };
+        if probe != Probe::Ok {
+            warn!(
+                event = "bad_block",
+                offset,
+                aliased_to = match probe {
+                    Probe::Aliased(other) => Some(other),
+                    _ => None,
+                },
+                "A capacity probe did not read back as written"
+            );
+        }
+        probes.push(probe);
+    }
+    Ok(CapacityEstimate::analyze(capacity, &offsets, &probes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::target::MemoryTarget;
+    use std::io;
+
+    const K: u64 = 1024;
+
+    #[test]
+    fn spreads_probes() {
+        let offsets = probe_offsets(1024 * K, K);
+        assert_eq!(offsets.len(), PROBES as usize + 1);
+        assert_eq!(offsets[..2], [0, 16 * K]);
+        assert_eq!(*offsets.last().unwrap(), 1023 * K);
+        assert_eq!(probe_offsets(4 * K, K), [0, K, 2 * K, 3 * K]);
+    }
+
+    #[test]
+    fn analyzes() {
+        let offsets: Vec<u64> = (0..8).map(|i| i * K).collect();
+        let analyze = |probes: &[Probe]| CapacityEstimate::analyze(8 * K, &offsets, probes);
+
+        let good = analyze(&[Probe::Ok; 8]);
+        assert_eq!(good.fraud_likelihood, Likelihood::Unlikely);
+        assert_eq!(good.usable_capacity, 8 * K);
+
+        let mut dropped = [Probe::Ok; 8];
+        dropped[5..].fill(Probe::Wrong);
+        let dropped = analyze(&dropped);
+        assert_eq!(dropped.fraud_likelihood, Likelihood::Likely);
+        assert_eq!(dropped.usable_capacity, 5 * K);
+        assert_eq!(dropped.failed_offsets, [5 * K, 6 * K, 7 * K]);
+
+        let mut wrapped = [Probe::Ok; 8];
+        wrapped[0] = Probe::Aliased(4 * K);
+        wrapped[1] = Probe::Aliased(5 * K);
+        let wrapped = analyze(&wrapped);
+        assert_eq!(wrapped.fraud_likelihood, Likelihood::Likely);
+        assert_eq!(wrapped.wraparound, Some((0, 4 * K)));
+        assert_eq!(wrapped.usable_capacity, 4 * K);
+
+        let mut scattered = [Probe::Ok; 8];
+        scattered[3] = Probe::Wrong;
+        assert_eq!(analyze(&scattered).fraud_likelihood, Likelihood::Possible);
+        assert_eq!(
+            analyze(&[Probe::Wrong; 8]).fraud_likelihood,
+            Likelihood::Possible
+        );
+    }
+
+    /// A fake drive that only has `real` bytes, and wraps around past them.
+    #[derive(Debug)]
+    struct Wrapping(MemoryTarget, u64);
+
+    impl Target for Wrapping {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.0.read_at(buf, offset % self.1)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+            self.0.write_at(buf, offset % self.1)
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            Ok(self.1 * 4)
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn estimates_capacity() {
+        let real = MemoryTarget::new(256 * K as usize);
+        let estimate = estimate_on(&real, 4096, None, 1.into()).expect("No io errors");
+        assert_eq!(estimate.fraud_likelihood, Likelihood::Unlikely);
+        assert!(estimate.failed_offsets.is_empty());
+
+        let fake = Wrapping(MemoryTarget::new(256 * K as usize), 256 * K);
+        let estimate = estimate_on(&fake, 4096, None, 1.into()).expect("No io errors");
+        assert_eq!(estimate.claimed_capacity, 1024 * K);
+        assert_eq!(estimate.fraud_likelihood, Likelihood::Likely);
+        assert_eq!(estimate.usable_capacity, 256 * K);
+    }
+}
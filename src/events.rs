@@ -0,0 +1,324 @@
+//! Streaming machine-readable events while the test runs.
+//!
+//! With `--events FILE`, every log event that has an `event` field is
+//! also written to FILE as a line of JSON, so a supervising process can
+//! follow along without waiting for the final report. The events are:
+//!
+//! * `start`: a device's test is starting, with the version and git
+//!   commit of disk-spinner.
+//! * `progress`: every [PROGRESS_INTERVAL] bytes of a write or read phase,
+//!   and also every --progress-interval if set.
+//! * `bad_block`: a block did not read back as written.
+//! * `hidden_capacity`: an ATA drive hides some of its capacity.
+//! * `idle_gap`: the device sat idle long enough to risk spinning down.
+//! * `pass_complete`: a pass of the test finished, with its result.
+//! * `device_done`: a device is done being tested, with its outcome.
+//!
+//! Each line has a `time` (in seconds since the Unix epoch), the fields
+//! of the spans the event happened in (like `device`), and the event's own
+//! fields. Events have no fixed schema beyond that: they carry the same
+//! data as the corresponding log lines.
+
+use anyhow::Context as _;
+use serde_json::{Map, Value};
+use std::{
+    cell::Cell,
+    fmt,
+    fs::File,
+    io::{LineWriter, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{field::Field, span, Event, Span, Subscriber};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// How many bytes are processed between two `progress` events.
+pub(crate) const PROGRESS_INTERVAL: u64 = 1024 * 1024 * 1024;
+
+/// The target of `progress` events, which are too frequent for the terminal.
+pub(crate) const PROGRESS_TARGET: &str = "disk_spinner::progress";
+
+/// How often a status line is logged for each phase, with `--progress line`.
+pub(crate) const STATUS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the progress bars, status lines and `progress` events are
+/// updated, if set with --progress-interval.
+static UPDATE_INTERVAL: OnceLock<Duration> = OnceLock::new();
+
+/// Sets how often progress is updated, for the rest of the process.
+pub(crate) fn set_update_interval(interval: Duration) {
+    let _ = UPDATE_INTERVAL.set(interval);
+}
+
+/// The target of the status lines, which only get printed instead of the
+/// progress bars (with `--progress line`).
+pub(crate) const STATUS_TARGET: &str = "disk_spinner::status";
+
+/// A [Layer] that writes events with an `event` field as lines of JSON.
+#[derive(Debug)]
+pub(crate) struct EventsLayer {
+    out: Mutex<LineWriter<File>>,
+}
+
+impl EventsLayer {
+    /// Creates (or truncates) the events file at `path`.
+    pub(crate) fn create(path: &Path) -> anyhow::Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Creating the events file {:?}", path))?;
+        Ok(Self {
+            out: Mutex::new(LineWriter::new(file)),
+        })
+    }
+}
+
+/// The fields of a span or event, as JSON values.
+#[derive(Debug, Default)]
+struct JsonFields(Map<String, Value>);
+
+impl tracing::field::Visit for JsonFields {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{:?}", value);
+        // Paths and strings logged with `?` are quoted, which we undo where
+        // their escaping happens to be valid JSON (as it is for most paths):
+        let value = serde_json::from_str::<String>(&value).unwrap_or(value);
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+impl<S> Layer<S> for EventsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = JsonFields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<JsonFields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+        if !fields.0.contains_key("event") {
+            return;
+        }
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut line = Map::new();
+        line.insert("time".to_string(), time.into());
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<JsonFields>() {
+                    line.extend(span_fields.0.clone());
+                }
+            }
+        }
+        line.extend(fields.0);
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        // There is nowhere to report a failure to write an event to, and
+        // it shouldn't stop the test, so errors are ignored.
+        let _ = serde_json::to_writer(&mut *out, &line);
+        let _ = out.write_all(b"\n");
+    }
+}
+
+/// Advances the progress bar of the current span, emits a `progress` event
+/// every [PROGRESS_INTERVAL] bytes of a phase, and logs a status line every
+/// [STATUS_INTERVAL].
+///
+/// With --progress-interval, the bar is only advanced, and status lines
+/// and `progress` events only emitted, once per interval.
+#[derive(Debug)]
+pub(crate) struct ProgressEvents {
+    phase: &'static str,
+    total: u64,
+    done: Cell<u64>,
+    next: Cell<u64>,
+    start: u64,
+    started: Instant,
+    bar: Span,
+    /// The bytes that were processed but not yet added to the bar.
+    bar_pending: Cell<u64>,
+    update_interval: Option<Duration>,
+    next_update: Cell<Instant>,
+    status_interval: Duration,
+    next_status: Cell<Instant>,
+}
+
+impl ProgressEvents {
+    /// Starts tracking a phase that has already processed `start` bytes,
+    /// with the progress bar of the current span.
+    pub(crate) fn new(phase: &'static str, total: u64, start: u64) -> Self {
+        let started = Instant::now();
+        let update_interval = UPDATE_INTERVAL.get().copied();
+        let status_interval = update_interval.unwrap_or(STATUS_INTERVAL);
+        Self {
+            phase,
+            total,
+            done: Cell::new(start),
+            next: Cell::new((start / PROGRESS_INTERVAL + 1) * PROGRESS_INTERVAL),
+            start,
+            started,
+            bar: Span::current(),
+            bar_pending: Cell::new(0),
+            update_interval,
+            next_update: Cell::new(started + update_interval.unwrap_or_default()),
+            status_interval,
+            next_status: Cell::new(started + status_interval),
+        }
+    }
+
+    /// Records that `n` more bytes were processed.
+    pub(crate) fn inc(&self, n: u64) {
+        let done = self.done.get() + n;
+        self.done.set(done);
+        let now = Instant::now();
+        let update = match self.update_interval {
+            None => true,
+            Some(interval) if now >= self.next_update.get() => {
+                self.next_update.set(now + interval);
+                true
+            }
+            Some(_) => false,
+        };
+        self.bar_pending.set(self.bar_pending.get() + n);
+        if update {
+            self.bar.pb_inc(self.bar_pending.replace(0));
+        }
+        if done >= self.next.get() || (update && self.update_interval.is_some()) {
+            self.next
+                .set((done / PROGRESS_INTERVAL + 1) * PROGRESS_INTERVAL);
+            tracing::info!(
+                target: PROGRESS_TARGET,
+                event = "progress",
+                phase = self.phase,
+                bytes = done,
+                total_bytes = self.total,
+                "progress"
+            );
+        }
+        if now >= self.next_status.get() {
+            self.next_status.set(now + self.status_interval);
+            self.status(done, now);
+        }
+    }
+
+    /// Logs a line with how far along the phase is, for logs that can't
+    /// show progress bars.
+    fn status(&self, done: u64, now: Instant) {
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let rate = (done - self.start) as f64 / elapsed.max(f64::EPSILON);
+        let eta = match rate > 0.0 {
+            true => Duration::from_secs_f64(self.total.saturating_sub(done) as f64 / rate),
+            false => Duration::ZERO,
+        };
+        tracing::info!(
+            target: STATUS_TARGET,
+            "{}: {:.1}% of {} at {}/s, ETA {}",
+            self.phase,
+            done as f64 * 100.0 / self.total.max(1) as f64,
+            indicatif::BinaryBytes(self.total),
+            indicatif::BinaryBytes(rate as u64),
+            indicatif::FormattedDuration(eta)
+        );
+    }
+}
+
+impl Drop for ProgressEvents {
+    fn drop(&mut self) {
+        // Whatever was held back still belongs on the bar at the end.
+        self.bar.pb_inc(self.bar_pending.get());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::sparse_file;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tracing_test::traced_test]
+    #[test]
+    fn logs_status_lines() {
+        let mut progress = ProgressEvents::new("write", 1000, 0);
+        progress.status_interval = Duration::ZERO;
+        progress.next_status = Cell::new(Instant::now());
+        progress.inc(500);
+        assert!(logs_contain("write: 50.0% of 1000 B at"));
+    }
+
+    #[test]
+    fn throttles_updates() {
+        let mut progress = ProgressEvents::new("write", 1000, 0);
+        progress.update_interval = Some(Duration::from_secs(3600));
+        progress.next_update = Cell::new(Instant::now() + Duration::from_secs(3600));
+        progress.inc(100);
+        progress.inc(200);
+        assert_eq!(progress.bar_pending.get(), 300);
+        progress.next_update = Cell::new(Instant::now());
+        progress.inc(50);
+        assert_eq!(progress.bar_pending.get(), 0);
+        assert_eq!(progress.done.get(), 350);
+    }
+
+    #[test]
+    fn writes_events() {
+        let path = sparse_file("events", 0);
+        let subscriber = tracing_subscriber::registry().with(EventsLayer::create(&path).unwrap());
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("test_device", device = ?Path::new("/dev/sda"));
+            let _handle = span.enter();
+            tracing::info!("not an event");
+            tracing::warn!(event = "bad_block", offset = 4096u64, "bad block");
+            let progress = ProgressEvents::new("write", 3 * PROGRESS_INTERVAL, 0);
+            progress.inc(PROGRESS_INTERVAL - 1);
+            progress.inc(PROGRESS_INTERVAL + 2);
+        });
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "bad_block");
+        assert_eq!(lines[0]["device"], "/dev/sda");
+        assert_eq!(lines[0]["offset"], 4096);
+        assert_eq!(lines[1]["event"], "progress");
+        assert_eq!(lines[1]["phase"], "write");
+        assert_eq!(lines[1]["bytes"], 2 * PROGRESS_INTERVAL + 1);
+    }
+}
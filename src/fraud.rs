@@ -0,0 +1,160 @@
+//! Spotting counterfeit drives that claim more capacity than they have.
+//!
+//! Fake drives typically do one of two things past their real capacity:
+//!
+//! * drop the writes, so everything after some offset reads back wrong,
+//!   while the start of the device is fine;
+//! * wrap around, so later writes overwrite the start of the device, and
+//!   only the last stretch that was written reads back right.
+//!
+//! Both show up in the layout of the bad blocks after a full write and
+//! read back, as a single run of bad blocks at the end or at the start of
+//! the device. Some drives fail the reads past their real capacity
+//! instead, which is the same as a run of bad blocks from the first read
+//! that failed to the end. Bad blocks scattered across the device point at failing
+//! media instead, and say nothing about the real capacity. So does a run
+//! shorter than [MIN_FAKE_FRACTION] of the device, which is more likely a
+//! bad patch at the edge than missing capacity.
+
+use serde::Serialize;
+
+/// The smallest fraction of the device that a run of bad blocks at its
+/// start or end needs to cover to count as missing capacity.
+const MIN_FAKE_FRACTION: u64 = 100;
+
+/// What the bad blocks of a device say about its real capacity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct CapacityReport {
+    /// How many bytes the device claims to have, and was tested with.
+    pub claimed_capacity: u64,
+    /// The longest stretch of the device that read back correctly.
+    pub largest_good_region_offset: u64,
+    pub largest_good_region_length: u64,
+    /// Where the device appears to wrap around: writes from this offset
+    /// on landed at the start of the device again, overwriting it.
+    pub wraparound_offset: Option<u64>,
+    /// The first block of the device was overwritten by later writes.
+    pub start_overwritten: bool,
+    /// How many bytes of the device appear to really be usable.
+    pub usable_capacity: u64,
+}
+
+impl CapacityReport {
+    /// Analyzes the (sorted) offsets of the bad blocks of a device whose
+    /// blocks of `block_size` bytes were all written and read back.
+    pub(crate) fn analyze(claimed_capacity: u64, block_size: u64, bad_offsets: &[u64]) -> Self {
+        let mut largest = (0, 0);
+        let mut start = 0;
+        for &bad in bad_offsets.iter().chain([&claimed_capacity]) {
+            if bad.saturating_sub(start) > largest.1 {
+                largest = (start, bad - start);
+            }
+            start = start.max(bad + block_size);
+        }
+        // A single run of bad blocks, from `first` to the end of `last`:
+        let run = match (bad_offsets.first(), bad_offsets.last()) {
+            (Some(&first), Some(&last))
+                if (last - first) / block_size + 1 == bad_offsets.len() as u64 =>
+            {
+                Some((first, (last + block_size).min(claimed_capacity)))
+            }
+            _ => None,
+        }
+        .filter(|(first, end)| (end - first) * MIN_FAKE_FRACTION >= claimed_capacity);
+        let (wraparound_offset, usable_capacity) = match run {
+            // Dropped writes past the real capacity:
+            Some((first, end)) if end == claimed_capacity => (None, first),
+            // Wrapped around, overwriting the start: only the last stretch
+            // of the real capacity survived, so that's where it wraps.
+            Some((0, end)) => (Some(claimed_capacity - end), claimed_capacity - end),
+            _ => (None, claimed_capacity),
+        };
+        Self::new(
+            claimed_capacity,
+            largest,
+            wraparound_offset,
+            usable_capacity,
+        )
+    }
+
+    /// Analyzes a device that was written in full, but whose reads failed
+    /// from `offset` on. The bad blocks before `offset`, if any, aren't
+    /// taken into account.
+    pub(crate) fn unreadable_from(claimed_capacity: u64, offset: u64) -> Self {
+        let missing = claimed_capacity.saturating_sub(offset);
+        let usable_capacity = match missing * MIN_FAKE_FRACTION >= claimed_capacity {
+            true => offset,
+            false => claimed_capacity,
+        };
+        Self::new(claimed_capacity, (0, offset), None, usable_capacity)
+    }
+
+    fn new(
+        claimed_capacity: u64,
+        largest: (u64, u64),
+        wraparound_offset: Option<u64>,
+        usable_capacity: u64,
+    ) -> Self {
+        Self {
+            claimed_capacity,
+            largest_good_region_offset: largest.0,
+            largest_good_region_length: largest.1,
+            wraparound_offset,
+            start_overwritten: wraparound_offset.is_some(),
+            usable_capacity,
+        }
+    }
+
+    /// Whether the device appears to have less capacity than it claims.
+    pub(crate) fn is_fake(&self) -> bool {
+        self.usable_capacity < self.claimed_capacity
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CapacityReport;
+
+    const K: u64 = 1024;
+
+    #[test]
+    fn analyzes() {
+        let good = CapacityReport::analyze(64 * K, K, &[]);
+        assert!(!good.is_fake());
+        assert_eq!(good.largest_good_region_length, 64 * K);
+
+        let scattered = CapacityReport::analyze(64 * K, K, &[3 * K, 40 * K]);
+        assert!(!scattered.is_fake());
+        assert_eq!(scattered.largest_good_region_offset, 4 * K);
+        assert_eq!(scattered.largest_good_region_length, 36 * K);
+
+        let last_block = CapacityReport::analyze(1024 * K, K, &[1023 * K]);
+        assert!(!last_block.is_fake());
+
+        let dropped: Vec<u64> = (16..64).map(|i| i * K).collect();
+        let dropped = CapacityReport::analyze(64 * K, K, &dropped);
+        assert!(dropped.is_fake());
+        assert_eq!(dropped.usable_capacity, 16 * K);
+        assert_eq!(dropped.wraparound_offset, None);
+        assert!(!dropped.start_overwritten);
+
+        let wrapped: Vec<u64> = (0..48).map(|i| i * K).collect();
+        let wrapped = CapacityReport::analyze(64 * K, K, &wrapped);
+        assert!(wrapped.is_fake());
+        assert_eq!(wrapped.usable_capacity, 16 * K);
+        assert_eq!(wrapped.wraparound_offset, Some(16 * K));
+        assert!(wrapped.start_overwritten);
+        assert_eq!(wrapped.largest_good_region_offset, 48 * K);
+
+        let all: Vec<u64> = (0..64).map(|i| i * K).collect();
+        let all = CapacityReport::analyze(64 * K, K, &all);
+        assert_eq!(all.usable_capacity, 0);
+        assert_eq!(all.largest_good_region_length, 0);
+
+        let unreadable = CapacityReport::unreadable_from(64 * K, 16 * K);
+        assert!(unreadable.is_fake());
+        assert_eq!(unreadable.usable_capacity, 16 * K);
+        assert_eq!(unreadable.largest_good_region_length, 16 * K);
+        assert!(!CapacityReport::unreadable_from(1024 * K, 1023 * K).is_fake());
+    }
+}
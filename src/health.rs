@@ -0,0 +1,108 @@
+//! Summarizing a device's test results as a single health score.
+//!
+//! The score runs from 0 to 100 and is computed from the outcome of the
+//! data-integrity test and the kind of errors it found, as follows:
+//!
+//! * A device that read back every block exactly as written scores 100.
+//! * A device whose every block read back as written in the end, but
+//!   some only the second time with --verify-twice, scores 70: its reads
+//!   can't be trusted, even if its data is intact.
+//! * Any corrupted data means the device should be returned, so a device
+//!   with bad blocks scores at most 40, minus 10 points for every order of
+//!   magnitude of bad blocks: 1 bad block scores 40, 10 score 30, 100 score
+//!   20, 1000 score 10 and 10000 or more score 0.
+//! * Devices with fewer bad blocks than --fail-threshold are scored the
+//!   same way, but their verdict is left to you.
+//! * Devices that read back correctly, but slower than --min-throughput,
+//!   score 50.
+//! * Devices whose data was never read back in full get no score: those
+//!   tested with --no-read-back, and those whose test ran out of
+//!   --max-runtime-per-device, panicked or stopped on an error.
+//!
+//! The score doesn't cover everything a health check could: it leaves out
+//! SMART deltas and throughput stability, which disk-spinner doesn't
+//! measure. It never reads SMART data, and it only times the write and
+//! read phases as a whole. Throughput counts only against the
+//! --min-throughput you set, since no scale fits every device (a good USB
+//! stick is a hundred times slower than a good NVMe drive). The kind of
+//! I/O error that stopped a test says why it has no verdict, not how
+//! healthy the device is, and is reported as the uncertain reason instead.
+
+use crate::{Outcome, UncertainReason};
+use serde::Serialize;
+
+/// A device's health score, along with a one-line verdict.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Health {
+    pub score: u8,
+    pub verdict: &'static str,
+}
+
+impl Health {
+    /// Scores a device's test outcome, if it can be scored, given how many
+    /// blocks read back correctly only the second time.
+    pub(crate) fn score(outcome: &Outcome, transient_bad_blocks: usize) -> Option<Self> {
+        let score = match outcome {
+            Outcome::Good if transient_bad_blocks > 0 => 70,
+            Outcome::Good => 100,
+            Outcome::Bad(bad_blocks)
+            | Outcome::Uncertain(bad_blocks, UncertainReason::BelowThreshold) => {
+                let magnitude = (*bad_blocks as f64).log10().floor() as u8;
+                40u8.saturating_sub(10 * magnitude)
+            }
+            // The data is fine, the drive just doesn't live up to the threshold:
+            Outcome::Slow => 50,
+            Outcome::Unverified | Outcome::Uncertain(..) => return None,
+        };
+        let verdict = match (outcome, score) {
+            (Outcome::Uncertain(_, UncertainReason::BelowThreshold), _) => {
+                "uncertain, below the failure threshold"
+            }
+            (Outcome::Slow, _) => "too slow",
+            (Outcome::Good, 70) => "unreliable reads",
+            (_, 90..) => "healthy",
+            _ => "return it",
+        };
+        Some(Self { score, verdict })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scores_outcomes() {
+        let score = |outcome| Health::score(&outcome, 0).map(|h| h.score);
+        assert_eq!(score(Outcome::Good), Some(100));
+        assert_eq!(score(Outcome::Bad(1)), Some(40));
+        assert_eq!(score(Outcome::Bad(9)), Some(40));
+        assert_eq!(score(Outcome::Bad(10)), Some(30));
+        assert_eq!(score(Outcome::Bad(1000)), Some(10));
+        assert_eq!(score(Outcome::Bad(1_000_000)), Some(0));
+        assert_eq!(
+            score(Outcome::Uncertain(10, UncertainReason::BelowThreshold)),
+            Some(30)
+        );
+        assert_eq!(score(Outcome::Unverified), None);
+        // Their data was never read back in full, so there is nothing to score:
+        assert_eq!(score(Outcome::Uncertain(0, UncertainReason::Timeout)), None);
+        assert_eq!(
+            score(Outcome::Uncertain(0, UncertainReason::Panicked)),
+            None
+        );
+        assert_eq!(
+            score(Outcome::Uncertain(0, UncertainReason::ReadError)),
+            None
+        );
+        assert_eq!(score(Outcome::Uncertain(3, UncertainReason::Timeout)), None);
+        assert_eq!(
+            Health::score(&Outcome::Bad(1), 0).unwrap().verdict,
+            "return it"
+        );
+        let flaky = Health::score(&Outcome::Good, 2).unwrap();
+        assert_eq!(flaky.score, 70);
+        assert_eq!(flaky.verdict, "unreliable reads");
+        assert_eq!(Health::score(&Outcome::Bad(10), 2).unwrap().score, 30);
+    }
+}
@@ -0,0 +1,413 @@
+//! Finding capacity that an ATA drive hides in a host protected area
+//! (HPA) or a device configuration overlay (DCO), and removing the HPA
+//! (--remove-hpa).
+//!
+//! An HPA makes a drive report less capacity than it has, and a DCO
+//! lowers what it reports as its native capacity, below what it left the
+//! factory with. Either way, the test never touches the hidden part,
+//! which on a used drive may hold data. The drive is asked through ATA
+//! PASS-THROUGH, which only SATA drives on a SCSI-style driver (and some
+//! USB bridges) answer, so most other devices are silently skipped.
+//!
+//! Only the HPA can be removed, and only until the drive is power cycled
+//! (with a volatile SET MAX ADDRESS EXT). Removing a DCO takes a
+//! DEVICE CONFIGURATION RESTORE, which also resets the drive's features,
+//! and is left to tools like hdparm.
+
+use crate::scsi_command;
+use anyhow::Context;
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+};
+use tracing::{debug, warn};
+
+const IDENTIFY_DEVICE: u8 = 0xec;
+const READ_NATIVE_MAX_ADDRESS_EXT: u8 = 0x27;
+const SET_MAX_ADDRESS_EXT: u8 = 0x37;
+const DEVICE_CONFIGURATION: u8 = 0xb1;
+const DEVICE_CONFIGURATION_IDENTIFY: u16 = 0xc2;
+
+/// An ATA command with 48-bit registers, to send with ATA PASS-THROUGH(16).
+#[derive(Debug, Clone, Copy, Default)]
+struct AtaCommand {
+    command: u8,
+    features: u16,
+    count: u16,
+    lba: u64,
+}
+
+impl AtaCommand {
+    /// The SCSI command that passes this one through, reading a sector of
+    /// data from the drive if `reads_data`.
+    fn cdb(&self, reads_data: bool) -> [u8; 16] {
+        let mut cdb = [0; 16];
+        cdb[0] = 0x85;
+        // The protocol (PIO data-in or non-data) and the EXTEND bit:
+        cdb[1] = match reads_data {
+            true => 4 << 1 | 1,
+            false => 3 << 1 | 1,
+        };
+        // Always return the registers (CK_COND), and for data-in, take
+        // the transfer length in sectors from the count register:
+        cdb[2] = match reads_data {
+            true => 0x2e,
+            false => 0x20,
+        };
+        cdb[3..5].copy_from_slice(&self.features.to_be_bytes());
+        cdb[5..7].copy_from_slice(&self.count.to_be_bytes());
+        let lba = self.lba.to_le_bytes();
+        cdb[7..13].copy_from_slice(&[lba[3], lba[0], lba[4], lba[1], lba[5], lba[2]]);
+        // LBA addressing:
+        cdb[13] = 0x40;
+        cdb[14] = self.command;
+        cdb
+    }
+}
+
+/// The registers that an ATA command returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AtaRegisters {
+    error: u8,
+    status: u8,
+    lba: u64,
+}
+
+impl AtaRegisters {
+    /// Finds the registers in the ATA Status Return descriptor of
+    /// descriptor-format sense data.
+    fn from_sense(sense: &[u8]) -> io::Result<Self> {
+        let not_ata = || {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the device did not return ATA registers, it may not be an ATA drive",
+            )
+        };
+        if sense.first().map(|b| b & 0x7f) != Some(0x72) {
+            return Err(not_ata());
+        }
+        let mut descriptors = sense.get(8..).unwrap_or_default();
+        while descriptors.len() >= 2 {
+            let len = 2 + descriptors[1] as usize;
+            if descriptors[0] == 0x09 && descriptors.len() >= 14 {
+                let d = descriptors;
+                let registers = Self {
+                    error: d[3],
+                    status: d[13],
+                    lba: u64::from_le_bytes([d[7], d[9], d[11], d[6], d[8], d[10], 0, 0]),
+                };
+                // The ERR bit of the status:
+                if registers.status & 1 != 0 {
+                    return Err(io::Error::other(format!(
+                        "the drive rejected the ATA command with error {:#x}",
+                        registers.error
+                    )));
+                }
+                return Ok(registers);
+            }
+            descriptors = &descriptors[len.min(descriptors.len())..];
+        }
+        Err(not_ata())
+    }
+}
+
+fn send(file: &File, command: AtaCommand) -> io::Result<AtaRegisters> {
+    AtaRegisters::from_sense(&scsi_command(file, &command.cdb(false), &mut [])?)
+}
+
+/// Sends a command that returns a sector of 256 words, like IDENTIFY DEVICE.
+fn send_identify(file: &File, command: AtaCommand) -> io::Result<[u16; 256]> {
+    let mut data = [0; 512];
+    // The transfer length is in the count register:
+    let command = AtaCommand {
+        count: 1,
+        ..command
+    };
+    let sense = scsi_command(file, &command.cdb(true), &mut data)?;
+    AtaRegisters::from_sense(&sense)?;
+    let mut words = [0; 256];
+    for (word, bytes) in words.iter_mut().zip(data.chunks_exact(2)) {
+        *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+    Ok(words)
+}
+
+/// Reads a little-endian number from consecutive IDENTIFY words.
+fn words_u64(words: &[u16]) -> u64 {
+    words.iter().rev().fold(0, |n, &word| n << 16 | word as u64)
+}
+
+/// What IDENTIFY DEVICE says about a drive's capacity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Identity {
+    lba48: bool,
+    hpa: bool,
+    dco: bool,
+    sector_size: u64,
+    /// How many sectors are addressable, without the HPA.
+    sectors: u64,
+}
+
+impl Identity {
+    fn parse(words: &[u16; 256]) -> Self {
+        // Word 106 is only valid with bit 14 set and bit 15 cleared, and
+        // bit 12 says that words 117-118 hold the logical sector size:
+        let sector_size = match words[106] & 0xd000 == 0x5000 {
+            true => words_u64(&words[117..119]) * 2,
+            false => 512,
+        };
+        Self {
+            lba48: words[83] & 1 << 10 != 0,
+            hpa: words[82] & 1 << 10 != 0,
+            dco: words[83] & 1 << 11 != 0,
+            sector_size,
+            sectors: words_u64(&words[100..104]),
+        }
+    }
+}
+
+/// A drive's capacity, with and without what it hides, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct Capacity {
+    /// What the drive makes accessible.
+    pub accessible: u64,
+    /// Without the host protected area.
+    pub native: u64,
+    /// Without the device configuration overlay either, if the drive
+    /// supports one.
+    pub factory: Option<u64>,
+}
+
+impl Capacity {
+    /// The bytes hidden in the host protected area.
+    pub(crate) fn hpa(&self) -> u64 {
+        self.native.saturating_sub(self.accessible)
+    }
+
+    /// The bytes hidden by the device configuration overlay.
+    pub(crate) fn dco(&self) -> u64 {
+        self.factory.map_or(0, |f| f.saturating_sub(self.native))
+    }
+}
+
+/// Asks an ATA drive for its accessible, native and factory capacities.
+pub(crate) fn detect(dev_path: &Path) -> io::Result<Capacity> {
+    let file = File::open(dev_path)?;
+    let identity = Identity::parse(&send_identify(
+        &file,
+        AtaCommand {
+            command: IDENTIFY_DEVICE,
+            ..Default::default()
+        },
+    )?);
+    if !identity.lba48 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the drive doesn't support 48-bit addresses",
+        ));
+    }
+    let accessible = identity.sectors * identity.sector_size;
+    let native = match identity.hpa {
+        true => (read_native_max(&file)? + 1) * identity.sector_size,
+        false => accessible,
+    };
+    let factory = match identity.dco {
+        true => match send_identify(
+            &file,
+            AtaCommand {
+                command: DEVICE_CONFIGURATION,
+                features: DEVICE_CONFIGURATION_IDENTIFY,
+                ..Default::default()
+            },
+        ) {
+            Ok(words) => Some((words_u64(&words[3..7]) + 1) * identity.sector_size),
+            Err(e) => {
+                debug!(device=?dev_path, error=%e, "Could not read the device configuration overlay");
+                None
+            }
+        },
+        false => None,
+    };
+    Ok(Capacity {
+        accessible,
+        native,
+        factory,
+    })
+}
+
+/// The highest LBA the drive has without its host protected area.
+fn read_native_max(file: &File) -> io::Result<u64> {
+    let registers = send(
+        file,
+        AtaCommand {
+            command: READ_NATIVE_MAX_ADDRESS_EXT,
+            ..Default::default()
+        },
+    )?;
+    Ok(registers.lba)
+}
+
+/// Removes the host protected area until the drive is power cycled.
+fn remove(dev_path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().read(true).write(true).open(dev_path)?;
+    // SET MAX ADDRESS EXT has to come right after READ NATIVE MAX ADDRESS
+    // EXT, and a count of zero makes it volatile:
+    let native_max = read_native_max(&file)?;
+    send(
+        &file,
+        AtaCommand {
+            command: SET_MAX_ADDRESS_EXT,
+            lba: native_max,
+            ..Default::default()
+        },
+    )?;
+    Ok(())
+}
+
+/// Warns about any capacity that an ATA drive hides, and with `remove_hpa`,
+/// removes its host protected area, calling `rescan` to make the kernel
+/// pick up the new capacity.
+///
+/// Returns the capacities found before removing anything, or `None` if
+/// the drive doesn't answer.
+pub(crate) fn check(
+    dev_path: &Path,
+    remove_hpa: bool,
+    rescan: impl FnOnce() -> io::Result<()>,
+) -> anyhow::Result<Option<Capacity>> {
+    let capacity = match detect(dev_path) {
+        Ok(capacity) => capacity,
+        Err(e) if remove_hpa => {
+            return Err(e).with_context(|| {
+                format!(
+                    "Checking {:?} for a host protected area (--remove-hpa)",
+                    dev_path
+                )
+            })
+        }
+        Err(e) => {
+            debug!(device=?dev_path, error=%e, "Could not check for hidden capacity");
+            return Ok(None);
+        }
+    };
+    debug!(device=?dev_path, ?capacity, "Checked for hidden capacity");
+    if capacity.dco() > 0 {
+        warn!(event = "hidden_capacity", device=?dev_path, native_capacity = capacity.native, factory_capacity = capacity.factory, "The drive hides {} with a device configuration overlay (DCO), which won't be tested. disk-spinner can't remove it.", indicatif::BinaryBytes(capacity.dco()));
+    }
+    if capacity.hpa() == 0 {
+        return Ok(Some(capacity));
+    }
+    if !remove_hpa {
+        warn!(event = "hidden_capacity", device=?dev_path, accessible_capacity = capacity.accessible, native_capacity = capacity.native, "The drive hides {} in a host protected area (HPA), which won't be tested. Pass --remove-hpa to test it too.", indicatif::BinaryBytes(capacity.hpa()));
+        return Ok(Some(capacity));
+    }
+    warn!(device=?dev_path, accessible_capacity = capacity.accessible, native_capacity = capacity.native, "Removing the host protected area (--remove-hpa). THIS CHANGES THE DRIVE, until it's power cycled.");
+    remove(dev_path)
+        .with_context(|| format!("Removing the host protected area of {:?}", dev_path))?;
+    rescan().with_context(|| {
+        format!(
+            "Rescanning the capacity of {:?} after removing its host protected area",
+            dev_path
+        )
+    })?;
+    let removed = detect(dev_path)?;
+    if removed.hpa() > 0 {
+        anyhow::bail!(
+            "The host protected area of {:?} is still there after removing it.",
+            dev_path
+        );
+    }
+    Ok(Some(capacity))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builds_commands() {
+        let cdb = AtaCommand {
+            command: SET_MAX_ADDRESS_EXT,
+            lba: 0x0605_0403_0201,
+            ..Default::default()
+        }
+        .cdb(false);
+        assert_eq!(
+            cdb,
+            [0x85, 0x07, 0x20, 0, 0, 0, 0, 0x04, 0x01, 0x05, 0x02, 0x06, 0x03, 0x40, 0x37, 0]
+        );
+        let cdb = AtaCommand {
+            command: DEVICE_CONFIGURATION,
+            features: DEVICE_CONFIGURATION_IDENTIFY,
+            ..Default::default()
+        }
+        .cdb(true);
+        assert_eq!(cdb[..5], [0x85, 0x09, 0x2e, 0, 0xc2]);
+        assert_eq!(cdb[14], 0xb1);
+    }
+
+    #[test]
+    fn parses_registers() {
+        let mut sense = [0; 22];
+        sense[0] = 0x72;
+        sense[7] = 14;
+        sense[8..22].copy_from_slice(&[
+            0x09, 0x0c, 0x01, 0x00, 0, 0, 0x04, 0x01, 0x05, 0x02, 0x06, 0x03, 0x40, 0x50,
+        ]);
+        let registers = AtaRegisters::from_sense(&sense).unwrap();
+        assert_eq!(registers.lba, 0x0605_0403_0201);
+        assert_eq!(registers.status, 0x50);
+
+        sense[21] = 0x51;
+        assert!(AtaRegisters::from_sense(&sense).is_err());
+        let fixed_format = [0x70, 0, 0x05, 0, 0, 0, 0, 10];
+        let err = AtaRegisters::from_sense(&fixed_format).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(AtaRegisters::from_sense(&[]).is_err());
+    }
+
+    #[test]
+    fn parses_identity() {
+        let mut words = [0; 256];
+        words[82] = 1 << 10;
+        words[83] = 1 << 10 | 1 << 11;
+        words[100..104].copy_from_slice(&[0x5000, 0x0e8e, 0, 0]);
+        let identity = Identity::parse(&words);
+        assert!(identity.lba48 && identity.hpa && identity.dco);
+        assert_eq!(identity.sectors, 0x0e8e_5000);
+        assert_eq!(identity.sector_size, 512);
+
+        words[106] = 0x5000;
+        words[117..119].copy_from_slice(&[2048, 0]);
+        assert_eq!(Identity::parse(&words).sector_size, 4096);
+        // Not valid without bit 14:
+        words[106] = 0x1000;
+        assert_eq!(Identity::parse(&words).sector_size, 512);
+    }
+
+    #[test]
+    fn measures_hidden_capacity() {
+        let capacity = Capacity {
+            accessible: 1000,
+            native: 1500,
+            factory: Some(2000),
+        };
+        assert_eq!(capacity.hpa(), 500);
+        assert_eq!(capacity.dco(), 500);
+        let capacity = Capacity {
+            accessible: 2000,
+            native: 2000,
+            factory: None,
+        };
+        assert_eq!((capacity.hpa(), capacity.dco()), (0, 0));
+    }
+
+    #[test]
+    fn skips_non_ata_devices() {
+        let path = crate::test_util::sparse_file("hpa", 4096);
+        assert_eq!(check(&path, false, || Ok(())).unwrap(), None);
+        assert!(check(&path, true, || Ok(())).is_err());
+    }
+}
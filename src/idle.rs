@@ -0,0 +1,126 @@
+//! Noticing when a device sits idle in the middle of a phase.
+//!
+//! Many external drives spin down after a minute or two without I/O. If
+//! the test leaves gaps that long between two reads or writes, because
+//! the machine is overloaded or --nice starves it of CPU, the drive may
+//! spin down mid-test, and the next I/O pays for spinning it up again.
+//! That shows up as latency spikes and throughput drops that aren't the
+//! drive's fault, or as the drive dropping off the bus entirely.
+//!
+//! While a phase is being watched, the gap between the end of one I/O on
+//! the device and the start of the next is measured, and the first gap
+//! over [IDLE_GAP_WARNING] is warned about. This is only a diagnostic, it
+//! never fails the test. Like the deadline, the watch is per thread, and
+//! each device is tested on its own thread.
+//!
+//! The gaps are an approximation of what the drive sees. The test's I/O
+//! is buffered, so a write only reaches the page cache, and the drive may
+//! be busy with writeback while the test seems idle, or idle while a
+//! write is blocked on a full cache. Only the gaps within the write,
+//! read-back and churn phases are watched, too: not the time between the
+//! phases, nor --warmup, --probe-initial-state and the other probes.
+
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+/// Idle gaps at least this long risk letting an external drive spin down.
+pub(crate) const IDLE_GAP_WARNING: Duration = Duration::from_secs(60);
+
+thread_local! {
+    /// Whether a phase is being watched on this thread.
+    static WATCHING: Cell<bool> = const { Cell::new(false) };
+    /// When the last I/O finished, if one did since the watch started.
+    static LAST_IO: Cell<Option<Instant>> = const { Cell::new(None) };
+    /// The longest gap so far, and whether it was warned about.
+    static LONGEST: Cell<(Duration, bool)> = const { Cell::new((Duration::ZERO, false)) };
+}
+
+/// Watches the gaps between the I/Os of the current thread until dropped.
+#[derive(Debug)]
+pub(crate) struct Watch {
+    phase: &'static str,
+}
+
+impl Watch {
+    pub(crate) fn start(phase: &'static str) -> Self {
+        WATCHING.set(true);
+        LAST_IO.set(None);
+        LONGEST.set((Duration::ZERO, false));
+        Self { phase }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let (longest, _) = LONGEST.get();
+        debug!(
+            phase = self.phase,
+            longest_gap_seconds = longest.as_secs_f64(),
+            "Longest gap between I/Os"
+        );
+        WATCHING.set(false);
+        LAST_IO.set(None);
+    }
+}
+
+/// Marks the start of an I/O on the device, measuring the gap since the
+/// previous one.
+pub(crate) fn io_starting() {
+    if !WATCHING.get() {
+        return;
+    }
+    let Some(last) = LAST_IO.get() else {
+        return;
+    };
+    let gap = last.elapsed();
+    let (longest, warned) = LONGEST.get();
+    if gap <= longest {
+        return;
+    }
+    if gap >= IDLE_GAP_WARNING && !warned {
+        warn!(
+            event = "idle_gap",
+            gap_seconds = gap.as_secs_f64(),
+            "The device sat idle for {} between two I/Os, long enough for some external drives to spin down, which distorts the timings. Check for other load on the machine, don't starve the test with --nice, or disable the drive's idle timer (e.g. with hdparm -S 0).",
+            indicatif::FormattedDuration(gap)
+        );
+        LONGEST.set((gap, true));
+    } else {
+        LONGEST.set((gap, warned));
+    }
+}
+
+/// Marks the end of an I/O on the device.
+pub(crate) fn io_finished() {
+    if WATCHING.get() {
+        LAST_IO.set(Some(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tracing_test::traced_test;
+
+    #[traced_test]
+    #[test]
+    fn warns_about_idle_gaps() {
+        // Not watching:
+        io_finished();
+        io_starting();
+        {
+            let _watch = Watch::start("write");
+            io_finished();
+            io_starting();
+            assert!(!logs_contain("sat idle"));
+            LAST_IO.set(Instant::now().checked_sub(IDLE_GAP_WARNING * 2));
+            io_starting();
+            assert!(logs_contain("sat idle"));
+            assert!(LONGEST.get().0 >= IDLE_GAP_WARNING * 2);
+        }
+        assert!(!WATCHING.get());
+    }
+}
@@ -0,0 +1,424 @@
+//! Writing a device with a prescribed layout of data (--layout-spec).
+//!
+//! To reproduce a failure seen in the field, it helps to recreate the
+//! exact data that was on the device when it happened. A layout spec is
+//! a text file with one region per line:
+//!
+//! ```text
+//! # offset length generator
+//! 0        1M     zeroes
+//! 1M       64K    pattern=checkerboard
+//! 2G       512M   seed=0x2a
+//! ```
+//!
+//! Offsets and lengths are sizes like `4K` or `1GiB`. The generator is
+//! `zeroes`, one of the --pattern-set patterns, or the random data of a
+//! seed, which lines up with the device offsets exactly like in a normal
+//! test run with that seed. Everything outside the regions gets the random
+//! data of the device's own seed. Regions can't overlap.
+//!
+//! The whole device is then read back and checked against the layout.
+
+use crate::{
+    crypto::{GarbageGenerator, Seed},
+    device_error::{DeviceIoError, Operation},
+    events::ProgressEvents,
+    pattern::{Pattern, PATTERN_SET},
+    target::Target,
+    units, PROGRESS_STYLE,
+};
+use anyhow::Context;
+use std::{fmt, fs, fs::OpenOptions, path::Path};
+use tracing::{info_span, warn};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+/// Where the data of a region comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Generator {
+    Zeroes,
+    Pattern(Pattern),
+    Seed(Seed),
+}
+
+impl std::str::FromStr for Generator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "zeroes" {
+            return Ok(Generator::Zeroes);
+        }
+        if let Some(name) = s.strip_prefix("pattern=") {
+            return PATTERN_SET
+                .into_iter()
+                .find(|pattern| pattern.to_string() == name)
+                .map(Generator::Pattern)
+                .ok_or_else(|| format!("unknown pattern {:?}", name));
+        }
+        if let Some(seed) = s.strip_prefix("seed=") {
+            return seed
+                .parse()
+                .map(Generator::Seed)
+                .map_err(|e| format!("invalid seed {:?}: {}", seed, e));
+        }
+        Err(format!(
+            "unknown generator {:?}, expected zeroes, pattern=NAME or seed=SEED",
+            s
+        ))
+    }
+}
+
+impl fmt::Display for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Generator::Zeroes => write!(f, "zeroes"),
+            Generator::Pattern(pattern) => write!(f, "pattern={}", pattern),
+            Generator::Seed(seed) => write!(f, "seed={}", seed),
+        }
+    }
+}
+
+/// A region of the device and the data it holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Region {
+    pub offset: u64,
+    pub length: u64,
+    pub generator: Generator,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+/// The regions of a layout spec, sorted by offset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Layout {
+    pub regions: Vec<Region>,
+}
+
+impl Layout {
+    /// Reads and checks the layout spec at `path`.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Reading the layout spec {:?}", path))?;
+        Self::parse(&contents).with_context(|| format!("Parsing the layout spec {:?}", path))
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut regions = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [offset, length, generator] = fields[..] else {
+                anyhow::bail!(
+                    "Line {}: expected an offset, a length and a generator",
+                    number + 1
+                );
+            };
+            let region = Region {
+                offset: units::parse_bytes(offset).map_err(anyhow::Error::msg)?,
+                length: units::parse_bytes(length).map_err(anyhow::Error::msg)?,
+                generator: generator.parse().map_err(anyhow::Error::msg)?,
+            };
+            anyhow::ensure!(region.length > 0, "Line {}: empty region", number + 1);
+            anyhow::ensure!(
+                region.offset.checked_add(region.length).is_some(),
+                "Line {}: the region ends past the largest possible offset",
+                number + 1
+            );
+            regions.push(region);
+        }
+        regions.sort_by_key(|region| region.offset);
+        for pair in regions.windows(2) {
+            anyhow::ensure!(
+                pair[0].end() <= pair[1].offset,
+                "The regions at offsets {} and {} overlap",
+                pair[0].offset,
+                pair[1].offset
+            );
+        }
+        Ok(Self { regions })
+    }
+}
+
+/// The random data of a seed, without progress reporting.
+type Garbage = GarbageGenerator<fn(u64)>;
+
+/// Produces the data of a [Layout] at any offset of the device.
+struct LayoutData<'a> {
+    layout: &'a Layout,
+    default: Garbage,
+    /// The random data of the regions with a seed, by region index.
+    seeded: Vec<Option<Garbage>>,
+}
+
+impl<'a> LayoutData<'a> {
+    fn new(layout: &'a Layout, default_seed: Seed) -> Self {
+        let generator = |seed| Garbage::new(1, seed, |_| {});
+        Self {
+            layout,
+            default: generator(default_seed),
+            seeded: layout
+                .regions
+                .iter()
+                .map(|region| match region.generator {
+                    Generator::Seed(seed) => Some(generator(seed)),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Fills `buf` with the data the layout puts at `offset`.
+    fn fill(&mut self, offset: u64, buf: &mut [u8]) {
+        let mut start = 0;
+        while start < buf.len() {
+            let position = offset + start as u64;
+            let index = self
+                .layout
+                .regions
+                .partition_point(|region| region.end() <= position);
+            let region = self.layout.regions.get(index);
+            let (generator, end) = match region {
+                Some(region) if region.offset <= position => (Some(region.generator), region.end()),
+                Some(region) => (None, region.offset),
+                None => (None, u64::MAX),
+            };
+            let len = (end - position).min((buf.len() - start) as u64) as usize;
+            let chunk = &mut buf[start..start + len];
+            match generator {
+                None => {
+                    self.default.seek(position);
+                    self.default.fill(chunk);
+                }
+                Some(Generator::Zeroes) => chunk.fill(0),
+                Some(Generator::Pattern(pattern)) => pattern.fill(position, chunk),
+                Some(Generator::Seed(_)) => {
+                    let seeded = self.seeded[index].as_mut().expect("A seeded region");
+                    seeded.seek(position);
+                    seeded.fill(chunk);
+                }
+            }
+            start += len;
+        }
+    }
+}
+
+/// Writes the layout to the first `capacity` bytes of the device (or all
+/// of it), with the data of `default_seed` outside its regions, and syncs
+/// it. Returns the number of bytes written.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity, layout), fields(device = ?dev_path))]
+pub(crate) fn write_layout(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    default_seed: Seed,
+    layout: &Layout,
+) -> anyhow::Result<u64> {
+    let blockdev = OpenOptions::new()
+        .write(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for writing", dev_path))?;
+    write_layout_to(&blockdev, buffer_size, capacity, default_seed, layout)
+}
+
+/// Like [write_layout], but to any [Target].
+pub(crate) fn write_layout_to(
+    target: &dyn Target,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    default_seed: Seed,
+    layout: &Layout,
+) -> anyhow::Result<u64> {
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => target.len()?,
+    };
+    if let Some(region) = layout.regions.last().filter(|r| r.end() > capacity) {
+        anyhow::bail!(
+            "The region at offset {} of the layout spec ends past the {} bytes being tested",
+            region.offset,
+            capacity
+        );
+    }
+
+    let bar_span = info_span!("writing layout");
+    bar_span.pb_set_style(&PROGRESS_STYLE);
+    bar_span.pb_set_length(capacity);
+    let _bar_span_handle = bar_span.enter();
+
+    let events = ProgressEvents::new("write", capacity, 0);
+    let mut data = LayoutData::new(layout, default_seed);
+    let mut buf = vec![0; buffer_size];
+    let mut offset = 0;
+    while offset < capacity {
+        let len = (capacity - offset).min(buffer_size as u64) as usize;
+        data.fill(offset, &mut buf[..len]);
+        let written = target
+            .write_at(&buf[..len], offset)
+            .map_err(|e| DeviceIoError::new(Operation::Write, offset, e))?;
+        if written == 0 {
+            anyhow::bail!(
+                "The device ended after {} bytes, before the capacity of {} bytes could be written",
+                offset,
+                capacity
+            );
+        }
+        offset += written as u64;
+        events.inc(written as u64);
+    }
+    target.sync().context("Syncing the device")?;
+    Ok(offset)
+}
+
+/// Reads back the first `capacity` bytes of the device (or all of it),
+/// comparing them against the layout. Returns the number of bytes read,
+/// and the offsets of the blocks that didn't match.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity, layout), fields(device = ?dev_path))]
+pub(crate) fn verify_layout(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    default_seed: Seed,
+    layout: &Layout,
+) -> anyhow::Result<(u64, Vec<u64>)> {
+    let blockdev = OpenOptions::new()
+        .read(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for reading", dev_path))?;
+    verify_layout_in(&blockdev, buffer_size, capacity, default_seed, layout)
+}
+
+/// Like [verify_layout], but on any [Target].
+pub(crate) fn verify_layout_in(
+    target: &dyn Target,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    default_seed: Seed,
+    layout: &Layout,
+) -> anyhow::Result<(u64, Vec<u64>)> {
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => target.len()?,
+    };
+
+    let bar_span = info_span!("verifying layout");
+    bar_span.pb_set_style(&PROGRESS_STYLE);
+    bar_span.pb_set_length(capacity);
+    let _bar_span_handle = bar_span.enter();
+
+    let events = ProgressEvents::new("read", capacity, 0);
+    let mut data = LayoutData::new(layout, default_seed);
+    let (mut expected, mut buf) = (vec![0; buffer_size], vec![0; buffer_size]);
+    let mut bad_offsets = Vec::new();
+    let mut offset = 0;
+    while offset < capacity {
+        let len = (capacity - offset).min(buffer_size as u64) as usize;
+        let read = target
+            .read_at(&mut buf[..len], offset)
+            .map_err(|e| DeviceIoError::new(Operation::Read, offset, e))?;
+        if read == 0 {
+            anyhow::bail!(
+                "The device ended after {} bytes, before the capacity of {} bytes could be verified",
+                offset,
+                capacity
+            );
+        }
+        data.fill(offset, &mut expected[..read]);
+        if buf[..read] != expected[..read] {
+            warn!(
+                event = "bad_block",
+                offset, "Did not read back the exact bytes of the layout"
+            );
+            bad_offsets.push(offset);
+        }
+        offset += read as u64;
+        events.inc(read as u64);
+    }
+    Ok((offset, bad_offsets))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{read_test::read_back_from, target::MemoryTarget};
+    use tracing_test::traced_test;
+
+    #[test]
+    fn parses_layouts() {
+        let layout = Layout::parse(
+            "# offset length generator\n\n8K 4K seed=7\n0 1K zeroes # the start\n1K 1K pattern=checkerboard\n",
+        )
+        .unwrap();
+        assert_eq!(
+            layout.regions,
+            [
+                Region {
+                    offset: 0,
+                    length: 1024,
+                    generator: Generator::Zeroes
+                },
+                Region {
+                    offset: 1024,
+                    length: 1024,
+                    generator: Generator::Pattern(Pattern::Checkerboard)
+                },
+                Region {
+                    offset: 8192,
+                    length: 4096,
+                    generator: Generator::Seed(7.into())
+                },
+            ]
+        );
+        assert!(Layout::parse("0 2K zeroes\n1K 1K zeroes\n").is_err());
+        assert!(Layout::parse("0 0 zeroes\n").is_err());
+        assert!(Layout::parse("0 1K ones\n").is_err());
+        assert!(Layout::parse("0 1K pattern=stripes\n").is_err());
+        assert!(Layout::parse("0 1K\n").is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn writes_layouts() {
+        let target = MemoryTarget::new(4096 * 8);
+        let layout =
+            Layout::parse("100 5000 zeroes\n8K 2K pattern=walking-ones\n16K 8K seed=7\n").unwrap();
+        let written =
+            write_layout_to(&target, 4096, None, 1.into(), &layout).expect("No io errors");
+        assert_eq!(written, 4096 * 8);
+        target.with_data(|data| {
+            assert!(data[100..5100].iter().all(|&b| b == 0));
+            assert_eq!(data[8192..8196], [0x01, 0x02, 0x04, 0x08]);
+        });
+        let (read, bad) =
+            verify_layout_in(&target, 4096, None, 1.into(), &layout).expect("No io errors");
+        assert_eq!(read, 4096 * 8);
+        assert!(bad.is_empty());
+
+        // Outside its regions, and in regions with a seed, the data is
+        // that of a normal test run:
+        let (_, result) =
+            read_back_from(&target, 4096, None, 1.into(), None, None, None).expect("No io errors");
+        assert_eq!(
+            result.unwrap_err().offsets,
+            [0, 4096, 8192, 4096 * 4, 4096 * 5]
+        );
+        let (_, result) =
+            read_back_from(&target, 4096, None, 7.into(), None, None, None).expect("No io errors");
+        let bad = result.unwrap_err().offsets;
+        assert!(!bad.contains(&(4096 * 4)) && !bad.contains(&(4096 * 5)));
+
+        target.with_data(|data| data[3000] ^= 1);
+        let (_, bad) =
+            verify_layout_in(&target, 4096, None, 1.into(), &layout).expect("No io errors");
+        assert_eq!(bad, vec![0]);
+        assert!(logs_contain("exact bytes of the layout"));
+
+        let past_the_end = Layout::parse("32K 1K zeroes\n").unwrap();
+        assert!(write_layout_to(&target, 4096, None, 1.into(), &past_the_end).is_err());
+    }
+}
@@ -1,29 +1,53 @@
 extern crate block_utils;
-use crate::Args;
+use crate::{priority::IoPriority, units::parse_cpu_list, Args};
+use anyhow::Context;
 use std::{
+    fs,
+    os::unix::fs::{FileTypeExt, MetadataExt},
     path::{Path, PathBuf},
     str::FromStr,
 };
-use tracing::warn;
+use tracing::{debug, info, warn};
 
 #[derive(Debug, Clone)]
 pub(crate) struct ValidDevice {
     pub path: PathBuf,
     pub partition: Option<u64>,
-    pub device: block_utils::Device,
+    /// The block device under test, or `None` if the path is a regular file.
+    pub device: Option<block_utils::Device>,
+    /// The character device (e.g. /dev/sg1) that was given on the command
+    /// line, if the device is tested through the block device behind it.
+    pub char_device: Option<PathBuf>,
 }
 
 impl FromStr for ValidDevice {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (partition, device) = block_utils::get_device_from_path(s)?;
+        let mut path = PathBuf::from(s);
+        if path.is_file() {
+            return Ok(Self {
+                path,
+                partition: None,
+                device: None,
+                char_device: None,
+            });
+        }
+        let mut char_device = None;
+        if path.metadata()?.file_type().is_char_device() {
+            // SCSI generic devices can't be read and written like files, so
+            // we test the block device behind them instead:
+            let block_path = block_device_for_char_device(&path)?;
+            char_device = Some(std::mem::replace(&mut path, block_path));
+        }
+        let (partition, device) = block_utils::get_device_from_path(&path)?;
         Ok(Self {
-            path: PathBuf::from(s),
+            path,
+            char_device,
             partition,
-            device: device.ok_or(anyhow::anyhow!(
-                "The device under test must be a valid block device."
-            ))?,
+            device: Some(device.ok_or(anyhow::anyhow!(
+                "The device under test must be a valid block device (or a regular file, with --file-device)."
+            ))?),
         })
     }
 }
@@ -32,8 +56,18 @@ pub(crate) fn sanity_checks(
     args: &Args,
     partition: Option<u64>,
     device_path: &Path,
+    char_device: Option<&Path>,
     device: &block_utils::Device,
 ) -> anyhow::Result<()> {
+    let kind = match (char_device, partition) {
+        (Some(char_device), _) => format!(
+            "a character device ({:?}), tested through its block device",
+            char_device
+        ),
+        (None, Some(_)) => "a partition".to_string(),
+        (None, None) => "a whole-disk block device".to_string(),
+    };
+    info!(?device_path, kind, "Detected device");
     // Sanity checks:
     if partition.is_some() {
         if !args.allow_any_block_device {
@@ -48,22 +82,543 @@ pub(crate) fn sanity_checks(
     }
     if device.media_type != block_utils::MediaType::Rotational {
         if !args.allow_any_media {
-            anyhow::bail!("Device is not a rotational disk - this tool may be harmful to solid-state drives and others! Pass --allow-any-media to run anyway.");
+            anyhow::bail!("Device ({}) is not a rotational disk - this tool may be harmful to solid-state drives and others! Pass --allow-any-media to run anyway.", kind);
         } else {
             warn!(?device.media_type, ?device_path, "Media type is not as expected but running tests anyway.");
         }
     }
-    let child_partitions: Vec<PathBuf> = block_utils::get_block_partitions_iter()?
-        .filter(|part_path| {
-            part_path
-                .file_name()
-                .map(|name| name.to_string_lossy().starts_with(&device.name))
-                .unwrap_or(false)
+    // Partitions can't have child partitions, but they (and their
+    // siblings, like sda10 for sda1) would match the name check below:
+    if partition.is_none() {
+        let child_partitions: Vec<PathBuf> = block_utils::get_block_partitions_iter()?
+            .filter(|part_path| {
+                part_path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().starts_with(&device.name))
+                    .unwrap_or(false)
+            })
+            .collect();
+
+        if !child_partitions.is_empty() {
+            anyhow::bail!("Detected child partitions on the device - I won't help you destroy an in-use drive: Delete those partitions yourself. Partitions found: {:?}", child_partitions);
+        }
+    }
+    match zoned_model(&device.name).as_deref() {
+        Some("host-managed") => anyhow::bail!("{:?} is a host-managed zoned device (e.g. an SMR or ZNS drive). These only accept sequential writes at each zone's write pointer, after the zone has been reset, so disk-spinner's writes would be rejected and the device can't be tested.", device_path),
+        Some("host-aware") if args.random_write_order => {
+            warn!(?device_path, "Device is a host-aware zoned device, random-order writes will be very slow.");
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Returns the zoned model of a block device ("none", "host-aware" or
+/// "host-managed"), if the kernel reports one.
+fn zoned_model(name: &str) -> Option<String> {
+    // Partitions don't have a queue of their own, their disk does:
+    let path = Path::new("/sys/class/block")
+        .join(disk_name(name))
+        .join("queue/zoned");
+    Some(fs::read_to_string(path).ok()?.trim().to_string())
+}
+
+/// The I/O size that the kernel reports as best for a block device, if
+/// any, and which queue attribute it came from.
+///
+/// The optimal I/O size is e.g. the stripe width of a RAID array. The
+/// minimum I/O size is only used if it's larger than the physical block
+/// size, as it's otherwise the same.
+pub(crate) fn preferred_io_size(device: &block_utils::Device) -> Option<(u64, &'static str)> {
+    let queue = Path::new("/sys/class/block")
+        .join(disk_name(&device.name))
+        .join("queue");
+    // Zero means that the device doesn't report it:
+    let read = |name: &str| -> Option<u64> {
+        let size = fs::read_to_string(queue.join(name)).ok()?;
+        size.trim().parse().ok().filter(|&size| size > 0)
+    };
+    read("optimal_io_size")
+        .map(|size| (size, "optimal I/O size"))
+        .or_else(|| {
+            read("minimum_io_size")
+                .filter(|&size| device.physical_block_size.is_some_and(|pbs| size > pbs))
+                .map(|size| (size, "minimum I/O size"))
+        })
+}
+
+/// Whether the device has spinning platters.
+pub(crate) fn is_rotational(device: &block_utils::Device) -> bool {
+    device.media_type == block_utils::MediaType::Rotational
+}
+
+/// The USB storage driver ("uas" or "usb-storage") of the USB bridge
+/// that a block device is behind, if it's behind one.
+pub(crate) fn usb_bridge(device: &block_utils::Device) -> Option<String> {
+    let sys_path =
+        fs::canonicalize(Path::new("/sys/class/block").join(disk_name(&device.name))).ok()?;
+    usb_storage_driver(&sys_path)
+}
+
+/// Looks for a USB storage driver bound to any of the parents of a
+/// device in sysfs, like the interface in
+/// .../usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0/block/sdb.
+fn usb_storage_driver(sys_path: &Path) -> Option<String> {
+    sys_path.ancestors().find_map(|dir| {
+        let driver = fs::read_link(dir.join("driver")).ok()?;
+        let name = driver.file_name()?.to_string_lossy().into_owned();
+        ["uas", "usb-storage"]
+            .contains(&name.as_str())
+            .then_some(name)
+    })
+}
+
+/// Finds the block device that backs a character device, like the
+/// /dev/sdb behind the SCSI generic device /dev/sg1.
+fn block_device_for_char_device(path: &Path) -> anyhow::Result<PathBuf> {
+    let rdev = path.metadata()?.rdev();
+    // SAFETY: these only do arithmetic on the device number.
+    let (major, minor) = unsafe { (libc::major(rdev), libc::minor(rdev)) };
+    let sys_path = PathBuf::from(format!("/sys/dev/char/{}:{}/device/block", major, minor));
+    let name = fs::read_dir(&sys_path)
+        .ok()
+        .and_then(|mut entries| entries.next()?.ok())
+        .map(|entry| entry.file_name());
+    match name {
+        Some(name) => Ok(Path::new("/dev").join(name)),
+        None => anyhow::bail!(
+            "{:?} is a character device without a block device behind it. Only character devices that expose a disk's block device (like SCSI generic /dev/sg* devices) can be tested.",
+            path
+        ),
+    }
+}
+
+/// Returns the name of the whole disk that a block device (e.g. "sda1") is on.
+fn disk_name(name: &str) -> String {
+    let sys_path = Path::new("/sys/class/block").join(name);
+    if sys_path.join("partition").exists() {
+        // Partitions live in a subdirectory of their disk, e.g. .../block/sda/sda1:
+        if let Some(disk) = fs::canonicalize(&sys_path)
+            .ok()
+            .and_then(|path| Some(path.parent()?.file_name()?.to_string_lossy().into_owned()))
+        {
+            return disk;
+        }
+    }
+    name.to_string()
+}
+
+/// Refuses to test the same device twice, or a disk along with one of
+/// its own partitions, in one invocation.
+pub(crate) fn check_overlaps(devices: &[ValidDevice]) -> anyhow::Result<()> {
+    let identities = devices
+        .iter()
+        .map(|d| Ok((d.path.as_path(), Identity::of(&d.path)?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if let Some((identity, aliases)) = find_aliases(&identities) {
+        anyhow::bail!(
+            "{:?} are all the same {} - testing it more than once at a time would corrupt the tests.",
+            aliases,
+            identity
+        );
+    }
+    let block_devices: Vec<(&Path, String, String)> = devices
+        .iter()
+        .filter_map(|d| {
+            let name = &d.device.as_ref()?.name;
+            Some((d.path.as_path(), name.clone(), disk_name(name)))
         })
         .collect();
+    if let Some((a, b)) = find_overlap(&block_devices) {
+        anyhow::bail!("{:?} and {:?} overlap (they are the same device, or one is a partition of the other) - testing both at once would corrupt both tests.", a, b);
+    }
+    Ok(())
+}
+
+/// What makes two paths the same device under test, however they're spelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Identity {
+    /// A block device, by its device number.
+    BlockDevice { major: u32, minor: u32 },
+    /// A regular file, by its inode.
+    File { dev: u64, ino: u64 },
+}
+
+impl Identity {
+    fn of(path: &Path) -> anyhow::Result<Self> {
+        // This follows symlinks, like /dev/disk/by-id/...
+        let metadata = path
+            .metadata()
+            .with_context(|| format!("Reading the metadata of {:?}", path))?;
+        if metadata.file_type().is_block_device() {
+            let rdev = metadata.rdev();
+            // SAFETY: these only do arithmetic on the device number.
+            let (major, minor) = unsafe { (libc::major(rdev), libc::minor(rdev)) };
+            Ok(Identity::BlockDevice { major, minor })
+        } else {
+            Ok(Identity::File {
+                dev: metadata.dev(),
+                ino: metadata.ino(),
+            })
+        }
+    }
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Identity::BlockDevice { major, minor } => write!(f, "block device {}:{}", major, minor),
+            Identity::File { ino, .. } => write!(f, "file (inode {})", ino),
+        }
+    }
+}
+
+/// Finds the first device that was given under more than one path, and
+/// returns all paths it was given as.
+fn find_aliases<'a>(devices: &[(&'a Path, Identity)]) -> Option<(Identity, Vec<&'a Path>)> {
+    devices.iter().find_map(|(_, identity)| {
+        let aliases: Vec<&Path> = devices
+            .iter()
+            .filter(|(_, other)| other == identity)
+            .map(|(path, _)| *path)
+            .collect();
+        (aliases.len() > 1).then_some((*identity, aliases))
+    })
+}
 
-    if child_partitions.len() > 0 {
-        anyhow::bail!("Detected child partitions on the device - I won't help you destroy an in-use drive: Delete those partitions yourself. Partitions found: {:?}", child_partitions);
+/// Finds a pair of overlapping devices, given their paths, names and disk names.
+fn find_overlap<'a>(devices: &[(&'a Path, String, String)]) -> Option<(&'a Path, &'a Path)> {
+    for (i, (path_a, name_a, disk_a)) in devices.iter().enumerate() {
+        for (path_b, name_b, disk_b) in &devices[i + 1..] {
+            if name_a == name_b || name_a == disk_b || name_b == disk_a {
+                return Some((path_a, path_b));
+            }
+        }
+    }
+    None
+}
+
+/// Pins the current thread to the CPUs of the NUMA node closest to a
+/// block device, until the returned guard is dropped.
+///
+/// Memory is allocated on the node of the CPU that first touches it, so
+/// this makes the buffers allocated while testing the device local to
+/// its PCIe root. Returns `None` on single-node systems, and when the
+/// device's node is unknown.
+pub(crate) fn bind_to_numa_node(
+    device: &block_utils::Device,
+) -> anyhow::Result<Option<CpuBinding>> {
+    let node_dir = Path::new("/sys/devices/system/node");
+    let nodes = match fs::read_dir(node_dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .filter(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                name.strip_prefix("node")
+                    .is_some_and(|n| n.parse::<u32>().is_ok())
+            })
+            .count(),
+        Err(_) => 0,
+    };
+    if nodes < 2 {
+        debug!(device = device.name, "Single NUMA node, ignoring --numa");
+        return Ok(None);
+    }
+    // The numa_node attribute lives on the PCI device that the disk hangs off:
+    let sys_path = fs::canonicalize(Path::new("/sys/class/block").join(&device.name))?;
+    let node = sys_path.ancestors().find_map(|dir| {
+        let node = fs::read_to_string(dir.join("numa_node")).ok()?;
+        node.trim().parse::<u32>().ok()
+    });
+    let Some(node) = node else {
+        warn!(
+            device = device.name,
+            "Could not find the device's NUMA node, ignoring --numa"
+        );
+        return Ok(None);
+    };
+    let cpu_list = fs::read_to_string(node_dir.join(format!("node{}/cpulist", node)))?;
+    let cpus = parse_cpu_list(&cpu_list).map_err(anyhow::Error::msg)?;
+    let binding = set_affinity(&cpus)?;
+    info!(
+        device = device.name,
+        node,
+        cpus = cpu_list.trim(),
+        "Bound to the device's NUMA node"
+    );
+    Ok(Some(binding))
+}
+
+/// Pins the current thread, which tests one device, to the given CPUs
+/// (with --cpu-affinity).
+pub(crate) fn bind_to_cpus(cpus: &[usize]) -> anyhow::Result<Option<CpuBinding>> {
+    let binding = set_affinity(cpus).with_context(|| format!("Pinning to the CPUs {:?}", cpus))?;
+    info!(?cpus, "Pinned to CPUs");
+    Ok(Some(binding))
+}
+
+/// Sets the CPU affinity of the current thread, until the returned
+/// binding is dropped.
+fn set_affinity(cpus: &[usize]) -> std::io::Result<CpuBinding> {
+    // SAFETY: cpu_set_t is a plain bitmask, and both calls are given its real size.
+    unsafe {
+        let mut previous: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut previous) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus.iter().filter(|&&cpu| cpu < libc::CPU_SETSIZE as usize) {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(CpuBinding { previous })
+    }
+}
+
+/// Restores the thread's previous CPU affinity when dropped.
+pub(crate) struct CpuBinding {
+    previous: libc::cpu_set_t,
+}
+
+impl Drop for CpuBinding {
+    fn drop(&mut self) {
+        // SAFETY: see set_affinity.
+        unsafe {
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &self.previous);
+        }
+    }
+}
+
+/// Sets the I/O priority and nice level of the current thread.
+///
+/// The nice level covers generating the data. The I/O priority covers
+/// the reads, but not the writes: those go to the page cache, and the
+/// kernel writes them back to the device without the thread's priority.
+pub(crate) fn set_thread_priority(
+    io_priority: Option<IoPriority>,
+    nice: Option<i32>,
+) -> anyhow::Result<()> {
+    if let Some(io_priority) = io_priority {
+        // From linux/ioprio.h:
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_SHIFT: u32 = 13;
+        let (class, level) = match io_priority {
+            IoPriority::Realtime(level) => (1, level),
+            IoPriority::BestEffort(level) => (2, level),
+            IoPriority::Idle => (3, 0),
+        };
+        let ioprio = (class << IOPRIO_CLASS_SHIFT) | level as libc::c_int;
+        // SAFETY: ioprio_set takes plain integers. A `who` of 0 is the calling thread.
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Setting the I/O priority to {:?}", io_priority));
+        }
+        debug!(?io_priority, "Set the I/O priority");
+    }
+    if let Some(nice) = nice {
+        // SAFETY: setpriority takes plain integers. On Linux, a `who` of 0
+        // with PRIO_PROCESS is the calling thread, not the whole process.
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Setting the nice level to {}", nice));
+        }
+        debug!(nice, "Set the nice level");
     }
     Ok(())
 }
+
+/// Evicts a range of a file or device from the page cache, so that the
+/// next read of it comes from the device itself.
+pub(crate) fn drop_cache(file: &fs::File, offset: u64, len: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: posix_fadvise only takes an open file descriptor and integers.
+    let err = unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        )
+    };
+    match err {
+        0 => Ok(()),
+        err => Err(std::io::Error::from_raw_os_error(err)),
+    }
+}
+
+/// The sg_io_hdr struct of the SG_IO ioctl, from <scsi/sg.h>.
+#[repr(C)]
+struct SgIoHdr {
+    interface_id: libc::c_int,
+    dxfer_direction: libc::c_int,
+    cmd_len: libc::c_uchar,
+    mx_sb_len: libc::c_uchar,
+    iovec_count: libc::c_ushort,
+    dxfer_len: libc::c_uint,
+    dxferp: *mut libc::c_void,
+    cmdp: *const libc::c_uchar,
+    sbp: *mut libc::c_uchar,
+    timeout: libc::c_uint,
+    flags: libc::c_uint,
+    pack_id: libc::c_int,
+    usr_ptr: *mut libc::c_void,
+    status: libc::c_uchar,
+    masked_status: libc::c_uchar,
+    msg_status: libc::c_uchar,
+    sb_len_wr: libc::c_uchar,
+    host_status: libc::c_ushort,
+    driver_status: libc::c_ushort,
+    resid: libc::c_int,
+    duration: libc::c_uint,
+    info: libc::c_uint,
+}
+
+const SG_IO: libc::c_ulong = 0x2285;
+const SG_DXFER_NONE: libc::c_int = -1;
+const SG_DXFER_FROM_DEV: libc::c_int = -3;
+/// The driver_status bit that only says that there is sense data.
+const DRIVER_SENSE: libc::c_ushort = 0x08;
+
+/// Sends a SCSI command (like an ATA PASS-THROUGH) to a device with the
+/// SG_IO ioctl, reading `data` from the device if it's not empty, and
+/// returns the sense data.
+pub(crate) fn scsi_command(
+    file: &fs::File,
+    cdb: &[u8; 16],
+    data: &mut [u8],
+) -> std::io::Result<Vec<u8>> {
+    use std::os::unix::io::AsRawFd;
+    let mut sense = vec![0; 32];
+    let mut hdr = SgIoHdr {
+        interface_id: 'S' as libc::c_int,
+        dxfer_direction: match data.is_empty() {
+            true => SG_DXFER_NONE,
+            false => SG_DXFER_FROM_DEV,
+        },
+        cmd_len: cdb.len() as libc::c_uchar,
+        mx_sb_len: sense.len() as libc::c_uchar,
+        iovec_count: 0,
+        dxfer_len: data.len() as libc::c_uint,
+        dxferp: data.as_mut_ptr().cast(),
+        cmdp: cdb.as_ptr(),
+        sbp: sense.as_mut_ptr(),
+        timeout: 10_000,
+        flags: 0,
+        pack_id: 0,
+        usr_ptr: std::ptr::null_mut(),
+        status: 0,
+        masked_status: 0,
+        msg_status: 0,
+        sb_len_wr: 0,
+        host_status: 0,
+        driver_status: 0,
+        resid: 0,
+        duration: 0,
+        info: 0,
+    };
+    // SAFETY: the header points to buffers that outlive the ioctl, with
+    // their actual lengths.
+    if unsafe { libc::ioctl(file.as_raw_fd(), SG_IO, &mut hdr) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if hdr.host_status != 0 || hdr.driver_status & !DRIVER_SENSE != 0 {
+        return Err(std::io::Error::other(format!(
+            "the SCSI command failed with host status {:#x} and driver status {:#x}",
+            hdr.host_status, hdr.driver_status
+        )));
+    }
+    sense.truncate(hdr.sb_len_wr as usize);
+    Ok(sense)
+}
+
+/// Makes the kernel read the capacity of a disk again, e.g. after its
+/// host protected area was removed.
+pub(crate) fn rescan_capacity(device: &block_utils::Device) -> std::io::Result<()> {
+    let rescan = Path::new("/sys/class/block")
+        .join(disk_name(&device.name))
+        .join("device/rescan");
+    fs::write(rescan, "1")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_aliases, find_overlap, usb_storage_driver, Identity};
+    use std::{fs, path::Path};
+
+    fn dev(name: &'static str, disk: &str) -> (&'static Path, String, String) {
+        (Path::new(name), name.to_string(), disk.to_string())
+    }
+
+    #[test]
+    fn finds_overlaps() {
+        let sda = dev("sda", "sda");
+        let sda1 = dev("sda1", "sda");
+        let sda2 = dev("sda2", "sda");
+        let sdb = dev("sdb", "sdb");
+        assert_eq!(
+            find_overlap(&[sda1.clone(), sda2.clone(), sdb.clone()]),
+            None
+        );
+        assert_eq!(
+            find_overlap(&[sdb.clone(), sda1.clone(), sda.clone()]),
+            Some((Path::new("sda1"), Path::new("sda")))
+        );
+        assert_eq!(
+            find_overlap(&[sda2.clone(), sdb, sda2]),
+            Some((Path::new("sda2"), Path::new("sda2")))
+        );
+    }
+
+    #[test]
+    fn finds_aliases() {
+        let sda = Identity::BlockDevice { major: 8, minor: 0 };
+        let sdb = Identity::BlockDevice {
+            major: 8,
+            minor: 16,
+        };
+        let file = Identity::File { dev: 8, ino: 16 };
+        assert_eq!(
+            find_aliases(&[
+                (Path::new("/dev/sda"), sda),
+                (Path::new("/dev/sdb"), sdb),
+                (Path::new("f"), file)
+            ]),
+            None
+        );
+        assert_eq!(
+            find_aliases(&[
+                (Path::new("/dev/disk/by-id/wwn-1"), sda),
+                (Path::new("/dev/sdb"), sdb),
+                (Path::new("/dev/sda"), sda),
+            ]),
+            Some((
+                sda,
+                vec![Path::new("/dev/disk/by-id/wwn-1"), Path::new("/dev/sda")]
+            ))
+        );
+    }
+
+    #[test]
+    fn identifies_files() {
+        let path = crate::test_util::sparse_file("identity", 0);
+        let link = path.with_extension("link");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        assert_eq!(Identity::of(&path).unwrap(), Identity::of(&link).unwrap());
+        fs::remove_file(link).unwrap();
+    }
+
+    #[test]
+    fn finds_usb_bridges() {
+        let sys = crate::test_util::temp_dir("sysfs");
+        let interface = sys.join("usb2/2-1/2-1:1.0");
+        let scsi_device = interface.join("host6/target6:0:0/6:0:0:0");
+        let disk = scsi_device.join("block/sdb");
+        fs::create_dir_all(&disk).unwrap();
+        std::os::unix::fs::symlink("../../drivers/sd", scsi_device.join("driver")).unwrap();
+        assert_eq!(usb_storage_driver(&disk), None);
+        std::os::unix::fs::symlink("../../../drivers/uas", interface.join("driver")).unwrap();
+        assert_eq!(usb_storage_driver(&disk).as_deref(), Some("uas"));
+    }
+}
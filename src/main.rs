@@ -1,57 +1,524 @@
-use std::path::PathBuf;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use indicatif::ProgressStyle;
 use rand::prelude::*;
-use rayon::iter::Either;
 use rayon::prelude::*;
+use tracing::debug;
 use tracing::error;
 use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+use tracing::Span;
 use tracing_indicatif::IndicatifLayer;
+use tracing_subscriber::filter::{filter_fn, LevelFilter};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+use crypto::Seed;
 
 #[macro_use]
 extern crate lazy_static;
 
+mod blank;
+mod block_log;
+mod build_info;
+mod checkpoint;
+mod churn;
+mod clean;
 mod crypto;
+mod deadline;
+mod device_error;
+mod estimate;
+mod events;
+mod fraud;
+mod health;
+mod hpa;
+mod idle;
+mod layout;
+mod manifest;
+mod order;
+mod pattern;
+mod policy;
+mod preserve;
+mod priority;
 mod read_test;
+mod report;
+mod self_test;
+mod speed_class;
+mod syslog;
+mod target;
+mod units;
 mod write_test;
+mod zones;
+
+#[cfg(test)]
+mod test_util;
 
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
+use linux::bind_to_cpus;
+#[cfg(target_os = "linux")]
+use linux::bind_to_numa_node;
+#[cfg(target_os = "linux")]
+use linux::check_overlaps;
+#[cfg(target_os = "linux")]
+use linux::drop_cache;
+#[cfg(target_os = "linux")]
+use linux::is_rotational;
+#[cfg(target_os = "linux")]
+use linux::preferred_io_size;
+#[cfg(target_os = "linux")]
+use linux::rescan_capacity;
+#[cfg(target_os = "linux")]
 use linux::sanity_checks;
 #[cfg(target_os = "linux")]
+use linux::scsi_command;
+#[cfg(target_os = "linux")]
+use linux::set_thread_priority;
+#[cfg(target_os = "linux")]
+use linux::usb_bridge;
+#[cfg(target_os = "linux")]
 use linux::ValidDevice;
 
 #[cfg(not(target_os = "linux"))]
 mod other_os;
 #[cfg(not(target_os = "linux"))]
+use other_os::bind_to_cpus;
+#[cfg(not(target_os = "linux"))]
+use other_os::bind_to_numa_node;
+#[cfg(not(target_os = "linux"))]
+use other_os::check_overlaps;
+#[cfg(not(target_os = "linux"))]
+use other_os::drop_cache;
+#[cfg(not(target_os = "linux"))]
+use other_os::is_rotational;
+#[cfg(not(target_os = "linux"))]
+use other_os::preferred_io_size;
+#[cfg(not(target_os = "linux"))]
+use other_os::rescan_capacity;
+#[cfg(not(target_os = "linux"))]
 use other_os::sanity_checks;
 #[cfg(not(target_os = "linux"))]
+use other_os::scsi_command;
+#[cfg(not(target_os = "linux"))]
+use other_os::set_thread_priority;
+#[cfg(not(target_os = "linux"))]
+use other_os::usb_bridge;
+#[cfg(not(target_os = "linux"))]
 use other_os::ValidDevice;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version = concat!(env!("CARGO_PKG_VERSION"), " (", env!("DISK_SPINNER_GIT_COMMIT"), ")"),
+    about,
+    long_about = None
+)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub(crate) struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Name of the devices to test.
     ///
     /// Each should be a mechanical disk block device (e.g. /dev/sda,
-    /// /dev/disk/by-id/wwn-...).
+    /// /dev/disk/by-id/wwn-...), or a regular file if --file-device is
+    /// given. On Linux, a SCSI generic character device (e.g. /dev/sg1)
+    /// is tested through the block device behind it.
     #[clap(value_parser = clap::value_parser!(ValidDevice), num_args = 1..)]
     devices: Vec<ValidDevice>,
 
-    /// Number of bytes to buffer for writing.
+    /// Number of bytes to buffer for writing, e.g. 4096, 64K or 1MiB.
     ///
-    /// Defaults to the physical block size of the device (or 8192 if that is unset).
-    #[clap(long)]
+    /// Defaults to the optimal I/O size that the device reports, or else its
+    /// physical block size (or 8192 if neither is known and plausible), but
+    /// at most 64K for devices behind a USB bridge. Giving it explicitly
+    /// lifts that cap.
+    /// Units: K/KiB = 1024 and KB = 1000 bytes, and so on for M, G and T.
+    #[clap(long, value_parser = units::parse_buffer_size)]
     buffer_size: Option<usize>,
 
+    /// Number of bytes to test on each device, e.g. 500GB or 4TiB.
+    ///
+    /// Defaults to the entire device, or the current size of the file with --file-device.
+    /// Units: K/KiB = 1024 and KB = 1000 bytes, and so on for M, G and T.
+    #[clap(long, value_parser = units::parse_bytes)]
+    capacity: Option<u64>,
+
     /// Random seed to use for generating random data. By default, this tool generates its own.
+    ///
+    /// Either a decimal number, or up to 64 hex digits after 0x for a
+    /// full 256-bit seed. Seeds are logged in a form that can be passed back.
+    #[clap(long)]
+    seed: Option<Seed>,
+
+    /// Draw a full 256-bit seed from the OS random number generator
+    /// (e.g. /dev/urandom), instead of a 64-bit one from the default
+    /// generator.
+    ///
+    /// The seed is logged at the start, printed under the summary, and
+    /// recorded in --json-report, so that --verify-only can check the
+    /// data later. A new seed can't match data that is already on the
+    /// device, so this can't be combined with --resume or verification.
+    #[clap(long, conflicts_with_all = ["seed", "resume", "verify_only", "verify_manifest"])]
+    os_random: bool,
+
+    /// Periodically save the progress of the write test to a checkpoint
+    /// file in this directory.
+    ///
+    /// Checkpoints are removed once a device's test has finished.
+    #[clap(long, value_name = "DIR")]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// Resume the write test from the checkpoint in --checkpoint-dir,
+    /// if there is one.
+    #[clap(long, requires = "checkpoint_dir")]
+    resume: bool,
+
+    /// Resume from a checkpoint even if the device now reports a different
+    /// capacity than when the checkpoint was written.
+    ///
+    /// By default that's refused: the device may have been resized (e.g.
+    /// by changing its HPA), or be a different drive in the same slot, and
+    /// resuming would verify the wrong data or miss some of it.
+    #[clap(long, requires = "resume")]
+    force_capacity_mismatch: bool,
+
+    /// Only write to the device, skipping the read-back verification.
+    ///
+    /// This is for filling a disk with garbage when you don't care
+    /// whether it can be read back (e.g. before setting up encryption):
+    /// it verifies nothing about data integrity. Requires
+    /// --i-understand-nothing-gets-verified.
+    #[clap(
+        long,
+        requires = "i_understand_nothing_gets_verified",
+        conflicts_with_all = ["export_manifest", "verify_manifest"]
+    )]
+    no_read_back: bool,
+
+    /// Acknowledge that --no-read-back does not check the device's data integrity.
+    #[clap(long, requires = "no_read_back")]
+    i_understand_nothing_gets_verified: bool,
+
+    /// Write the device's blocks in a shuffled order instead of sequentially.
+    ///
+    /// The order is derived from the seed, and every block gets the same
+    /// data it would get in a sequential write, so it is read back
+    /// sequentially as usual. Random writes can surface controller
+    /// remapping bugs that sequential writes don't. Can't be combined
+    /// with checkpoints.
+    #[clap(long, conflicts_with_all = ["checkpoint_dir", "resume"])]
+    random_write_order: bool,
+
+    /// While writing, read back this percentage of the written buffers
+    /// right away, failing the device on the first mismatch.
+    ///
+    /// This finds grossly broken devices early, without waiting for the
+    /// read-back test. The reads are likely served from a cache though,
+    /// so passing them proves little: they are NOT a substitute for the
+    /// read-back test, which still runs as usual.
+    #[clap(long, value_name = "PERCENT", value_parser = units::parse_percent, conflicts_with_all = ["random_write_order", "churn", "verify_only"])]
+    sanity_reads: Option<f64>,
+
+    /// With --random-write-order, keep consecutive writes at most this
+    /// many bytes apart, e.g. 1GiB.
+    ///
+    /// The blocks are then shuffled within consecutive stretches of the
+    /// device, which still covers all of it, but simulates a more local
+    /// workload and limits how far the actuator has to travel. Must be at
+    /// least the buffer size.
+    #[clap(long, value_name = "BYTES", requires = "random_write_order", value_parser = units::parse_bytes)]
+    max_offset_jump: Option<u64>,
+
+    /// Write the device a few blocks at a time, overwriting each window
+    /// with its inverse and then rewriting it, and verifying the window
+    /// after each step.
+    ///
+    /// This stresses the drive's caching and remapping more than a clean
+    /// write. Mismatches are logged with the steps that led to them. The
+    /// whole device is read back as usual afterwards.
+    #[clap(long, conflicts_with_all = ["random_write_order", "checkpoint_dir", "resume", "verify_only", "no_read_back"])]
+    churn: bool,
+
+    /// Also report which SD card speed classes (Class 10, U3, V30, A1, A2
+    /// and so on) the device appears to meet.
+    ///
+    /// Random 4 KiB reads and writes are timed before the write test, and
+    /// the write test's own speed stands in for the sequential speed. This
+    /// is only an APPROXIMATION of the official test methodology.
+    #[clap(long, conflicts_with_all = ["churn", "random_write_order", "resume", "verify_only", "passes", "repeat_until_fail", "pattern_set", "verify_blank", "verify_manifest"])]
+    speed_class: bool,
+
+    /// Skip the write test, and only read back the data that an earlier
+    /// run wrote with the given --seed.
+    ///
+    /// This does not write to the device, so it works on write-protected
+    /// devices too.
+    #[clap(
+        long,
+        requires = "seed",
+        conflicts_with_all = ["no_read_back", "verify_manifest", "resume", "random_write_order"]
+    )]
+    verify_only: bool,
+
+    /// Give each device its own data, derived from the seed and the
+    /// device's serial number (or its path, if it has none).
+    ///
+    /// When verifying, pass the same --seed and --key-by-serial. A device
+    /// that fails --verify-only is then checked against the data of the
+    /// other devices under test, to catch drives that were swapped.
     #[clap(long)]
-    seed: Option<u64>,
+    key_by_serial: bool,
+
+    /// Before writing, sample a few blocks of each device to report
+    /// whether it was blank (zeroes or erased) or held data.
+    ///
+    /// A device that reads as zeroes throughout may be new, trimmed, or
+    /// thinly provisioned.
+    #[clap(long, conflicts_with_all = ["verify_only", "verify_manifest"])]
+    probe_initial_state: bool,
+
+    /// I/O scheduling class and level for the test, like ionice: `idle`,
+    /// `best-effort[:LEVEL]` or `realtime[:LEVEL]`, with levels from 0
+    /// (highest) to 7. Use `idle` to yield to other workloads on the host.
+    ///
+    /// This only applies to reading the device back: the writes are
+    /// buffered, and the kernel writes them back at its own priority.
+    /// Defaults to the normal priority. Linux only.
+    #[clap(long, value_name = "CLASS[:LEVEL]")]
+    io_priority: Option<priority::IoPriority>,
+
+    /// Nice level for the test, from -20 (highest priority) to 19. Linux only.
+    #[clap(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    nice: Option<i32>,
+
+    /// Spin up each drive by reading from it for this long before the
+    /// test starts, e.g. 30s or 2m, so that spin-up doesn't skew the
+    /// measured throughput.
+    #[clap(long, value_name = "DURATION", value_parser = units::parse_duration)]
+    warmup: Option<Duration>,
+
+    /// Stop testing a device once it has taken this long, e.g. 36h, and
+    /// mark it uncertain. Other devices carry on.
+    ///
+    /// This is for devices that are pathologically slow rather than hung.
+    /// A single slow I/O can't be interrupted, so the device stops at its
+    /// first I/O after the budget runs out.
+    #[clap(long, value_name = "DURATION", value_parser = units::parse_duration)]
+    max_runtime_per_device: Option<Duration>,
+
+    /// Repeat the test with a fresh seed for each iteration, until a
+    /// device fails (or you stop it).
+    ///
+    /// This is meant for soak testing and chasing intermittent failures.
+    /// The seed of the failing iteration is logged and reported, to pass
+    /// back with --seed to reproduce it.
+    #[clap(
+        long,
+        conflicts_with_all = ["resume", "no_read_back", "verify_only", "export_manifest", "verify_manifest"]
+    )]
+    repeat_until_fail: bool,
+
+    /// Run the test this many times in a row, with different data each
+    /// time, stopping at the first pass that fails.
+    ///
+    /// The passes before the last use seeds derived from --seed, and are
+    /// verified as --intermediate-verify says. The last one writes the
+    /// data of --seed itself, and is always verified in full.
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all = ["repeat_until_fail", "resume", "no_read_back", "verify_only", "export_manifest", "verify_manifest", "verify_blank"]
+    )]
+    passes: Option<u64>,
+
+    /// How to verify the passes before the last one with --passes: "full"
+    /// reads everything back, "sample" only 1024 blocks spread across the
+    /// device (which still catches gross failures early), and "none"
+    /// skips verifying them.
+    #[clap(long, value_enum, default_value_t = Verification::Full, requires = "passes")]
+    intermediate_verify: Verification,
+
+    /// With --passes, write the passes before the last one with stress
+    /// patterns instead of random data, rotating through walking-ones,
+    /// walking-zeros, checkerboard and inverse-checkerboard.
+    ///
+    /// Each pattern pass is read back in full, and a failure reports the
+    /// pattern it happened under. The last pass still uses random data,
+    /// which also catches writes landing at the wrong offset. This can't
+    /// be combined with options that change how the data is read back,
+    /// or with --block-log.
+    #[clap(long, requires = "passes", conflicts_with_all = ["intermediate_verify", "max_bad_blocks", "abort_on_first_bad", "verify_twice", "block_log"])]
+    pattern_set: bool,
+
+    /// Write the regions listed in this file with the given data, to
+    /// recreate the exact layout that triggered a failure, and verify it.
+    ///
+    /// Each line is an offset, a length and a generator: `zeroes`,
+    /// `pattern=NAME` (a --pattern-set pattern) or `seed=SEED`. The rest
+    /// of the device gets the random data of its usual seed.
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["passes", "repeat_until_fail", "churn", "random_write_order", "resume", "verify_only", "no_read_back", "speed_class", "verify_blank", "verify_manifest", "export_manifest", "max_bad_blocks", "abort_on_first_bad", "verify_twice"])]
+    layout_spec: Option<PathBuf>,
+
+    /// After a successful test, write a manifest of per-region SHA-256
+    /// checksums of the device's contents to this file.
+    ///
+    /// The manifest can later be checked with --verify-manifest, without
+    /// needing the seed. Only one device can be tested with this option.
+    #[clap(long, value_name = "FILE")]
+    export_manifest: Option<PathBuf>,
+
+    /// Instead of running the test, check that the device's contents
+    /// still match a manifest written by --export-manifest.
+    ///
+    /// This does not write to the device.
+    #[clap(long, value_name = "FILE", conflicts_with = "export_manifest")]
+    verify_manifest: Option<PathBuf>,
+
+    /// Instead of running the test, read the whole device and check that
+    /// it is blank, e.g. to confirm that a secure erase really happened.
+    ///
+    /// Blocks that are all zeroes or all 0xff bytes count as blank. Where
+    /// any other data is found is reported. This does not write to the
+    /// device.
+    #[clap(long, conflicts_with_all = ["verify_manifest", "export_manifest", "verify_only", "no_read_back", "churn", "random_write_order", "preserve", "checkpoint_dir", "probe_initial_state", "sanity_reads"])]
+    verify_blank: bool,
+
+    /// Instead of running the test, quickly check whether the claimed
+    /// capacity is plausible, by writing and reading back a few dozen
+    /// blocks spread over the device, including its last one.
+    ///
+    /// DANGEROUS: the data in those blocks is destroyed. This catches most
+    /// counterfeit drives in seconds, but does not verify the rest of the
+    /// device, which only the full test does.
+    #[clap(long, conflicts_with_all = ["verify_manifest", "export_manifest", "verify_only", "verify_blank", "no_read_back", "churn", "random_write_order", "preserve", "checkpoint_dir", "passes", "repeat_until_fail", "speed_class", "layout_spec", "sanity_reads"])]
+    estimate_only: bool,
+
+    /// Only fail a device once it has at least this many bad blocks.
+    ///
+    /// Devices with some bad blocks, but fewer than this, are reported
+    /// as uncertain instead of bad, and don't fail the run. By default a
+    /// single bad block fails the device.
+    #[clap(long, value_name = "N", default_value_t = 1)]
+    fail_threshold: read_test::FailedReads,
+
+    /// Fail a device whose write or read phase averaged less than this
+    /// many bytes per second, like 30M, even if its data read back fine.
+    ///
+    /// This is for rejecting drives that work, but are too slow, like a
+    /// hard disk that spends its time on reallocated sectors.
+    #[clap(long, value_name = "BYTES", value_parser = units::parse_bytes)]
+    min_throughput: Option<u64>,
+
+    /// Stop reading back a device once this many bad blocks were found,
+    /// and declare it bad.
+    ///
+    /// The reported number of bad blocks is then a lower bound.
+    #[clap(long, value_name = "N")]
+    max_bad_blocks: Option<read_test::FailedReads>,
+
+    /// Stop reading back a device at its first bad block, and declare it
+    /// bad.
+    ///
+    /// For quick triage: a clearly failing drive is found in seconds
+    /// instead of hours. Same as --max-bad-blocks 1.
+    #[clap(long, conflicts_with = "max_bad_blocks")]
+    abort_on_first_bad: bool,
+
+    /// Read every block that didn't read back as written once more, after
+    /// a short delay and bypassing the page cache, and only count it as bad
+    /// if it fails again.
+    ///
+    /// Blocks that read back correctly the second time are reported as
+    /// transient glitches, which don't affect the verdict. A glitchy
+    /// cable or controller can be worth looking into all the same.
+    ///
+    /// The whole device is always read, since transient glitches would
+    /// otherwise count towards --max-bad-blocks.
+    #[clap(long, conflicts_with_all = ["abort_on_first_bad", "max_bad_blocks"])]
+    verify_twice: bool,
+
+    /// Read the device back from its end to its start.
+    ///
+    /// Backwards reads defeat read-ahead and make hard drives seek
+    /// differently, which can surface caching and seek problems that a
+    /// forward read doesn't. The data verifies the same either way. Compare
+    /// the read throughput with that of a forward --verify-only run.
+    #[clap(long, conflicts_with_all = ["no_read_back", "export_manifest"])]
+    reverse_read: bool,
+
+    /// Give a device a friendly name for the output, e.g. /dev/sda=bay3.
+    ///
+    /// Can be repeated, once per device.
+    #[clap(long = "label", value_name = "DEVICE=LABEL", value_parser = parse_label)]
+    labels: Vec<(PathBuf, String)>,
+
+    /// Stream events (like progress and bad blocks) to this file as
+    /// newline-delimited JSON while the test runs.
+    ///
+    /// This can also be a file descriptor, like /dev/fd/3.
+    #[clap(long, value_name = "FILE")]
+    events: Option<PathBuf>,
+
+    /// Write a line of CSV to this file for every block of the sequential
+    /// write and of the read-back, with its offset, whether it was good,
+    /// how long it took and whether it was retried.
+    ///
+    /// The file is written as the test goes, so an interrupted run still
+    /// leaves the blocks up to the interruption in it.
+    #[clap(long, value_name = "FILE")]
+    block_log: Option<PathBuf>,
+
+    /// DANGEROUS: try to keep the data on the device, by copying it to an
+    /// image file in this directory before the test and writing it back
+    /// afterwards.
+    ///
+    /// THIS IS AT YOUR OWN RISK. If disk-spinner is interrupted, the host
+    /// crashes, or the device fails during the test, the data is only in
+    /// the image, and you'll have to restore it yourself (e.g. with dd).
+    /// If the device is bad, the restored data may be corrupted too. Keep
+    /// a real backup. The directory needs as much free space as the
+    /// tested capacity, and must not be on the device under test. The
+    /// restore is verified against a manifest of the image, which is only
+    /// deleted once that succeeds.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["resume", "verify_only", "verify_manifest"]
+    )]
+    preserve: Option<PathBuf>,
+
+    /// Write a JSON report of each device's results to this file.
+    ///
+    /// This and the other report files can all be combined, and are
+    /// written in addition to the summary printed at the end.
+    #[clap(long, value_name = "FILE", alias = "json-file")]
+    json_report: Option<PathBuf>,
+
+    /// Write the summary table and totals to this file, as an HTML page.
+    #[clap(long, value_name = "FILE")]
+    html_report: Option<PathBuf>,
+
+    /// Write a line of CSV per device with its result, throughput and
+    /// health score to this file.
+    #[clap(long, value_name = "FILE")]
+    stats_csv: Option<PathBuf>,
+
+    /// Also send each device's verdict and the batch totals to the local
+    /// syslog daemon, e.g. to collect the results of headless machines on
+    /// a remote log server. Failing to reach syslog isn't an error.
+    #[clap(long)]
+    syslog: bool,
 
     /// Test the device even if the media type is not a spinning disk.
     #[clap(long)]
@@ -62,53 +529,1713 @@ pub(crate) struct Args {
     #[clap(long)]
     allow_any_block_device: bool,
 
+    /// Allow testing regular files instead of block devices.
+    ///
+    /// This skips the block device sanity checks and is mainly useful
+    /// for testing disk-spinner itself (e.g. against a sparse file in CI).
+    #[clap(long)]
+    file_device: bool,
+
+    /// Log more details, like the effective I/O configuration used for each device.
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// How to show progress: "bars" that update in place, or a status
+    /// "line" per phase every minute, which suits logs that can't handle
+    /// cursor movement.
+    ///
+    /// Defaults to bars if stderr is a terminal, and to lines otherwise.
+    #[clap(long, value_enum)]
+    progress: Option<ProgressMode>,
+
+    /// How often to update the progress, e.g. 5s or 10m.
+    ///
+    /// The progress bars are advanced, and the status lines logged, at
+    /// most once per interval. With --events, a `progress` event is also
+    /// emitted every interval, besides every GiB. By default, the bars
+    /// update continuously and the status lines come every minute, which
+    /// can be too much for a slow terminal or SSH connection.
+    #[clap(long, value_name = "DURATION", value_parser = units::parse_duration)]
+    progress_interval: Option<Duration>,
+
+    /// Run each device's test on the CPUs of the NUMA node closest to
+    /// the device, so that its I/O buffers are allocated on that node.
+    ///
+    /// This helps on multi-socket machines testing many fast devices,
+    /// and does nothing on single-node systems.
+    #[clap(long)]
+    numa: bool,
+
+    /// Pin the test of a device to the given CPUs, e.g. /dev/sda=0-3,8.
+    ///
+    /// Each device is tested on a thread of its own, which also generates
+    /// and verifies its data, so on a big rig testing many drives at once
+    /// this keeps each device's work on its own cores and caches. By
+    /// default nothing is pinned. Only supported on Linux.
+    ///
+    /// Can be repeated, once per device.
+    #[clap(
+        long = "cpu-affinity",
+        value_name = "DEVICE=CPUS",
+        value_parser = parse_cpu_affinity,
+        conflicts_with = "numa"
+    )]
+    cpu_affinity: Vec<(PathBuf, Vec<usize>)>,
+
+    /// Remove a drive's host protected area (HPA) before testing it, so
+    /// that its full native capacity gets tested. THIS CHANGES THE DRIVE.
+    ///
+    /// Without it, a hidden HPA or DCO (device configuration overlay) on
+    /// an ATA drive is only warned about. The HPA is removed until the drive
+    /// is power cycled, and a DCO is never removed.
+    #[clap(long, conflicts_with_all = ["resume", "verify_only", "verify_manifest"])]
+    remove_hpa: bool,
+
+    /// Before testing, ask for the serial number of each device to be
+    /// typed, and abort if it doesn't match the one the device reports.
+    ///
+    /// This guards against wiping the wrong drive because of a typo in
+    /// its path. Devices without a serial number can't be confirmed.
+    #[clap(long, conflicts_with = "expect_serial")]
+    confirm_serial: bool,
+
+    /// Like --confirm-serial, but for scripts: the serial number of each
+    /// device must be one of the given ones, and each given one must
+    /// belong to a device.
+    ///
+    /// Can be repeated, once per device.
+    #[clap(long, value_name = "SERIAL")]
+    expect_serial: Vec<String>,
+
+    /// Run the test even if another process holds a lock on the device.
+    ///
+    /// Normally, disk-spinner takes an advisory lock on each device, so
+    /// that two runs against the same device can't corrupt each other's test.
+    #[clap(long)]
+    ignore_lock: bool,
+
     /// Run the test even if any sanity check at all could fail. This is dangerous.
     #[clap(long)]
     i_know_what_im_doing_let_me_skip_sanity_checks: bool,
 }
 
+/// How to verify a pass of the test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Verification {
+    /// Read back everything.
+    Full,
+    /// Read back a sample of blocks spread across the device.
+    Sample,
+    /// Don't read anything back.
+    None,
+}
+
+/// How to show the progress of the test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ProgressMode {
+    /// Progress bars that update in place, for terminals.
+    Bars,
+    /// A status line per phase every minute, for logs.
+    Line,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check that the data generator and verifier work together, in
+    /// memory and without touching any device.
+    SelfTest,
+    /// List the checkpoints and other state files that disk-spinner left
+    /// in a directory, and remove them once confirmed.
+    ///
+    /// This never touches the data on a device, only the files in DIR.
+    Clean {
+        /// The directory to clean, as given to --checkpoint-dir or --preserve.
+        dir: PathBuf,
+        /// Only clean up the files of these devices (and their partitions),
+        /// found by serial number where known. By default, all files are.
+        #[clap(value_parser = clap::value_parser!(ValidDevice))]
+        devices: Vec<ValidDevice>,
+        /// Also remove the images left by --preserve. These may hold the
+        /// only copy of a device's data!
+        #[clap(long)]
+        include_images: bool,
+        /// Remove the files without asking for confirmation.
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Print a shell completion script, e.g. for ~/.bash_completion.
+    #[command(hide = true)]
+    Completions { shell: clap_complete::Shell },
+}
+
+/// The verdict on a single device under test.
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "result", content = "bad_blocks", rename_all = "snake_case")]
+pub(crate) enum Outcome {
+    /// All data was read back exactly as it was written.
+    Good,
+    /// Some blocks did not read back correctly, and the device should be returned.
+    Bad(read_test::FailedReads),
+    /// The test can't tell whether the device is good or bad, for the given
+    /// reason. Some blocks may not have read back correctly, but fewer than
+    /// --fail-threshold.
+    #[serde(serialize_with = "serialize_uncertain")]
+    Uncertain(read_test::FailedReads, UncertainReason),
+    /// Data was written without errors, but never read back (with --no-read-back).
+    Unverified,
+    /// All data was read back exactly as it was written, but the device was
+    /// slower than --min-throughput.
+    Slow,
+}
+
+/// Why the verdict on a device is [Outcome::Uncertain].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum UncertainReason {
+    /// Some blocks did not read back correctly, but fewer than --fail-threshold.
+    BelowThreshold,
+    /// The test ran out of --max-runtime-per-device before it finished.
+    Timeout,
+    /// The test of the device panicked, which is a bug in disk-spinner.
+    Panicked,
+    /// Writing to the device failed with an I/O error.
+    WriteFailed,
+    /// Reading from the device failed with an I/O error.
+    ReadError,
+    /// The device went away during the test, e.g. it was unplugged.
+    Disconnected,
+    /// The test stopped on some other error before reaching a verdict.
+    Aborted,
+}
+
+/// Serializes just the bad blocks of an `Uncertain` outcome, so that they
+/// show up like those of a `Bad` one. The reason is reported separately.
+fn serialize_uncertain<S: serde::Serializer>(
+    bad_blocks: &read_test::FailedReads,
+    _: &UncertainReason,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serde::Serialize::serialize(bad_blocks, serializer)
+}
+
+/// Everything the test found out about a single device.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct DeviceResult {
+    pub outcome: Outcome,
+    /// The offsets of the blocks that did not read back as written.
+    pub bad_offsets: Vec<u64>,
+    /// The read test stopped at --max-bad-blocks, so there may be more bad blocks.
+    pub aborted_early: bool,
+    pub write: Option<PhaseTiming>,
+    pub read: Option<PhaseTiming>,
+    /// What the device held before the test, with --probe-initial-state.
+    pub initial_state: Option<read_test::InitialState>,
+    /// What the bad blocks say about the real capacity of the device, if
+    /// it was read back in full.
+    pub capacity: Option<fraud::CapacityReport>,
+    /// Roughly where on the platters the bad blocks are, for hard disks.
+    pub bad_block_zones: Option<zones::ZoneReport>,
+    /// The data left on a device that should be blank, with --verify-blank.
+    pub residual_data: Option<blank::ResidualData>,
+    /// The offsets of the blocks that failed to read back as written, but
+    /// read back correctly the second time, with --verify-twice.
+    pub transient_offsets: Option<Vec<u64>>,
+    /// The stress pattern that was written, if not random data.
+    pub pattern: Option<pattern::Pattern>,
+    /// The speed classes the device appears to meet, with --speed-class.
+    pub speed_class: Option<speed_class::SpeedClassReport>,
+    /// What the test of the device panicked with, if it did.
+    pub panic_message: Option<String>,
+    /// The error that stopped the test of the device, if any.
+    pub error_message: Option<String>,
+    /// What a few probes say about the capacity, with --estimate-only.
+    pub capacity_estimate: Option<estimate::CapacityEstimate>,
+    /// The seed that the data on the device was written with, before it
+    /// was derived for the device, if it is known.
+    pub seed: Option<Seed>,
+    /// The accessible, native and factory capacities of an ATA drive.
+    pub ata_capacity: Option<hpa::Capacity>,
+    /// How far the test got, if it ran out of --max-runtime-per-device.
+    pub timed_out_at: Option<deadline::Progress>,
+}
+
+impl From<Outcome> for DeviceResult {
+    fn from(outcome: Outcome) -> Self {
+        Self {
+            outcome,
+            bad_offsets: Vec::new(),
+            aborted_early: false,
+            write: None,
+            read: None,
+            initial_state: None,
+            capacity: None,
+            bad_block_zones: None,
+            residual_data: None,
+            transient_offsets: None,
+            pattern: None,
+            speed_class: None,
+            panic_message: None,
+            error_message: None,
+            capacity_estimate: None,
+            seed: None,
+            ata_capacity: None,
+            timed_out_at: None,
+        }
+    }
+}
+
+/// When a phase of the test (writing or reading back) ran, and how much
+/// data it went through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PhaseTiming {
+    pub started: SystemTime,
+    pub elapsed: Duration,
+    pub bytes: u64,
+}
+
+impl PhaseTiming {
+    /// Runs a phase that returns the number of bytes it processed, and times it.
+    fn measure<T>(
+        phase: impl FnOnce() -> anyhow::Result<T>,
+        bytes: impl FnOnce(&T) -> u64,
+    ) -> anyhow::Result<(T, Self)> {
+        let started = SystemTime::now();
+        let timer = Instant::now();
+        let result = phase()?;
+        let timing = Self {
+            started,
+            elapsed: timer.elapsed(),
+            bytes: bytes(&result),
+        };
+        Ok((result, timing))
+    }
+
+    pub(crate) fn finished(&self) -> SystemTime {
+        self.started + self.elapsed
+    }
+
+    pub(crate) fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
 fn main() -> anyhow::Result<()> {
-    let indicatif_layer = IndicatifLayer::new()
-        .with_max_progress_bars(128, None);
+    let args = Args::parse();
+    let progress = args.progress.unwrap_or(if std::io::stderr().is_terminal() {
+        ProgressMode::Bars
+    } else {
+        ProgressMode::Line
+    });
+    if let Some(interval) = args.progress_interval {
+        events::set_update_interval(interval);
+    }
+    let indicatif_layer = (progress == ProgressMode::Bars)
+        .then(|| IndicatifLayer::new().with_max_progress_bars(128, None));
+    let stderr = match &indicatif_layer {
+        Some(layer) => BoxMakeWriter::new(layer.get_stderr_writer()),
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let events_layer = args
+        .events
+        .as_deref()
+        .map(events::EventsLayer::create)
+        .transpose()?
+        .map(|layer| layer.with_filter(filter_fn(|meta| meta.target() != block_log::BLOCK_TARGET)));
+    let block_log_layer = args
+        .block_log
+        .as_deref()
+        .map(block_log::BlockLogLayer::create)
+        .transpose()?;
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_writer(indicatif_layer.get_stderr_writer()))
+        .with(if args.verbose {
+            LevelFilter::DEBUG
+        } else {
+            LevelFilter::INFO
+        })
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(stderr)
+                .with_ansi(std::io::stderr().is_terminal())
+                .with_filter(filter_fn(move |meta| {
+                    meta.target() != events::PROGRESS_TARGET
+                        && meta.target() != block_log::BLOCK_TARGET
+                        && (progress == ProgressMode::Line
+                            || meta.target() != events::STATUS_TARGET)
+                })),
+        )
         .with(indicatif_layer)
+        .with(events_layer)
+        .with(block_log_layer)
         .init();
-    let args = Args::parse();
-    let seed = args.seed.unwrap_or_else(|| thread_rng().gen());
-    let (_, failed) = args.devices.clone().into_par_iter().map(|device| {
-        let ValidDevice {
-            device,
-            partition,
-            path,
-        } = device;
-        let buffer_size = args.buffer_size.unwrap_or_else(|| {
-            device
-                .physical_block_size
-                .unwrap_or(8192)
-                .try_into()
-                .unwrap()
-        });
-        sanity_checks(&args, partition, &path, &device)?;
+    match args.command {
+        Some(Command::SelfTest) => return self_test::run(),
+        Some(Command::Clean {
+            dir,
+            devices,
+            include_images,
+            yes,
+        }) => return clean::run(&dir, &devices, include_images, yes),
+        Some(Command::Completions { shell }) => {
+            let mut command = <Args as clap::CommandFactory>::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+            return Ok(());
+        }
+        None => {}
+    }
+    if args.devices.is_empty() {
+        <Args as clap::CommandFactory>::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "no devices to test.\n\n\
+                Pass the devices to test, e.g. `disk-spinner /dev/sdb /dev/sdc`.\n\
+                To find their paths, run `lsblk -d -o NAME,SIZE,MODEL,SERIAL,ROTA`, \
+                or look in /dev/disk/by-id/ for names that stay the same across reboots.\n\
+                To check that disk-spinner itself works, run `disk-spinner self-test`.",
+            )
+            .exit();
+    }
+    if (args.export_manifest.is_some() || args.verify_manifest.is_some()) && args.devices.len() != 1
+    {
+        anyhow::bail!("Manifests can only be used when testing a single device.");
+    }
+    check_overlaps(&args.devices)?;
+    if let Some(spec) = &args.layout_spec {
+        layout::Layout::load(spec)?;
+    }
+    for (path, _) in &args.labels {
+        if !args.devices.iter().any(|d| same_path(&d.path, path)) {
+            anyhow::bail!(
+                "--label {:?} does not name one of the devices under test.",
+                path
+            );
+        }
+    }
+    for (path, _) in &args.cpu_affinity {
+        if !args.devices.iter().any(|d| same_path(&d.path, path)) {
+            anyhow::bail!(
+                "--cpu-affinity {:?} does not name one of the devices under test.",
+                path
+            );
+        }
+    }
+    if args.confirm_serial || !args.expect_serial.is_empty() {
+        let destructive =
+            !(args.verify_only || args.verify_blank || args.verify_manifest.is_some());
+        let mut confirmed = Vec::new();
+        for device in &args.devices {
+            let serial = device
+                .device
+                .as_ref()
+                .and_then(|d| d.serial_number.as_deref());
+            confirm_serial(
+                &device.path,
+                serial,
+                &args.expect_serial,
+                destructive,
+                &mut std::io::stdin().lock(),
+            )?;
+            confirmed.extend(serial);
+        }
+        if let Some(unused) = args
+            .expect_serial
+            .iter()
+            .find(|expected| !confirmed.contains(&expected.as_str()))
+        {
+            anyhow::bail!(
+                "--expect-serial {:?} does not match any of the devices under test.",
+                unused
+            );
+        }
+    }
+    let (seed, seed_source) = match (args.seed, args.os_random) {
+        (Some(seed), _) => (seed, crypto::SeedSource::Given),
+        (None, true) => (crypto::os_random_seed()?, crypto::SeedSource::OsRandom),
+        (None, false) => (thread_rng().gen(), crypto::SeedSource::Generated),
+    };
+    if args.os_random {
+        warn!(%seed, "Drew the seed from the OS random number generator (--os-random). Keep it: the data can only be verified later with --seed {}.", seed);
+    }
+    // Each device gets an OS thread of its own, rather than sharing rayon's
+    // global pool, which is sized to the CPUs: the tests spend their time
+    // blocked on I/O, and with more devices than CPUs the rest would wait
+    // for a whole test to finish before starting. The deadline, the NUMA
+    // binding and the I/O priority are also all per thread.
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.devices.len())
+        .thread_name(|i| format!("device-{}", i))
+        .build()
+        .context("Starting the device threads")?;
+    let batch_timer = Instant::now();
+    let reports = pool.install(|| {
+        args
+        .devices
+        .clone()
+        .into_par_iter()
+        .map(|device| {
+            let path = device.path.clone();
+            let serial = device
+                .device
+                .as_ref()
+                .and_then(|d| d.serial_number.clone());
+            let label = args
+                .labels
+                .iter()
+                .find(|(labelled, _)| same_path(labelled, &path))
+                .map(|(_, label)| label.clone());
+            let span = match &label {
+                Some(label) => info_span!("device", label),
+                None => Span::none(),
+            };
+            let _span_handle = span.enter();
+            let result = catch_error(&path, catch_panic(&path, || test_device(&args, seed, device)));
+            info!(event = "device_done", device=?path, outcome=?result.outcome, "Finished testing device");
+            // A device resumed from a checkpoint keeps the seed it was
+            // started with, and --repeat-until-fail derives one per iteration:
+            let seed_source = result.seed.map(|used| match (used == seed, args.repeat_until_fail) {
+                (true, _) => seed_source,
+                (false, true) => crypto::SeedSource::Iteration,
+                (false, false) => crypto::SeedSource::Checkpoint,
+            });
+            report::DeviceReport {
+                seed_source,
+                ..report::DeviceReport::new(path, label, serial, result)
+            }
+        })
+        .collect::<Vec<report::DeviceReport>>()
+    });
+    report::print_summary(&reports);
+    let totals = report::BatchTotals::new(&reports, batch_timer.elapsed());
+    totals.print();
+    if args.os_random {
+        println!(
+            "SEED: {} (from the OS random number generator, pass it as --seed to verify the data again)",
+            seed
+        );
+    }
+    if args.syslog {
+        syslog::send_summary(&reports, &totals);
+    }
+    let outputs = [
+        args.json_report.clone().map(report::Output::Json),
+        args.html_report.clone().map(report::Output::Html),
+        args.stats_csv.clone().map(report::Output::StatsCsv),
+    ];
+    // One report that can't be written shouldn't cost the others:
+    let mut unwritten = Vec::new();
+    for output in outputs.iter().flatten() {
+        if let Err(e) = output.write(&reports, &totals) {
+            error!(error=%format!("{:#}", e), "Could not write a report");
+            unwritten.push(output.path());
+        }
+    }
+    let failed: Vec<&Path> = reports
+        .iter()
+        .filter(|r| matches!(r.outcome, Outcome::Bad(_) | Outcome::Slow))
+        .map(|r| r.device.as_path())
+        .collect();
+    let uncertain = |reason| -> Vec<&Path> {
+        reports
+            .iter()
+            .filter(|r| matches!(r.outcome, Outcome::Uncertain(_, found) if found == reason))
+            .map(|r| r.device.as_path())
+            .collect()
+    };
+    let below_threshold = uncertain(UncertainReason::BelowThreshold);
+    if !below_threshold.is_empty() {
+        warn!(devices=?below_threshold, "Devices have bad blocks, but fewer than --fail-threshold.");
+    }
+    let timed_out = uncertain(UncertainReason::Timeout);
+    if !timed_out.is_empty() {
+        warn!(devices=?timed_out, "Devices ran out of --max-runtime-per-device before their test finished.");
+    }
+    let panicked = uncertain(UncertainReason::Panicked);
+    let errored: Vec<&Path> = [
+        UncertainReason::WriteFailed,
+        UncertainReason::ReadError,
+        UncertainReason::Disconnected,
+        UncertainReason::Aborted,
+    ]
+    .into_iter()
+    .flat_map(uncertain)
+    .collect();
+    if !failed.is_empty() {
+        error!(devices=?failed, "Devices have failed validation. You should return them.");
+        anyhow::bail!("Tests not successful.");
+    }
+    if !panicked.is_empty() {
+        error!(devices=?panicked, "The tests of some devices panicked, so their results are unknown.");
+        anyhow::bail!("Panic in one of the data-integrity test threads.");
+    }
+    if !errored.is_empty() {
+        error!(devices=?errored, "The tests of some devices stopped on an error, so their results are unknown.");
+        anyhow::bail!("Error in one of the data-integrity tests.");
+    }
+    if !unwritten.is_empty() {
+        anyhow::bail!("Could not write the reports {:?}.", unwritten);
+    }
+    Ok(())
+}
 
-        info!(?seed, ?partition, ?device, ?path, "Starting test");
+/// Runs the test of a device, turning a panic into an uncertain result,
+/// so that one device hitting a bug doesn't lose the results of the rest.
+fn catch_panic(
+    path: &Path,
+    test: impl FnOnce() -> anyhow::Result<DeviceResult>,
+) -> anyhow::Result<DeviceResult> {
+    let payload = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(test)) {
+        Ok(result) => return result,
+        Err(payload) => payload,
+    };
+    let message = match (
+        payload.downcast_ref::<&str>(),
+        payload.downcast_ref::<String>(),
+    ) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_string(),
+    };
+    error!(device=?path, panic=%message, "The test of the device panicked, marking it uncertain. This is a bug in disk-spinner.");
+    Ok(DeviceResult {
+        panic_message: Some(message),
+        ..Outcome::Uncertain(0, UncertainReason::Panicked).into()
+    })
+}
+
+/// Turns an error that stopped the test of a device into an uncertain
+/// result, so that one device failing doesn't lose the results of the rest.
+fn catch_error(path: &Path, result: anyhow::Result<DeviceResult>) -> DeviceResult {
+    let e = match result {
+        Ok(result) => return result,
+        Err(e) => e,
+    };
+    let io_error = e
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<device_error::DeviceIoError>());
+    let reason = match io_error.map(|e| e.operation) {
+        _ if device_error::is_disconnected(&e) => UncertainReason::Disconnected,
+        Some(device_error::Operation::Write) => UncertainReason::WriteFailed,
+        Some(device_error::Operation::Read) => UncertainReason::ReadError,
+        None => UncertainReason::Aborted,
+    };
+    let message = format!("{:#}", e);
+    error!(device=?path, error=%message, ?reason, "The test of the device stopped on an error, marking it uncertain.");
+    DeviceResult {
+        error_message: Some(message),
+        ..Outcome::Uncertain(0, reason).into()
+    }
+}
 
-        write_test::write(&path, buffer_size, seed).context("During write test")?;
-        info!(device=?path, "write test succeeded");
-        match read_test::read_back(&path, buffer_size, seed).context("During read test")? {
+/// The block sizes that a device can plausibly report. Some virtual and
+/// USB devices report nonsense, like 0 or 1.
+const PLAUSIBLE_BLOCK_SIZES: std::ops::RangeInclusive<u64> = 512..=1024 * 1024;
+
+/// The buffer size to use for a device without --buffer-size: the I/O
+/// size it prefers, or else its physical block size, unless those are
+/// implausible. Returns where the size came from, too.
+fn default_buffer_size(
+    path: &Path,
+    preferred_io_size: Option<(u64, &'static str)>,
+    physical_block_size: Option<u64>,
+) -> (usize, &'static str) {
+    let reported = physical_block_size.map(|size| (size, "physical block size"));
+    for (size, source) in preferred_io_size.into_iter().chain(reported) {
+        if PLAUSIBLE_BLOCK_SIZES.contains(&size) {
+            return (size as usize, source);
+        }
+        warn!(device=?path, size, source, "The device reports an implausible block size, ignoring it.");
+    }
+    (8192, "default")
+}
+
+/// The largest buffer size used by default for devices behind a USB
+/// bridge. This only sizes the buffers that the test reads and writes
+/// with: the kernel still merges buffered writes into requests of up to
+/// the device's max_sectors_kb, so it doesn't limit the transfers that
+/// the bridge sees.
+const USB_MAX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Runs the write and read-back tests on a single device.
+fn test_device(args: &Args, seed: Seed, device: ValidDevice) -> anyhow::Result<DeviceResult> {
+    let mut run_seed = Some(seed);
+    let siblings = args
+        .devices
+        .iter()
+        .filter(|d| !same_path(&d.path, &device.path))
+        .map(|d| (d.path.clone(), device_seed(args, seed, d)))
+        .collect();
+    let run_seed_for_iterations = seed;
+    let valid_device = device.clone();
+    let mut seed = device_seed(args, seed, &device);
+    if args.key_by_serial
+        && device
+            .device
+            .as_ref()
+            .and_then(|d| d.serial_number.as_ref())
+            .is_none()
+    {
+        warn!(device=?device.path, "The device has no serial number, keying its data by its path instead.");
+    }
+    let ValidDevice {
+        device,
+        partition,
+        path,
+        char_device,
+    } = device;
+    let (mut buffer_size, mut buffer_size_source) = match (args.buffer_size, &device) {
+        (Some(buffer_size), _) => (buffer_size, "--buffer-size"),
+        (None, Some(device)) => {
+            default_buffer_size(&path, preferred_io_size(device), device.physical_block_size)
+        }
+        (None, None) => (8192, "default"),
+    };
+    if let Some(driver) = device.as_ref().and_then(usb_bridge) {
+        warn!(device=?path, driver, "The device is behind a USB bridge. Bridges can misreport the capacity of the disk, so a capacity mismatch may be the bridge's fault rather than the disk's.");
+        if args.buffer_size.is_none() && buffer_size > USB_MAX_BUFFER_SIZE {
+            buffer_size = USB_MAX_BUFFER_SIZE;
+            buffer_size_source = "USB bridge cap";
+        }
+    }
+    // Before anything changes the device, like --remove-hpa:
+    let _lock = lock_device(&path, args.ignore_lock)?;
+    let mut ata_capacity = None;
+    let mut capacity = match &device {
+        Some(device) => {
+            sanity_checks(args, partition, &path, char_device.as_deref(), device)?;
+            ata_capacity = hpa::check(&path, args.remove_hpa, || rescan_capacity(device))?;
+            args.capacity
+        }
+        None if args.file_device => {
+            warn!(?path, "Testing a regular file instead of a block device.");
+            let capacity = match args.capacity {
+                Some(capacity) => capacity,
+                None => path
+                    .metadata()
+                    .with_context(|| format!("Determining the size of {:?}", path))?
+                    .len(),
+            };
+            if capacity == 0 && args.capacity.is_none() {
+                anyhow::bail!(
+                    "{:?} is empty - pass --capacity to set how many bytes to test.",
+                    path
+                );
+            }
+            Some(capacity)
+        }
+        None => anyhow::bail!(
+            "{:?} is a regular file, not a block device - pass --file-device to test it anyway.",
+            path
+        ),
+    };
+
+    // Nothing would get written or read back, and the device would pass:
+    if capacity == Some(0) || (capacity.is_none() && device_capacity(&path)? == Some(0)) {
+        anyhow::bail!(
+            "{:?} reports zero capacity; refusing to report Good for a device that can't be tested.",
+            path
+        );
+    }
+
+    // A capacity that the device can't address would only fail hours
+    // into the write test, so read its last block right away:
+    if device.is_some() {
+        let blockdev = fs::File::open(&path).with_context(|| format!("Opening {:?}", path))?;
+        match check_last_block(&blockdev) {
+            Ok(()) => {}
+            Err(e) if args.i_know_what_im_doing_let_me_skip_sanity_checks => {
+                warn!(device=?path, error=%e, "The last block is not addressable, but running tests anyway.");
+            }
+            Err(e) => {
+                return Err(e.context(format!(
+                    "{:?}: last block not addressable - reported capacity is wrong (the device may be fake, or misreport its geometry). Pass --i-know-what-im-doing-let-me-skip-sanity-checks to run anyway.",
+                    path
+                )))
+            }
+        }
+    }
+
+    let with_ata_capacity = |result| DeviceResult {
+        ata_capacity,
+        ..result
+    };
+    let _numa_binding = match (&device, args.numa) {
+        (Some(device), true) => bind_to_numa_node(device)?,
+        _ => None,
+    };
+    let _cpu_binding = match args.cpu_affinity.iter().find(|(p, _)| same_path(p, &path)) {
+        Some((_, cpus)) => bind_to_cpus(cpus)?,
+        None => None,
+    };
+    set_thread_priority(args.io_priority, args.nice)?;
+
+    if let Some(manifest_path) = &args.verify_manifest {
+        let manifest = manifest::Manifest::load(manifest_path)?;
+        info!(?partition, ?device, ?path, manifest=?manifest_path, "Starting manifest verification");
+        return match manifest::verify(&path, buffer_size, &manifest)
+            .context("During manifest verification")?
+        {
             Ok(_) => {
-                info!(device=?path, "read-back test succeeded");
-                Ok(Either::Left(()))
+                info!(event = "pass_complete", device=?path, bad_regions = 0, "device contents match the manifest");
+                Ok(with_ata_capacity(Outcome::Good.into()))
             }
             Err(n) => {
-                error!(device=?path, bad_blocks=?n, "Data on disk is inconsistent/corrupted. THIS IS BAD - RMA THE DRIVE!");
-                Ok(Either::Right(path))
+                error!(event = "pass_complete", device=?path, bad_regions = n, "Data on disk does not match the manifest. THIS IS BAD!");
+                Ok(with_ata_capacity(Outcome::Bad(n).into()))
+            }
+        };
+    }
+
+    if args.verify_blank {
+        info!(?partition, ?device, ?path, "Starting blank verification");
+        let ((_, residual), read_timing) = PhaseTiming::measure(
+            || {
+                blank::verify_blank(&path, buffer_size, capacity)
+                    .context("During blank verification")
+            },
+            |(bytes, _)| *bytes,
+        )?;
+        let outcome = match residual.regions.first() {
+            None => {
+                info!(event = "pass_complete", device=?path, "The device is blank");
+                Outcome::Good
+            }
+            Some((first_offset, _)) => {
+                error!(event = "pass_complete", device=?path, residual_bytes = residual.bytes, residual_blocks = residual.blocks, first_offset, "The device is NOT blank: the erase left data behind.");
+                Outcome::Bad(residual.blocks as read_test::FailedReads)
+            }
+        };
+        return Ok(with_ata_capacity(DeviceResult {
+            read: Some(read_timing),
+            residual_data: Some(residual),
+            ..outcome.into()
+        }));
+    }
+
+    if args.estimate_only {
+        info!(?partition, ?device, ?path, "Starting the capacity estimate");
+        let estimate = estimate::estimate(&path, buffer_size, capacity, seed)
+            .context("During the capacity estimate")?;
+        let failed = estimate.failed_offsets.len();
+        let outcome = match estimate.fraud_likelihood {
+            estimate::Likelihood::Unlikely => {
+                warn!(event = "pass_complete", device=?path, probes = estimate.probes, "All capacity probes read back correctly, so the capacity is plausible. NO FULL DATA INTEGRITY VERIFICATION WAS PERFORMED.");
+                Outcome::Unverified
+            }
+            estimate::Likelihood::Possible => {
+                error!(event = "pass_complete", device=?path, probes = estimate.probes, failed, "Some capacity probes did not read back as written, though not like a fake drive. THE DEVICE MAY BE BAD.");
+                Outcome::Bad(failed)
+            }
+            estimate::Likelihood::Likely => {
+                error!(event = "pass_complete", device=?path, probes = estimate.probes, failed, usable_capacity = estimate.usable_capacity, wraparound = ?estimate.wraparound, "The device appears to be FAKE: it can't hold its claimed capacity.");
+                Outcome::Bad(failed)
+            }
+        };
+        return Ok(with_ata_capacity(DeviceResult {
+            capacity_estimate: Some(estimate),
+            ..outcome.into()
+        }));
+    }
+
+    let checkpoint_path = args.checkpoint_dir.as_ref().map(|dir| {
+        let serial = device.as_ref().and_then(|d| d.serial_number.as_deref());
+        checkpoint::Checkpoint::path_for(dir, &path, serial, partition)
+    });
+    let mut start = 0;
+    if let (true, Some(checkpoint_path)) = (args.resume, &checkpoint_path) {
+        match checkpoint::Checkpoint::load(checkpoint_path)? {
+            Some(checkpoint) => {
+                if args.seed.is_some() && seed != checkpoint.seed {
+                    anyhow::bail!(
+                        "The checkpoint {:?} was written with seed {}, not the given --seed.",
+                        checkpoint_path,
+                        checkpoint.seed
+                    );
+                }
+                // The rest of the device has to be written, and all of it
+                // read back, exactly like the part before the checkpoint:
+                if args.buffer_size.is_some() && buffer_size != checkpoint.buffer_size {
+                    anyhow::bail!(
+                        "The checkpoint {:?} was written with a buffer size of {}, not the given --buffer-size.",
+                        checkpoint_path,
+                        checkpoint.buffer_size
+                    );
+                }
+                if capacity.is_some_and(|capacity| capacity != checkpoint.capacity) {
+                    anyhow::bail!(
+                        "The checkpoint {:?} was written for a capacity of {} bytes, not {} bytes.",
+                        checkpoint_path,
+                        checkpoint.capacity,
+                        capacity.unwrap()
+                    );
+                }
+                check_device_capacity(
+                    checkpoint_path,
+                    checkpoint.device_capacity,
+                    device_capacity(&path)?,
+                    args.force_capacity_mismatch,
+                )?;
+                info!(device=?path, checkpoint=?checkpoint_path, offset=checkpoint.offset, "Resuming from checkpoint");
+                if args.seed.is_none() {
+                    // The checkpoint only has the seed derived for the device:
+                    run_seed =
+                        (partition.is_none() && !args.key_by_serial).then_some(checkpoint.seed);
+                    if run_seed.is_none() {
+                        warn!(device=?path, "The seed that the device was first written with is unknown, so the report can't record it.");
+                    }
+                }
+                seed = checkpoint.seed;
+                start = checkpoint.offset;
+                buffer_size = checkpoint.buffer_size;
+                buffer_size_source = "checkpoint";
+                capacity = Some(checkpoint.capacity);
+            }
+            None => {
+                warn!(device=?path, checkpoint=?checkpoint_path, "No checkpoint found, starting from the beginning.")
             }
         }
-    }).collect::<anyhow::Result<(Vec<()>, Vec<PathBuf>)>>()?;
-    if failed.len() > 0 {
-        error!(devices=?failed, "Devices have failed validation. You should return them.");
-        anyhow::bail!("Tests not successful.");
+    }
+
+    let build = build_info::BuildInfo::current();
+    info!(
+        event = "start",
+        version = build.version,
+        git_commit = build.git_commit,
+        %seed,
+        ?partition,
+        device=?path,
+        block_device=?device,
+        buffer_size,
+        buffer_size_source,
+        "Starting test"
+    );
+    debug!(
+        device=?path,
+        buffer_size,
+        buffer_size_source,
+        ?capacity,
+        io_engine = "blocking std::fs",
+        direct_io = false,
+        queue_depth = 1,
+        "I/O configuration"
+    );
+
+    let initial_state = match (args.probe_initial_state, start) {
+        (true, 0) => {
+            let state = read_test::probe_initial_state(&path, buffer_size, capacity)
+                .context("While probing the initial state of the device")?;
+            info!(device=?path, initial_state=?state, "Probed the initial state of the device");
+            Some(state)
+        }
+        (true, _) => {
+            warn!(device=?path, "Resuming, so the initial state of the device is unknown.");
+            None
+        }
+        (false, _) => None,
+    };
+
+    if let Some(duration) = args.warmup {
+        info!(device=?path, duration=%indicatif::FormattedDuration(duration), "warming up");
+        read_test::warm_up(&path, buffer_size, capacity, duration)
+            .context("While warming up the device")?;
+    }
+
+    let options = TestOptions {
+        path,
+        buffer_size,
+        capacity,
+        seed,
+        checkpoint: checkpoint_path,
+        siblings,
+        verification: Verification::Full,
+        run_seed: run_seed_for_iterations,
+        device: valid_device,
+    };
+    let finish = |result: anyhow::Result<DeviceResult>| {
+        result.map(|result| DeviceResult {
+            initial_state,
+            seed: result.seed.or(run_seed),
+            ..with_ata_capacity(result)
+        })
+    };
+    let Some(preserve_dir) = &args.preserve else {
+        return finish(run_passes_within_budget(args, options, start));
+    };
+    let serial = device.as_ref().and_then(|d| d.serial_number.as_deref());
+    let image_path =
+        checkpoint::sidecar_path(preserve_dir, &options.path, serial, partition, "img");
+    warn!(device=?options.path, image=?image_path, "Imaging the device before the test. If anything goes wrong, its data is only in the image!");
+    let manifest = preserve::image(&options.path, &image_path, buffer_size, capacity)
+        .context("While imaging the device")?;
+    let path = options.path.clone();
+    let result = run_passes_within_budget(args, options, start);
+    if let Err(e) = &result {
+        error!(device=?path, error=%format!("{:#}", e), "The test failed, restoring the device anyway");
+    }
+    preserve::restore(&path, &image_path, buffer_size, &manifest).with_context(|| {
+        format!(
+            "While restoring the device - its data is in {:?}, restore it manually",
+            image_path
+        )
+    })?;
+    finish(result)
+}
+
+/// Runs [run_passes], stopping early with an `Uncertain` outcome if the
+/// device takes longer than --max-runtime-per-device.
+fn run_passes_within_budget(
+    args: &Args,
+    options: TestOptions,
+    start: u64,
+) -> anyhow::Result<DeviceResult> {
+    let Some(budget) = args.max_runtime_per_device else {
+        return run_passes(args, options, start);
+    };
+    let path = options.path.clone();
+    let _deadline = deadline::Deadline::start(budget);
+    match run_passes(args, options, start) {
+        Err(e) if deadline::exceeded() => {
+            let reached = e
+                .chain()
+                .find_map(|e| e.downcast_ref::<device_error::DeviceIoError>());
+            let progress = deadline::progress();
+            warn!(
+                device=?path,
+                budget=%indicatif::FormattedDuration(budget),
+                operation=reached.map(|e| e.operation.to_string()),
+                offset=reached.map(|e| e.offset),
+                bytes_written=progress.bytes_written,
+                bytes_read=progress.bytes_read,
+                "The device ran out of --max-runtime-per-device, marking it uncertain."
+            );
+            Ok(DeviceResult {
+                timed_out_at: Some(progress),
+                ..Outcome::Uncertain(0, UncertainReason::Timeout).into()
+            })
+        }
+        result => result,
+    }
+}
+
+/// Runs one pass of the test, or several with --passes or --repeat-until-fail.
+fn run_passes(args: &Args, mut options: TestOptions, start: u64) -> anyhow::Result<DeviceResult> {
+    if let Some(spec) = &args.layout_spec {
+        return run_layout_pass(args, &options, &layout::Layout::load(spec)?);
+    }
+    if let Some(passes) = args.passes {
+        return run_fixed_passes(args, options, passes);
+    }
+    if !args.repeat_until_fail {
+        return run_pass(args, &options, start);
+    }
+    for iteration in 1.. {
+        // Derived like the seed of the run, so that the iteration can be
+        // reproduced by passing its seed back with --seed:
+        let iteration_seed = crypto::derive_seed(options.run_seed, iteration);
+        options.seed = device_seed(args, iteration_seed, &options.device);
+        info!(device=?options.path, iteration, seed=%iteration_seed, "Starting iteration");
+        let result = run_pass(args, &options, 0).with_context(|| {
+            format!(
+                "In iteration {} (with --seed {})",
+                iteration, iteration_seed
+            )
+        })?;
+        if result.outcome != Outcome::Good {
+            error!(
+                device=?options.path,
+                iteration,
+                seed=%iteration_seed,
+                "Found a failure after {} iterations. Offsets of the bad blocks are logged above.",
+                iteration
+            );
+            return Ok(DeviceResult {
+                seed: Some(iteration_seed),
+                ..result
+            });
+        }
+        info!(device=?options.path, iteration, "Iteration passed");
+    }
+    unreachable!("Ran out of iterations")
+}
+
+/// How many blocks are read back after a pass with --intermediate-verify sample.
+const INTERMEDIATE_SAMPLES: u64 = 1024;
+
+/// Runs `passes` passes of the test, verifying the ones before the last as
+/// --intermediate-verify says, and stopping at the first failure.
+///
+/// The last pass writes the data of the device's own seed, so that it can
+/// be verified again later with --verify-only.
+fn run_fixed_passes(
+    args: &Args,
+    mut options: TestOptions,
+    passes: u64,
+) -> anyhow::Result<DeviceResult> {
+    let device_seed = options.seed;
+    for pass in 1..passes {
+        let result = if args.pattern_set {
+            let pattern = pattern::PATTERN_SET[(pass - 1) as usize % pattern::PATTERN_SET.len()];
+            info!(device=?options.path, pass, passes, %pattern, "Starting pass");
+            run_pattern_pass(args, &options, pattern)?
+        } else {
+            options.seed = crypto::derive_seed(device_seed, pass);
+            options.verification = args.intermediate_verify;
+            info!(device=?options.path, pass, passes, seed=%options.seed, verification=?options.verification, "Starting pass");
+            run_pass(args, &options, 0)?
+        };
+        match result.outcome {
+            Outcome::Good => {
+                info!(device=?options.path, pass, passes, verification=?options.verification, "Pass {} of {} passed", pass, passes)
+            }
+            Outcome::Unverified => {
+                info!(device=?options.path, pass, passes, "Pass written, not verified")
+            }
+            Outcome::Uncertain(..) => {
+                warn!(device=?options.path, pass, passes, seed=%options.seed, outcome=?result.outcome, pattern=result.pattern.map(|p| p.to_string()), "Pass {} of {} is uncertain, stopping.", pass, passes);
+                return Ok(result);
+            }
+            _ => {
+                error!(device=?options.path, pass, passes, seed=%options.seed, verification=?options.verification, pattern=result.pattern.map(|p| p.to_string()), "Pass {} of {} failed, stopping.", pass, passes);
+                return Ok(result);
+            }
+        }
+    }
+    options.seed = device_seed;
+    options.verification = Verification::Full;
+    info!(device=?options.path, pass = passes, passes, seed=%options.seed, verification=?options.verification, "Starting the last pass");
+    run_pass(args, &options, 0)
+}
+
+/// Runs a pass that writes and verifies the layout of --layout-spec.
+fn run_layout_pass(
+    args: &Args,
+    options: &TestOptions,
+    layout: &layout::Layout,
+) -> anyhow::Result<DeviceResult> {
+    let TestOptions {
+        path,
+        buffer_size,
+        capacity,
+        seed,
+        ..
+    } = options;
+    info!(device=?path, regions = layout.regions.len(), %seed, "Writing the layout of --layout-spec");
+    let (_, write_timing) = PhaseTiming::measure(
+        || {
+            layout::write_layout(path, *buffer_size, *capacity, *seed, layout)
+                .context("During layout write")
+        },
+        |bytes| *bytes,
+    )?;
+    let ((_, bad_offsets), read_timing) = PhaseTiming::measure(
+        || {
+            layout::verify_layout(path, *buffer_size, *capacity, *seed, layout)
+                .context("During layout verification")
+        },
+        |(bytes, _)| *bytes,
+    )?;
+    let bad_blocks = bad_offsets.len();
+    let outcome = policy::Policy::from_args(args).decide(policy::Metrics::Verified {
+        bad_blocks,
+        aborted: false,
+        throughput: [write_timing, read_timing]
+            .iter()
+            .map(|t| t.bytes_per_second() as u64)
+            .min(),
+    });
+    match outcome {
+        Outcome::Good => {
+            info!(event = "pass_complete", device=?path, %seed, bad_blocks, "layout read-back succeeded")
+        }
+        Outcome::Slow => {
+            error!(event = "pass_complete", device=?path, %seed, min_throughput = args.min_throughput, "The layout read back correctly, but the device is slower than --min-throughput.")
+        }
+        _ => {
+            error!(event = "pass_complete", device=?path, %seed, bad_blocks, ?outcome, "The layout did not read back as written.")
+        }
+    }
+    Ok(DeviceResult {
+        outcome,
+        bad_offsets,
+        write: Some(write_timing),
+        read: Some(read_timing),
+        ..Outcome::Good.into()
+    })
+}
+
+/// Runs a pass that writes a stress pattern to the device and reads it
+/// back in full, with --pattern-set.
+fn run_pattern_pass(
+    args: &Args,
+    options: &TestOptions,
+    pattern: pattern::Pattern,
+) -> anyhow::Result<DeviceResult> {
+    let TestOptions {
+        path,
+        buffer_size,
+        capacity,
+        ..
+    } = options;
+    let (_, write_timing) = PhaseTiming::measure(
+        || {
+            pattern::write_pattern(path, *buffer_size, *capacity, pattern)
+                .context("During pattern write")
+        },
+        |bytes| *bytes,
+    )?;
+    let ((_, bad_offsets), read_timing) = PhaseTiming::measure(
+        || {
+            pattern::verify_pattern(path, *buffer_size, *capacity, pattern)
+                .context("During pattern verification")
+        },
+        |(bytes, _)| *bytes,
+    )?;
+    let bad_blocks = bad_offsets.len();
+    let outcome = policy::Policy::from_args(args).decide(policy::Metrics::Verified {
+        bad_blocks,
+        aborted: false,
+        throughput: [write_timing, read_timing]
+            .iter()
+            .map(|t| t.bytes_per_second() as u64)
+            .min(),
+    });
+    match outcome {
+        Outcome::Good => {
+            info!(event = "pass_complete", device=?path, %pattern, bad_blocks, "pattern read-back succeeded")
+        }
+        Outcome::Uncertain(..) => {
+            warn!(event = "pass_complete", device=?path, %pattern, bad_blocks, fail_threshold = args.fail_threshold, "The {} pattern partly did not read back as written, but below the failure threshold.", pattern)
+        }
+        Outcome::Slow => {
+            error!(event = "pass_complete", device=?path, %pattern, min_throughput = args.min_throughput, "The {} pattern read back correctly, but the device is slower than --min-throughput.", pattern)
+        }
+        _ => {
+            error!(event = "pass_complete", device=?path, %pattern, bad_blocks, "The {} pattern did not read back as written. THIS IS BAD - RMA THE DRIVE!", pattern)
+        }
+    }
+    Ok(DeviceResult {
+        outcome,
+        bad_offsets,
+        write: Some(write_timing),
+        read: Some(read_timing),
+        pattern: Some(pattern),
+        ..Outcome::Good.into()
+    })
+}
+
+/// The effective parameters of one pass of the test on a device.
+#[derive(Debug, Clone)]
+pub(crate) struct TestOptions {
+    pub path: PathBuf,
+    pub buffer_size: usize,
+    pub capacity: Option<u64>,
+    pub seed: Seed,
+    pub checkpoint: Option<PathBuf>,
+    /// The other devices under test, and the seeds of their data.
+    pub siblings: Vec<(PathBuf, Seed)>,
+    /// How to read back the data after writing it.
+    pub verification: Verification,
+    /// The seed of the run, before it was derived for the device, to
+    /// derive the seeds of the iterations of --repeat-until-fail from.
+    pub run_seed: Seed,
+    pub device: ValidDevice,
+}
+
+/// The seed of the data on a device, derived from the seed of the run.
+///
+/// Partitions of the same disk each get their own data. With
+/// --key-by-serial, so does every disk.
+fn device_seed(args: &Args, seed: Seed, device: &ValidDevice) -> Seed {
+    let seed = match device.partition {
+        Some(partition) => crypto::derive_seed(seed, partition),
+        None => seed,
+    };
+    if !args.key_by_serial {
+        return seed;
+    }
+    match device
+        .device
+        .as_ref()
+        .and_then(|d| d.serial_number.as_deref())
+    {
+        Some(serial) => crypto::derive_seed_for_key(seed, serial),
+        None => crypto::derive_seed_for_key(seed, &device.path.to_string_lossy()),
+    }
+}
+
+/// Writes garbage to a device, starting at offset `start`, and reads it back.
+fn run_pass(args: &Args, options: &TestOptions, start: u64) -> anyhow::Result<DeviceResult> {
+    let TestOptions {
+        path,
+        buffer_size,
+        capacity,
+        seed,
+        checkpoint,
+        siblings,
+        verification,
+        ..
+    } = options;
+    let mut write_timing = None;
+    let mut churn_bad_offsets = Vec::new();
+    // This overwrites blocks all over the device, so it goes before the write test:
+    let random_iops = args
+        .speed_class
+        .then(|| speed_class::measure_random_iops(path, *capacity, *seed))
+        .transpose()
+        .context("During the random I/O measurement")?;
+    if args.verify_only {
+        info!(device=?path, "Skipping the write test, verifying data from an earlier run");
+    } else {
+        let written = PhaseTiming::measure(
+            || {
+                if args.churn {
+                    churn::churn(path, *buffer_size, *capacity, *seed)
+                        .map(|(bytes, bad)| (bytes, bad, None))
+                } else if args.random_write_order {
+                    write_test::write_shuffled(
+                        path,
+                        *buffer_size,
+                        *capacity,
+                        *seed,
+                        args.max_offset_jump,
+                    )
+                    .map(|bytes| (bytes, Vec::new(), None))
+                } else {
+                    write_test::write(
+                        path,
+                        *buffer_size,
+                        *capacity,
+                        *seed,
+                        start,
+                        checkpoint.clone(),
+                        args.sanity_reads,
+                    )
+                    .map(|(bytes, sanity)| (bytes, Vec::new(), sanity))
+                }
+            },
+            |(bytes, _, _)| *bytes,
+        );
+        let timing = match written {
+            Ok(((_, _, Some(write_test::SanityFailure { offset })), timing)) => {
+                remove_checkpoint(checkpoint.as_deref())?;
+                error!(event = "pass_complete", device=?path, %seed, bad_blocks = 1, aborted_early = true, offset, "A block did not read back right after writing it (--sanity-reads), skipping the read-back test. THIS IS BAD - RMA THE DRIVE!");
+                return Ok(DeviceResult {
+                    bad_offsets: vec![offset],
+                    aborted_early: true,
+                    write: Some(timing),
+                    ..Outcome::Bad(1).into()
+                });
+            }
+            Ok(((_, bad, None), timing)) => {
+                churn_bad_offsets = bad;
+                timing
+            }
+            Err(e) => {
+                if device_error::is_write_protected(&e) {
+                    error!(device=?path, "Device is write-protected, so it can't be tested.");
+                    anyhow::bail!(
+                        "{:?} is write-protected (read-only). If it holds data from an earlier disk-spinner run, pass --verify-only --seed <seed> to check that data instead.",
+                        path
+                    );
+                }
+                return Err(e).context("During write test");
+            }
+        };
+        write_timing = Some(timing);
+        info!(device=?path, random_order = args.random_write_order, seconds = timing.elapsed.as_secs_f64(), bytes_per_second = timing.bytes_per_second(), "write test succeeded");
+    }
+    let speed_class = random_iops
+        .zip(write_timing)
+        .map(|(iops, timing)| speed_class::SpeedClassReport::new(timing.bytes_per_second(), iops));
+    if let Some(report) = &speed_class {
+        report.log(path);
+    }
+    let policy = policy::Policy::from_args(args);
+    if args.no_read_back {
+        remove_checkpoint(checkpoint.as_deref())?;
+        warn!(event = "pass_complete", device=?path, %seed, "Skipping the read-back test: NO DATA INTEGRITY VERIFICATION WAS PERFORMED.");
+        return Ok(DeviceResult {
+            write: write_timing,
+            speed_class,
+            ..policy.decide(policy::Metrics::Unverified).into()
+        });
+    }
+    if *verification != Verification::Full {
+        remove_checkpoint(checkpoint.as_deref())?;
+        let (mut bad_offsets, read_timing) = match verification {
+            Verification::Sample => {
+                let ((_, bad_offsets), timing) = PhaseTiming::measure(
+                    || {
+                        read_test::verify_samples(
+                            path,
+                            *buffer_size,
+                            *capacity,
+                            *seed,
+                            INTERMEDIATE_SAMPLES,
+                        )
+                        .context("During sampled verification")
+                    },
+                    |(bytes, _)| *bytes,
+                )?;
+                (bad_offsets, Some(timing))
+            }
+            _ => (Vec::new(), None),
+        };
+        for &offset in &bad_offsets {
+            warn!(event = "bad_block", device=?path, offset, "Did not read back the exact bytes written");
+        }
+        bad_offsets.extend(churn_bad_offsets);
+        bad_offsets.sort_unstable();
+        bad_offsets.dedup();
+        let outcome = policy.decide(match (verification, bad_offsets.len()) {
+            (Verification::None, 0) => policy::Metrics::Unverified,
+            // Sampled reads are random, so only the write was sustained:
+            (_, bad_blocks) => policy::Metrics::Verified {
+                bad_blocks,
+                aborted: false,
+                throughput: write_timing.map(|t| t.bytes_per_second() as u64),
+            },
+        });
+        return Ok(DeviceResult {
+            outcome,
+            bad_offsets,
+            write: write_timing,
+            read: read_timing,
+            speed_class,
+            ..Outcome::Unverified.into()
+        });
+    }
+    let mut manifest = args
+        .export_manifest
+        .as_ref()
+        .map(|_| manifest::Manifest::default());
+    let mut transient_offsets = args.verify_twice.then(Vec::new);
+    let read_phase = PhaseTiming::measure(
+        || {
+            let max_bad_blocks = match args.abort_on_first_bad {
+                true => Some(1),
+                false => args.max_bad_blocks,
+            };
+            match args.reverse_read {
+                true => read_test::read_back_reversed(
+                    path,
+                    *buffer_size,
+                    *capacity,
+                    *seed,
+                    max_bad_blocks,
+                    transient_offsets.as_mut(),
+                ),
+                false => read_test::read_back(
+                    path,
+                    *buffer_size,
+                    *capacity,
+                    *seed,
+                    manifest.as_mut(),
+                    max_bad_blocks,
+                    transient_offsets.as_mut(),
+                ),
+            }
+            .context("During read test")
+        },
+        |(bytes, _)| *bytes,
+    );
+    let ((_, result), read_timing) = match read_phase {
+        Ok(read_phase) => read_phase,
+        Err(e) => {
+            let written = write_timing.map(|t| start + t.bytes);
+            return read_error_result(path, written, e);
+        }
+    };
+    debug!(device=?path, reverse = args.reverse_read, seconds = read_timing.elapsed.as_secs_f64(), bytes_per_second = read_timing.bytes_per_second(), "read phase finished");
+    if let (Some(write_timing), Err(read_test::BadBlocks { aborted: false, .. }) | Ok(())) =
+        (write_timing, &result)
+    {
+        // Verifying a different range than was written would report the
+        // difference as bad blocks, or miss it entirely.
+        anyhow::ensure!(
+            start + write_timing.bytes == read_timing.bytes,
+            "Bug: {} bytes were written from offset {}, but {} bytes were read back",
+            write_timing.bytes,
+            start,
+            read_timing.bytes
+        );
+    }
+    remove_checkpoint(checkpoint.as_deref())?;
+    let mut bad = result.err().unwrap_or(read_test::BadBlocks {
+        count: 0,
+        offsets: Vec::new(),
+        aborted: false,
+    });
+    if !churn_bad_offsets.is_empty() {
+        warn!(device=?path, bad_blocks = churn_bad_offsets.len(), "Blocks failed to read back while churning");
+        bad.offsets.extend(churn_bad_offsets);
+        bad.offsets.sort_unstable();
+        bad.offsets.dedup();
+        bad.count = bad.offsets.len();
+    }
+    if args.verify_only && bad.count > 0 {
+        for (sibling, sibling_seed) in siblings.iter().filter(|(_, s)| s != seed) {
+            if read_test::matches_stream(path, *buffer_size, *capacity, *sibling_seed)? {
+                error!(device=?path, belongs_to=?sibling, "The data on {:?} appears to belong to {:?}. Were the drives swapped?", path, sibling);
+            }
+        }
+    }
+    let capacity_report = (!bad.aborted).then(|| {
+        fraud::CapacityReport::analyze(read_timing.bytes, *buffer_size as u64, &bad.offsets)
+    });
+    if let Some(report) = capacity_report.filter(|r| r.is_fake()) {
+        error!(device=?path, claimed_capacity = report.claimed_capacity, usable_capacity = report.usable_capacity, wraparound_offset = report.wraparound_offset, "The device appears to be FAKE: only part of its claimed capacity holds data.");
+    }
+    // The zones only mean something for platters:
+    let rotational = options.device.device.as_ref().is_some_and(is_rotational);
+    let bad_block_zones = capacity_report
+        .filter(|_| rotational)
+        .and_then(|r| zones::ZoneReport::of(r.claimed_capacity, &bad.offsets));
+    if let Some(zone) = bad_block_zones.and_then(|z| z.concentrated_in) {
+        warn!(device=?path, %zone, "Bad blocks are concentrated in the {} of the disk (a rough estimate, assuming a linear outer-to-inner layout).", zone);
+    }
+    let bad_blocks = bad.count;
+    let throughput = write_timing
+        .into_iter()
+        .chain([read_timing])
+        .map(|t| t.bytes_per_second() as u64)
+        .min();
+    if let (Some(throughput), Some(min_throughput)) = (throughput, policy.min_throughput) {
+        info!(device=?path, sustained_bytes_per_second = throughput, min_throughput, "Sustained throughput was {}/s, against a --min-throughput of {}/s", indicatif::BinaryBytes(throughput), indicatif::BinaryBytes(min_throughput));
+    }
+    let outcome = policy.decide(policy::Metrics::Verified {
+        bad_blocks,
+        aborted: bad.aborted,
+        throughput,
+    });
+    match outcome {
+        Outcome::Good => {
+            info!(event = "pass_complete", device=?path, %seed, bad_blocks, "read-back test succeeded");
+            if let (Some(manifest), Some(manifest_path)) = (manifest, &args.export_manifest) {
+                manifest.save(manifest_path)?;
+                info!(device=?path, manifest=?manifest_path, "wrote manifest");
+            }
+        }
+        Outcome::Uncertain(n, _) => {
+            warn!(event = "pass_complete", device=?path, %seed, bad_blocks = n, fail_threshold = policy.fail_threshold, "Data on disk is partly corrupted, but below the failure threshold.");
+        }
+        Outcome::Bad(n) if args.abort_on_first_bad => {
+            error!(event = "pass_complete", device=?path, %seed, bad_blocks = n, aborted_early = true, "Stopped at the first bad block (--abort-on-first-bad). THIS IS BAD - RMA THE DRIVE!");
+        }
+        Outcome::Bad(n) => {
+            error!(event = "pass_complete", device=?path, %seed, bad_blocks = n, aborted_early = bad.aborted, random_write_order = args.random_write_order, "Data on disk is inconsistent/corrupted. THIS IS BAD - RMA THE DRIVE!");
+        }
+        Outcome::Slow => {
+            error!(event = "pass_complete", device=?path, %seed, sustained_bytes_per_second = throughput, min_throughput = policy.min_throughput, "The data read back correctly, but the device is slower than --min-throughput.");
+        }
+        Outcome::Unverified => unreachable!("The data was read back"),
+    }
+    Ok(DeviceResult {
+        outcome,
+        bad_offsets: bad.offsets,
+        aborted_early: bad.aborted,
+        write: write_timing,
+        read: Some(read_timing),
+        initial_state: None,
+        capacity: capacity_report,
+        bad_block_zones,
+        residual_data: None,
+        transient_offsets,
+        pattern: None,
+        speed_class,
+        panic_message: None,
+        error_message: None,
+        capacity_estimate: None,
+        seed: None,
+        ata_capacity: None,
+        timed_out_at: None,
+    })
+}
+
+/// Stops the test of a device on a read error, like [catch_error] would,
+/// but first checks whether the device was written in full (up to
+/// `written`) and fails its reads past some point, as a fake drive that
+/// drops writes does. If it does, the result says how much of it is
+/// usable.
+fn read_error_result(
+    path: &Path,
+    written: Option<u64>,
+    e: anyhow::Error,
+) -> anyhow::Result<DeviceResult> {
+    let failed_at = e
+        .chain()
+        .find_map(|e| e.downcast_ref::<device_error::DeviceIoError>())
+        .filter(|e| e.operation == device_error::Operation::Read)
+        .map(|e| e.offset);
+    let report = match (written, failed_at) {
+        (Some(written), Some(offset)) if !deadline::exceeded() => {
+            fraud::CapacityReport::unreadable_from(written, offset)
+        }
+        _ => return Err(e),
+    };
+    if device_error::is_disconnected(&e) || !report.is_fake() {
+        return Err(e);
+    }
+    error!(device=?path, claimed_capacity = report.claimed_capacity, usable_capacity = report.usable_capacity, "The device appears to be FAKE: it fails to read back anything past {} bytes.", report.usable_capacity);
+    Ok(DeviceResult {
+        capacity: Some(report),
+        ..catch_error(path, Err(e))
+    })
+}
+
+/// The capacity that a block device reports, or None for regular files,
+/// whose length changes as they are written.
+fn device_capacity(path: &Path) -> anyhow::Result<Option<u64>> {
+    if fs::metadata(path)?.is_file() {
+        return Ok(None);
+    }
+    let file = fs::File::open(path).with_context(|| format!("Opening {:?}", path))?;
+    Ok(Some(target::Target::len(&file)?))
+}
+
+/// The size of the block at the end of the device that is read before
+/// testing it: the smallest logical block size, so that it's always whole.
+const LAST_BLOCK_SIZE: u64 = 512;
+
+/// Reads the last block of the capacity that the target reports, bypassing
+/// the cache where possible, to check that it exists.
+fn check_last_block(target: &dyn target::Target) -> anyhow::Result<()> {
+    let len = target.len().context("Determining the device's capacity")?;
+    let offset = len.saturating_sub(LAST_BLOCK_SIZE);
+    if let Err(e) = target.drop_cache(offset, len - offset) {
+        debug!(offset, error = %e, "Could not drop the cache before reading the last block");
+    }
+    let mut buf = vec![0; (len - offset) as usize];
+    std::io::Read::read_exact(&mut target::Cursor::new(target, offset), &mut buf)
+        .map_err(|e| device_error::DeviceIoError::new(device_error::Operation::Read, offset, e))?;
+    Ok(())
+}
+
+/// Refuses to resume from a checkpoint written when the device reported
+/// a different capacity, unless forced to.
+fn check_device_capacity(
+    checkpoint_path: &Path,
+    recorded: Option<u64>,
+    current: Option<u64>,
+    force: bool,
+) -> anyhow::Result<()> {
+    let (Some(recorded), Some(current)) = (recorded, current) else {
+        return Ok(());
+    };
+    if recorded == current {
+        return Ok(());
+    }
+    if force {
+        warn!(checkpoint=?checkpoint_path, recorded, current, "The device's capacity changed since the checkpoint was written, resuming anyway (--force-capacity-mismatch).");
+        return Ok(());
+    }
+    anyhow::bail!(
+        "The device now reports a capacity of {} bytes, but reported {} bytes when the checkpoint {:?} was written. It may have been resized (e.g. by an HPA change) or be a different drive. Pass --force-capacity-mismatch to resume anyway.",
+        current,
+        recorded,
+        checkpoint_path
+    )
+}
+
+/// Parses a `--label` argument, like `/dev/sda=bay3`.
+fn parse_label(s: &str) -> Result<(PathBuf, String), String> {
+    match s.rsplit_once('=') {
+        Some((path, label)) if !path.is_empty() && !label.is_empty() => {
+            Ok((PathBuf::from(path), label.to_string()))
+        }
+        _ => Err(format!("{:?} is not of the form DEVICE=LABEL", s)),
+    }
+}
+
+/// Parses a `--cpu-affinity` argument, like `/dev/sda=0-3,8`.
+fn parse_cpu_affinity(s: &str) -> Result<(PathBuf, Vec<usize>), String> {
+    let (path, cpus) =
+        parse_label(s).map_err(|_| format!("{:?} is not of the form DEVICE=CPUS", s))?;
+    let cpus = units::parse_cpu_list(&cpus)?;
+    if cpus.is_empty() {
+        return Err(format!("{:?} does not list any CPUs", s));
+    }
+    Ok((path, cpus))
+}
+
+/// Checks that the serial number of a device is one of the `expected`
+/// ones (--expect-serial), or if there are none, has the user type it
+/// (--confirm-serial).
+fn confirm_serial(
+    path: &Path,
+    serial: Option<&str>,
+    expected: &[String],
+    destructive: bool,
+    input: &mut dyn std::io::BufRead,
+) -> anyhow::Result<()> {
+    let Some(serial) = serial else {
+        anyhow::bail!(
+            "{:?} has no serial number, so it can't be confirmed with --confirm-serial or --expect-serial.",
+            path
+        );
+    };
+    if !expected.is_empty() {
+        if expected.iter().any(|expected| expected == serial) {
+            return Ok(());
+        }
+        anyhow::bail!(
+            "The serial number of {:?} is {:?}, which was not given with --expect-serial. Is this the right device?",
+            path,
+            serial
+        );
+    }
+    eprint!(
+        "Type the serial number of {:?} to confirm that it's the device to test{}: ",
+        path,
+        if destructive {
+            ", DESTROYING ALL ITS DATA"
+        } else {
+            ""
+        }
+    );
+    std::io::Write::flush(&mut std::io::stderr())?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    if answer.trim() != serial {
+        anyhow::bail!(
+            "That is not the serial number of {:?}. Is this the right device?",
+            path
+        );
+    }
+    Ok(())
+}
+
+/// Returns whether two paths name the same file, following symlinks
+/// (like /dev/disk/by-id/...) where possible.
+fn same_path(a: &Path, b: &Path) -> bool {
+    let canonical = |p: &Path| fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf());
+    a == b || canonical(a) == canonical(b)
+}
+
+/// Takes an exclusive advisory lock on the device, which is held until
+/// the returned file is dropped (or the process exits).
+fn lock_device(path: &Path, ignore_lock: bool) -> anyhow::Result<fs::File> {
+    let file = fs::File::open(path).with_context(|| format!("Opening {:?} for locking", path))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) if ignore_lock => {
+            warn!(device=?path, "Device is locked by another process, but running tests anyway.");
+        }
+        Err(fs::TryLockError::WouldBlock) => anyhow::bail!(
+            "{:?} is locked by another process - is disk-spinner already testing it? Pass --ignore-lock to run anyway.",
+            path
+        ),
+        Err(fs::TryLockError::Error(e)) => {
+            return Err(e).with_context(|| format!("Locking {:?}", path))
+        }
+    }
+    Ok(file)
+}
+
+/// Removes a device's checkpoint once its test has finished.
+fn remove_checkpoint(checkpoint_path: Option<&Path>) -> anyhow::Result<()> {
+    if let Some(checkpoint_path) = checkpoint_path {
+        fs::remove_file(checkpoint_path)
+            .with_context(|| format!("Removing checkpoint {:?}", checkpoint_path))?;
     }
     Ok(())
 }
@@ -118,3 +2245,783 @@ lazy_static! {
         "[{elapsed_precise}] {bar:40.white/grey} {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta_precise}) {msg}",
     ).expect("Internal error in indicatif progress bar template syntax");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::sparse_file;
+    use std::io::{Seek, SeekFrom, Write};
+    use tracing_test::traced_test;
+
+    fn file_args(path: &Path, extra: &[&str]) -> Args {
+        let mut argv = vec!["disk-spinner", "--file-device", "--buffer-size", "4096"];
+        argv.extend_from_slice(extra);
+        argv.push(path.to_str().unwrap());
+        Args::parse_from(argv)
+    }
+
+    #[traced_test]
+    #[test]
+    fn file_device_good() {
+        let path = sparse_file("good", 1024 * 1024);
+        let args = file_args(&path, &[]);
+        let outcome = test_device(&args, 1.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert_eq!(outcome, Outcome::Good);
+    }
+
+    #[traced_test]
+    #[test]
+    fn fails_sanity_reads() {
+        let path = sparse_file("sanity", 65536);
+        let args = file_args(&path, &["--capacity", "65536", "--sanity-reads", "100"]);
+        // Reads of /dev/zero never return what was written to it. It is
+        // a character device, so it can only be passed in directly:
+        let device = ValidDevice {
+            path: "/dev/zero".into(),
+            ..args.devices[0].clone()
+        };
+        let result = test_device(&args, 1.into(), device).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Bad(1));
+        assert_eq!(result.bad_offsets, vec![0]);
+        assert!(result.read.is_none());
+    }
+
+    #[traced_test]
+    #[test]
+    fn reports_unreadable_capacity() {
+        let path = Path::new("/dev/fake");
+        let read_error = |offset| {
+            let e = device_error::DeviceIoError::new(
+                device_error::Operation::Read,
+                offset,
+                std::io::Error::from_raw_os_error(libc::EIO),
+            );
+            anyhow::Error::new(e).context("During read test")
+        };
+        let result = read_error_result(path, Some(65536), read_error(16384)).expect("Fake device");
+        assert_eq!(
+            result.outcome,
+            Outcome::Uncertain(0, UncertainReason::ReadError)
+        );
+        assert_eq!(result.capacity.unwrap().usable_capacity, 16384);
+        assert!(result.error_message.is_some());
+        assert!(logs_contain("The device appears to be FAKE"));
+        // A read error near the end is more likely a bad patch, and
+        // without a write there's no telling what should be readable:
+        assert!(read_error_result(path, Some(65536), read_error(65000)).is_err());
+        assert!(read_error_result(path, None, read_error(16384)).is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn repeats_until_fail() {
+        let path = sparse_file("repeat", 65536);
+        let args = file_args(&path, &["--capacity", "65536", "--repeat-until-fail"]);
+        let device = ValidDevice {
+            path: "/dev/zero".into(),
+            partition: Some(2),
+            ..args.devices[0].clone()
+        };
+        let result = test_device(&args, 1.into(), device).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Bad(16));
+        assert_eq!(result.seed, Some(crypto::derive_seed(1.into(), 1)));
+        // It isn't a hard disk, so there are no platters to place them on:
+        assert!(result.capacity.is_some());
+        assert_eq!(result.bad_block_zones, None);
+        assert!(logs_contain("Found a failure after 1 iterations"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn stops_at_uncertain_passes() {
+        let path = sparse_file("uncertain-pass", 65536);
+        let args = file_args(
+            &path,
+            &[
+                "--capacity",
+                "65536",
+                "--passes",
+                "2",
+                "--fail-threshold",
+                "100",
+            ],
+        );
+        // Every block of /dev/zero reads back wrong, but fewer than the threshold:
+        let device = ValidDevice {
+            path: "/dev/zero".into(),
+            ..args.devices[0].clone()
+        };
+        let result = test_device(&args, 1.into(), device).expect("No io errors");
+        assert_eq!(
+            result.outcome,
+            Outcome::Uncertain(16, UncertainReason::BelowThreshold)
+        );
+        assert!(logs_contain("Pass 1 of 2 is uncertain"));
+        assert!(!logs_contain("Pass 1 of 2 failed"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn runs_out_of_time() {
+        let path = sparse_file("budget", 1024 * 1024);
+        let args = file_args(&path, &["--max-runtime-per-device", "0s"]);
+        let result = test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(
+            result.outcome,
+            Outcome::Uncertain(0, UncertainReason::Timeout)
+        );
+        assert_eq!(result.timed_out_at, Some(deadline::Progress::default()));
+        assert!(logs_contain("ran out of --max-runtime-per-device"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn churns() {
+        let path = sparse_file("churn", 1024 * 1024);
+        let args = file_args(&path, &["--churn"]);
+        let result = test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Good);
+        assert_eq!(result.write.unwrap().bytes, 1024 * 1024);
+    }
+
+    #[traced_test]
+    #[test]
+    fn file_device_bad() {
+        let path = sparse_file("bad", 1024 * 1024);
+        let args = file_args(&path, &[]);
+        let result = test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Good);
+
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(1024 * 512)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+
+        let args = file_args(&path, &["--verify-only", "--seed", "1"]);
+        let result = test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Bad(1));
+        assert_eq!(result.bad_offsets, vec![1024 * 512]);
+    }
+
+    #[traced_test]
+    #[test]
+    fn fail_threshold() {
+        let path = sparse_file("threshold", 65536);
+        write_test::write(&path, 4096, Some(65536), 1.into(), 0, None, None).expect("No io errors");
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(1000)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+
+        let args = file_args(&path, &["--verify-only", "--seed", "1"]);
+        let outcome = test_device(&args, 1.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert_eq!(outcome, Outcome::Bad(1));
+        let args = file_args(
+            &path,
+            &["--verify-only", "--seed", "1", "--fail-threshold", "2"],
+        );
+        let outcome = test_device(&args, 1.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert_eq!(
+            outcome,
+            Outcome::Uncertain(1, UncertainReason::BelowThreshold)
+        );
+        let args = file_args(
+            &path,
+            &[
+                "--verify-only",
+                "--seed",
+                "1",
+                "--fail-threshold",
+                "2",
+                "--abort-on-first-bad",
+            ],
+        );
+        let result = test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Bad(1));
+        assert!(result.aborted_early);
+        assert!(logs_contain("Stopped at the first bad block"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn ignores_implausible_block_sizes() {
+        let path = Path::new("/dev/sdz");
+        assert_eq!(
+            default_buffer_size(path, None, Some(4096)),
+            (4096, "physical block size")
+        );
+        assert_eq!(
+            default_buffer_size(path, Some((65536, "optimal I/O size")), Some(4096)),
+            (65536, "optimal I/O size")
+        );
+        assert!(!logs_contain("implausible"));
+        assert_eq!(default_buffer_size(path, None, Some(0)), (8192, "default"));
+        assert_eq!(default_buffer_size(path, None, Some(1)), (8192, "default"));
+        assert_eq!(
+            default_buffer_size(path, Some((1 << 30, "optimal I/O size")), Some(512)),
+            (512, "physical block size")
+        );
+        assert_eq!(default_buffer_size(path, None, None), (8192, "default"));
+        assert!(logs_contain("implausible block size"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn warms_up() {
+        let path = sparse_file("warmup-device", 65536);
+        assert_eq!(
+            file_args(&path, &["--warmup", "2m"]).warmup,
+            Some(Duration::from_secs(120))
+        );
+        let args = file_args(&path, &["--warmup", "0s"]);
+        let result = test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Good);
+        assert!(logs_contain("warming up"));
+    }
+
+    #[test]
+    fn parses_labels() {
+        assert_eq!(
+            parse_label("/dev/sda=bay3"),
+            Ok((PathBuf::from("/dev/sda"), "bay3".to_string()))
+        );
+        assert!(parse_label("/dev/sda").is_err());
+        assert!(parse_label("/dev/sda=").is_err());
+        assert!(parse_label("=bay3").is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn pins_to_cpus() {
+        assert_eq!(
+            parse_cpu_affinity("/dev/sda=0-2,8"),
+            Ok((PathBuf::from("/dev/sda"), vec![0, 1, 2, 8]))
+        );
+        assert!(parse_cpu_affinity("/dev/sda=").is_err());
+        assert!(parse_cpu_affinity("/dev/sda=x").is_err());
+
+        let path = sparse_file("affinity", 65536);
+        let affinity = format!("{}=0", path.display());
+        let args = file_args(&path, &["--cpu-affinity", &affinity]);
+        let outcome = test_device(&args, 1.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert_eq!(outcome, Outcome::Good);
+        #[cfg(target_os = "linux")]
+        assert!(logs_contain("Pinned to CPUs"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn preserves_data() {
+        let path = sparse_file("preserve-data", 0);
+        let contents: Vec<u8> = (0..65536u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        let dir = std::env::temp_dir();
+        let args = file_args(&path, &["--preserve", dir.to_str().unwrap()]);
+        let outcome = test_device(&args, 1.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert_eq!(outcome, Outcome::Good);
+        assert_eq!(fs::read(&path).unwrap(), contents);
+        let image_path = checkpoint::sidecar_path(&dir, &path, None, None, "img");
+        assert!(!image_path.exists());
+    }
+
+    #[traced_test]
+    #[test]
+    fn refuses_zero_capacity() {
+        let path = sparse_file("zero-capacity", 65536);
+        let args = file_args(&path, &["--capacity", "0"]);
+        let err = test_device(&args, 1.into(), args.devices[0].clone()).unwrap_err();
+        assert!(err.to_string().contains("zero capacity"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn catches_panics() {
+        let path = Path::new("/dev/sdz");
+        let result = catch_panic(path, || Ok(Outcome::Good.into())).unwrap();
+        assert_eq!(result.outcome, Outcome::Good);
+        let result = catch_panic(path, || panic!("oops at offset {}", 4096)).unwrap();
+        assert_eq!(
+            result.outcome,
+            Outcome::Uncertain(0, UncertainReason::Panicked)
+        );
+        assert_eq!(result.panic_message.as_deref(), Some("oops at offset 4096"));
+        assert!(logs_contain("The test of the device panicked"));
+        assert!(catch_panic(path, || anyhow::bail!("not a panic")).is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn catches_errors() {
+        let path = Path::new("/dev/sdz");
+        let io_error = |operation, errno| -> anyhow::Result<DeviceResult> {
+            let e = std::io::Error::from_raw_os_error(errno);
+            Err(device_error::DeviceIoError::new(operation, 4096, e)).context("During the test")
+        };
+        let reason = |result| match catch_error(path, result).outcome {
+            Outcome::Uncertain(0, reason) => reason,
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        };
+        assert_eq!(
+            reason(io_error(device_error::Operation::Write, libc::EIO)),
+            UncertainReason::WriteFailed
+        );
+        assert_eq!(
+            reason(io_error(device_error::Operation::Read, libc::EIO)),
+            UncertainReason::ReadError
+        );
+        assert_eq!(
+            reason(io_error(device_error::Operation::Read, libc::ENODEV)),
+            UncertainReason::Disconnected
+        );
+        assert_eq!(
+            reason(Err(anyhow::anyhow!("not an I/O error"))),
+            UncertainReason::Aborted
+        );
+        let result = catch_error(
+            path,
+            Err(anyhow::anyhow!("oops")).context("During the test"),
+        );
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("During the test: oops")
+        );
+        assert!(logs_contain("stopped on an error"));
+        let result = catch_error(path, Ok(Outcome::Good.into()));
+        assert_eq!(result.outcome, Outcome::Good);
+    }
+
+    #[traced_test]
+    #[test]
+    fn measures_speed_class() {
+        let path = sparse_file("speed-class", 0);
+        let args = file_args(&path, &["--capacity", "1M", "--speed-class"]);
+        let result = test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Good);
+        let report = result.speed_class.expect("Speed classes were measured");
+        assert!(report.random_read_iops > 0 && report.random_write_iops > 0);
+        assert_eq!(report.meets.len() + report.fails.len(), 13);
+        assert!(logs_contain("Measured speed classes"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn fails_slow_devices() {
+        let path = sparse_file("min-throughput", 65536);
+        let result = |extra: &[&str]| {
+            let args = file_args(&path, extra);
+            test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors")
+        };
+        let outcome = |min: &str| result(&["--min-throughput", min]).outcome;
+        assert_eq!(outcome("1"), Outcome::Good);
+        assert_eq!(outcome("1000T"), Outcome::Slow);
+        assert!(logs_contain("slower than --min-throughput"));
+        // The pattern passes of --pattern-set are held to it, too:
+        let slow = result(&[
+            "--passes",
+            "2",
+            "--pattern-set",
+            "--min-throughput",
+            "1000T",
+        ]);
+        assert_eq!(slow.outcome, Outcome::Slow);
+        assert_eq!(slow.pattern, Some(pattern::PATTERN_SET[0]));
+    }
+
+    #[test]
+    fn verifies_twice_in_full() {
+        let path = sparse_file("verify-twice", 4096);
+        let parse = |extra: &[&str]| {
+            let mut argv = vec!["disk-spinner", "--file-device", "--verify-twice"];
+            argv.extend_from_slice(extra);
+            argv.push(path.to_str().unwrap());
+            Args::try_parse_from(argv)
+        };
+        assert!(parse(&[]).is_ok());
+        assert!(parse(&["--abort-on-first-bad"]).is_err());
+        assert!(parse(&["--max-bad-blocks", "3"]).is_err());
+    }
+
+    #[test]
+    fn confirms_serials() {
+        let path = Path::new("/dev/sda");
+        let expected = ["ZL2ABC".to_string(), "ZL2DEF".to_string()];
+        let confirm = |serial, expected: &[String], input: &str| {
+            confirm_serial(path, serial, expected, true, &mut input.as_bytes())
+        };
+        assert!(confirm(Some("ZL2ABC"), &expected, "").is_ok());
+        assert!(confirm(Some("ZL2XYZ"), &expected, "ZL2XYZ\n").is_err());
+        assert!(confirm(Some("ZL2ABC"), &[], "ZL2ABC\n").is_ok());
+        assert!(confirm(Some("ZL2ABC"), &[], "  ZL2ABC  \n").is_ok());
+        assert!(confirm(Some("ZL2ABC"), &[], "zl2abc\n").is_err());
+        assert!(confirm(Some("ZL2ABC"), &[], "").is_err());
+        let err = confirm(None, &[], "\n").unwrap_err();
+        assert!(err.to_string().contains("has no serial number"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn reads_back_in_reverse() {
+        let path = sparse_file("reverse-read", 65536 + 1000);
+        let args = file_args(&path, &["--reverse-read"]);
+        let result = test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Good);
+        assert_eq!(result.read.unwrap().bytes, 65536 + 1000);
+    }
+
+    #[traced_test]
+    #[test]
+    fn writes_layout_spec() {
+        let path = sparse_file("layout-spec", 65536);
+        let spec = path.with_extension("layout");
+        fs::write(&spec, "4K 8K pattern=checkerboard\n32K 4K zeroes\n").unwrap();
+        let args = file_args(&path, &["--layout-spec", spec.to_str().unwrap()]);
+        let result = test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Good);
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data[4096..4098], [0x55, 0xaa]);
+        assert!(data[32768..36864].iter().all(|&b| b == 0));
+        assert!(logs_contain("layout read-back succeeded"));
+        let slow = file_args(
+            &path,
+            &[
+                "--layout-spec",
+                spec.to_str().unwrap(),
+                "--min-throughput",
+                "1000T",
+            ],
+        );
+        let result = test_device(&slow, 1.into(), slow.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Slow);
+        assert!(logs_contain(
+            "The layout read back correctly, but the device is slower"
+        ));
+        let mut argv = vec!["disk-spinner", "--file-device", "--layout-spec"];
+        argv.extend([spec.to_str().unwrap(), "--max-bad-blocks", "1"]);
+        argv.push(path.to_str().unwrap());
+        assert!(Args::try_parse_from(argv).is_err());
+        fs::remove_file(spec).unwrap();
+    }
+
+    #[traced_test]
+    #[test]
+    fn estimates_capacity() {
+        let path = sparse_file("estimate-only", 1024 * 1024);
+        let args = file_args(&path, &["--estimate-only"]);
+        let result = test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Unverified);
+        let estimate = result.capacity_estimate.expect("An estimate");
+        assert_eq!(estimate.fraud_likelihood, estimate::Likelihood::Unlikely);
+        assert_eq!(estimate.claimed_capacity, 1024 * 1024);
+        assert!(logs_contain("the capacity is plausible"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn capacity_override() {
+        let path = sparse_file("capacity", 0);
+        let args = file_args(&path, &["--capacity", "64K"]);
+        let outcome = test_device(&args, 1.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert_eq!(outcome, Outcome::Good);
+        assert_eq!(path.metadata().unwrap().len(), 65536);
+    }
+
+    #[traced_test]
+    #[test]
+    fn manifest_roundtrip() {
+        let path = sparse_file("manifest", 1024 * 1024);
+        let manifest_path = path.with_extension("manifest");
+        let args = file_args(
+            &path,
+            &["--export-manifest", manifest_path.to_str().unwrap()],
+        );
+        let outcome = test_device(&args, 1.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert_eq!(outcome, Outcome::Good);
+
+        let args = file_args(
+            &path,
+            &["--verify-manifest", manifest_path.to_str().unwrap()],
+        );
+        let outcome = test_device(&args, 2.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert_eq!(outcome, Outcome::Good);
+
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(1000)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+        let outcome = test_device(&args, 2.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert_eq!(outcome, Outcome::Bad(1));
+        fs::remove_file(manifest_path).unwrap();
+    }
+
+    #[traced_test]
+    #[test]
+    fn resumes_from_checkpoint() {
+        let path = sparse_file("resume", 1024 * 1024);
+        let dir = std::env::temp_dir();
+        let args = file_args(
+            &path,
+            &["--checkpoint-dir", dir.to_str().unwrap(), "--resume"],
+        );
+        let checkpoint_path = checkpoint::Checkpoint::path_for(&dir, &path, None, None);
+        // Pretend an earlier run got halfway, writing the first half properly:
+        write_test::write(
+            &path,
+            4096,
+            Some(1024 * 512),
+            7.into(),
+            0,
+            Some(checkpoint_path.clone()),
+            None,
+        )
+        .expect("No io errors");
+        let mut checkpoint = checkpoint::Checkpoint::load(&checkpoint_path)
+            .unwrap()
+            .unwrap();
+        assert_eq!(checkpoint.offset, 1024 * 512);
+        assert_eq!(checkpoint.seed, 7.into());
+        checkpoint.capacity = 1024 * 1024;
+        checkpoint.save(&checkpoint_path).unwrap();
+
+        // Resuming has to continue with the same options:
+        let mismatched = Args::parse_from([
+            "disk-spinner",
+            "--file-device",
+            "--buffer-size",
+            "8192",
+            "--checkpoint-dir",
+            dir.to_str().unwrap(),
+            "--resume",
+            path.to_str().unwrap(),
+        ]);
+        let err = test_device(&mismatched, 1.into(), mismatched.devices[0].clone()).unwrap_err();
+        assert!(err.to_string().contains("buffer size of 4096"));
+        let mismatched = file_args(
+            &path,
+            &[
+                "--checkpoint-dir",
+                dir.to_str().unwrap(),
+                "--resume",
+                "--capacity",
+                "768K",
+            ],
+        );
+        let err = test_device(&mismatched, 1.into(), mismatched.devices[0].clone()).unwrap_err();
+        assert!(err.to_string().contains("capacity of 1048576 bytes"));
+
+        let result = test_device(&args, 1.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Good);
+        // The data is that of the earlier run, not of this one's seed:
+        assert_eq!(result.seed, Some(7.into()));
+        assert!(!checkpoint_path.exists());
+        let os_random = Args::try_parse_from([
+            "disk-spinner",
+            "--file-device",
+            "--os-random",
+            "--checkpoint-dir",
+            dir.to_str().unwrap(),
+            "--resume",
+            path.to_str().unwrap(),
+        ]);
+        assert!(os_random.is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn checks_device_capacity() {
+        let checkpoint = Path::new("disk-spinner-ZL2ABC.checkpoint");
+        assert!(check_device_capacity(checkpoint, Some(4096), Some(4096), false).is_ok());
+        assert!(check_device_capacity(checkpoint, None, Some(4096), false).is_ok());
+        assert!(check_device_capacity(checkpoint, Some(4096), None, false).is_ok());
+        let err = check_device_capacity(checkpoint, Some(8192), Some(4096), false).unwrap_err();
+        assert!(err.to_string().contains("--force-capacity-mismatch"));
+        assert!(check_device_capacity(checkpoint, Some(8192), Some(4096), true).is_ok());
+        assert!(logs_contain("resuming anyway"));
+        let path = sparse_file("capacity", 4096);
+        assert_eq!(device_capacity(&path).unwrap(), None);
+    }
+
+    /// A device that claims twice the capacity it has.
+    #[derive(Debug)]
+    struct Oversized(target::MemoryTarget);
+
+    impl target::Target for Oversized {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+            self.0.read_at(buf, offset)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+            self.0.write_at(buf, offset)
+        }
+
+        fn len(&self) -> std::io::Result<u64> {
+            Ok(self.0.len()? * 2)
+        }
+
+        fn sync(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn checks_last_block() {
+        assert!(check_last_block(&target::MemoryTarget::new(4096)).is_ok());
+        assert!(check_last_block(&target::MemoryTarget::new(100)).is_ok());
+        let path = sparse_file("last-block", 4096);
+        assert!(check_last_block(&fs::File::open(&path).unwrap()).is_ok());
+        let err = check_last_block(&Oversized(target::MemoryTarget::new(4096))).unwrap_err();
+        assert!(format!("{:#}", err).contains("7680"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn no_read_back() {
+        let path = sparse_file("noreadback", 65536);
+        let args = file_args(
+            &path,
+            &["--no-read-back", "--i-understand-nothing-gets-verified"],
+        );
+        let outcome = test_device(&args, 1.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert_eq!(outcome, Outcome::Unverified);
+        assert!(Args::try_parse_from(["disk-spinner", "--no-read-back", "/dev/null"]).is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn verify_only() {
+        let path = sparse_file("verifyonly", 65536);
+        write_test::write(&path, 4096, Some(65536), 5.into(), 0, None, None).expect("No io errors");
+        let args = file_args(&path, &["--verify-only", "--seed", "5"]);
+        let outcome = test_device(&args, 5.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert_eq!(outcome, Outcome::Good);
+        let outcome = test_device(&args, 6.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert!(matches!(outcome, Outcome::Bad(_)));
+    }
+
+    #[traced_test]
+    #[test]
+    fn runs_several_passes() {
+        let path = sparse_file("passes", 65536);
+        for verification in ["full", "sample", "none"] {
+            let args = file_args(
+                &path,
+                &[
+                    "--passes",
+                    "3",
+                    "--intermediate-verify",
+                    verification,
+                    "--seed",
+                    "5",
+                ],
+            );
+            let outcome = test_device(&args, 5.into(), args.devices[0].clone())
+                .expect("No io errors")
+                .outcome;
+            assert_eq!(outcome, Outcome::Good);
+            // The last pass leaves the data of --seed on the device.
+            let args = file_args(&path, &["--verify-only", "--seed", "5"]);
+            let outcome = test_device(&args, 5.into(), args.devices[0].clone())
+                .expect("No io errors")
+                .outcome;
+            assert_eq!(outcome, Outcome::Good);
+        }
+        assert!(logs_contain("Pass 1 of 3 passed"));
+        assert!(logs_contain("Pass written, not verified"));
+        assert!(Args::try_parse_from([
+            "disk-spinner",
+            "--intermediate-verify",
+            "none",
+            "/dev/null"
+        ])
+        .is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn rotates_patterns() {
+        let path = sparse_file("patterns", 65536);
+        let args = file_args(&path, &["--passes", "3", "--pattern-set"]);
+        let result = test_device(&args, 5.into(), args.devices[0].clone()).expect("No io errors");
+        assert_eq!(result.outcome, Outcome::Good);
+        assert_eq!(result.pattern, None);
+        assert!(logs_contain("walking-ones"));
+        assert!(logs_contain("walking-zeros"));
+        assert!(!logs_contain("inverse-checkerboard"));
+        assert!(Args::try_parse_from(["disk-spinner", "--pattern-set", "/dev/null"]).is_err());
+        let with_pattern_set = |flags: &[&str]| {
+            let mut argv = vec![
+                "disk-spinner",
+                "--file-device",
+                "--passes",
+                "3",
+                "--pattern-set",
+            ];
+            argv.extend_from_slice(flags);
+            argv.push(path.to_str().unwrap());
+            Args::try_parse_from(argv)
+        };
+        assert!(with_pattern_set(&[]).is_ok());
+        assert!(with_pattern_set(&["--max-bad-blocks", "1"]).is_err());
+        assert!(with_pattern_set(&["--block-log", "/tmp/blocks.csv"]).is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn detects_swapped_devices() {
+        let a = sparse_file("swapped-a", 65536);
+        let b = sparse_file("swapped-b", 65536);
+        let mut args = file_args(&a, &["--verify-only", "--seed", "5", "--key-by-serial"]);
+        args.devices.push(b.to_str().unwrap().parse().unwrap());
+        let b_seed = device_seed(&args, 5.into(), &args.devices[1]);
+        assert_ne!(b_seed, device_seed(&args, 5.into(), &args.devices[0]));
+        write_test::write(&a, 4096, Some(65536), b_seed, 0, None, None).expect("No io errors");
+        let outcome = test_device(&args, 5.into(), args.devices[0].clone())
+            .expect("No io errors")
+            .outcome;
+        assert!(matches!(outcome, Outcome::Bad(_)));
+        assert!(logs_contain("appears to belong to"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn refuses_locked_device() {
+        let path = sparse_file("locked", 65536);
+        let other_run = fs::File::open(&path).unwrap();
+        other_run.lock().unwrap();
+        let args = file_args(&path, &[]);
+        let err = test_device(&args, 1.into(), args.devices[0].clone()).unwrap_err();
+        assert!(err.to_string().contains("locked by another process"));
+        drop(other_run);
+    }
+
+    #[traced_test]
+    #[test]
+    fn regular_file_needs_flag() {
+        let path = sparse_file("noflag", 4096);
+        let args = Args::parse_from(["disk-spinner", path.to_str().unwrap()]);
+        assert!(test_device(&args, 1.into(), args.devices[0].clone()).is_err());
+    }
+}
@@ -0,0 +1,276 @@
+//! Exporting and verifying manifests of per-region checksums.
+//!
+//! A manifest records a SHA-256 hash for each fixed-size region of a
+//! device, so its contents can be verified later without knowing the
+//! seed that generated them.
+
+use crate::{
+    device_error::{DeviceIoError, Operation},
+    events::ProgressEvents,
+    read_test::FailedReads,
+    PROGRESS_STYLE,
+};
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+};
+use tracing::{info_span, warn};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+/// The size of the regions that get hashed individually.
+pub(crate) const REGION_SIZE: u64 = 1024 * 1024 * 1024;
+
+const HEADER: &str = "# disk-spinner manifest v1";
+
+/// The checksum of one region of a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Region {
+    pub offset: u64,
+    pub length: u64,
+    pub hash: [u8; 32],
+}
+
+/// A list of checksummed regions, covering a device from its start.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub regions: Vec<Region>,
+}
+
+impl Manifest {
+    /// The number of bytes covered by the manifest.
+    pub(crate) fn length(&self) -> u64 {
+        self.regions
+            .last()
+            .map(|r| r.offset + r.length)
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, self.to_string()).with_context(|| format!("Writing manifest {:?}", path))
+    }
+
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Reading manifest {:?}", path))?;
+        contents
+            .parse()
+            .with_context(|| format!("Parsing manifest {:?}", path))
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        for region in &self.regions {
+            write!(f, "{} {} sha256:", region.offset, region.length)?;
+            for byte in region.hash {
+                write!(f, "{:02x}", byte)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Manifest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines();
+        if lines.next() != Some(HEADER) {
+            anyhow::bail!(
+                "Not a disk-spinner manifest (expected {:?} on the first line)",
+                HEADER
+            );
+        }
+        let mut manifest = Manifest::default();
+        for (n, line) in lines.enumerate().filter(|(_, l)| !l.starts_with('#')) {
+            let parse_line = || -> anyhow::Result<Region> {
+                let mut fields = line.split_whitespace();
+                let mut next = || fields.next().ok_or(anyhow::anyhow!("missing field"));
+                let offset = next()?.parse()?;
+                let length = next()?.parse()?;
+                let hex = next()?
+                    .strip_prefix("sha256:")
+                    .ok_or(anyhow::anyhow!("unsupported hash type"))?;
+                if hex.len() != 64 {
+                    anyhow::bail!("hash has the wrong length");
+                }
+                let mut hash = [0; 32];
+                for (i, byte) in hash.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+                }
+                Ok(Region {
+                    offset,
+                    length,
+                    hash,
+                })
+            };
+            let region = parse_line().with_context(|| format!("On line {}", n + 2))?;
+            if region.offset != manifest.length() {
+                anyhow::bail!("Regions on line {} are not contiguous", n + 2);
+            }
+            manifest.regions.push(region);
+        }
+        Ok(manifest)
+    }
+}
+
+/// An [io::Write] that hashes all data passing through it into
+/// regions, before handing it on to an inner writer.
+#[derive(Debug)]
+pub(crate) struct RegionHasher<W: io::Write> {
+    inner: W,
+    region_size: u64,
+    hasher: Sha256,
+    offset: u64,
+    filled: u64,
+    manifest: Manifest,
+}
+
+impl<W: io::Write> RegionHasher<W> {
+    pub(crate) fn new(inner: W, region_size: u64) -> Self {
+        Self {
+            inner,
+            region_size,
+            hasher: Sha256::new(),
+            offset: 0,
+            filled: 0,
+            manifest: Manifest::default(),
+        }
+    }
+
+    fn finish_region(&mut self) {
+        let hash = std::mem::take(&mut self.hasher).finalize().into();
+        self.manifest.regions.push(Region {
+            offset: self.offset,
+            length: self.filled,
+            hash,
+        });
+        self.offset += self.filled;
+        self.filled = 0;
+    }
+
+    /// Returns the manifest of all data written so far, and the inner
+    /// writer, which is not flushed.
+    pub(crate) fn finish(mut self) -> (Manifest, W) {
+        if self.filled > 0 {
+            self.finish_region();
+        }
+        (self.manifest, self.inner)
+    }
+}
+
+impl<W: io::Write> io::Write for RegionHasher<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let mut rest = &buf[..written];
+        while !rest.is_empty() {
+            let space = (self.region_size - self.filled).min(rest.len() as u64) as usize;
+            self.hasher.update(&rest[..space]);
+            self.filled += space as u64;
+            rest = &rest[space..];
+            if self.filled == self.region_size {
+                self.finish_region();
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads back the device and compares its hashed regions against a manifest.
+#[tracing::instrument(skip(dev_path, buffer_size, manifest), fields(device = ?dev_path))]
+pub(crate) fn verify(
+    dev_path: &Path,
+    buffer_size: usize,
+    manifest: &Manifest,
+) -> anyhow::Result<Result<(), FailedReads>> {
+    let blockdev = OpenOptions::new()
+        .read(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for reading", dev_path))?;
+    let capacity = manifest.length();
+    let region_size = manifest
+        .regions
+        .first()
+        .map(|r| r.length)
+        .unwrap_or(REGION_SIZE);
+
+    let bar_span = info_span!("verifying manifest");
+    bar_span.pb_set_style(&PROGRESS_STYLE);
+    bar_span.pb_set_length(capacity);
+    let _bar_span_handle = bar_span.enter();
+
+    let events = ProgressEvents::new("verify", capacity, 0);
+    let mut blockdev = blockdev.take(capacity);
+    let mut hasher = RegionHasher::new(io::sink(), region_size);
+    let mut buf = vec![0; buffer_size];
+    let mut offset = 0;
+    loop {
+        let read = blockdev
+            .read(&mut buf)
+            .map_err(|e| DeviceIoError::new(Operation::Read, offset, e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.write_all(&buf[..read])?;
+        offset += read as u64;
+        events.inc(read as u64);
+    }
+    let (found, _) = hasher.finish();
+
+    let mut mismatched = 0;
+    for expected in &manifest.regions {
+        let actual = found.regions.iter().find(|r| r.offset == expected.offset);
+        if actual != Some(expected) {
+            warn!(
+                offset = expected.offset,
+                length = expected.length,
+                "Region does not match the manifest"
+            );
+            mismatched += 1;
+        }
+    }
+    if mismatched > 0 {
+        return Ok(Err(mismatched));
+    }
+    Ok(Ok(()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hashes_regions() {
+        let data: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+        let mut hasher = RegionHasher::new(Vec::new(), 4096);
+        for chunk in data.chunks(1000) {
+            hasher.write_all(chunk).unwrap();
+        }
+        let (manifest, inner) = hasher.finish();
+        assert_eq!(inner, data);
+        let lengths: Vec<u64> = manifest.regions.iter().map(|r| r.length).collect();
+        assert_eq!(lengths, vec![4096, 4096, 1808]);
+        assert_eq!(manifest.length(), 10_000);
+        let expected: [u8; 32] = Sha256::digest(&data[4096..8192]).into();
+        assert_eq!(manifest.regions[1].hash, expected);
+    }
+
+    #[test]
+    fn roundtrips_text_format() {
+        let mut hasher = RegionHasher::new(io::sink(), 16);
+        hasher.write_all(&[7; 40]).unwrap();
+        let (manifest, _) = hasher.finish();
+        assert_eq!(manifest.to_string().parse::<Manifest>().unwrap(), manifest);
+        assert!("garbage".parse::<Manifest>().is_err());
+    }
+}
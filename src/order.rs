@@ -0,0 +1,122 @@
+//! Orders in which to visit the blocks of a device.
+
+use crate::crypto::Seed;
+use rand::prelude::*;
+
+/// A permutation of the block indexes `0..n`, derived from a seed.
+///
+/// This is an affine map `i -> (a * i + b) mod n` with `a` coprime to
+/// `n`, which visits every block exactly once without having to keep a
+/// shuffled list of billions of blocks in memory.
+///
+/// A windowed permutation only shuffles the blocks within consecutive
+/// windows, visiting the windows in order, which bounds how far apart
+/// two consecutive blocks can be.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BlockPermutation {
+    n: u64,
+    window: u64,
+    /// The order within each full window, and within the last, shorter one.
+    full: Affine,
+    last: Affine,
+}
+
+impl BlockPermutation {
+    pub(crate) fn new(n: u64, seed: Seed) -> Self {
+        Self::windowed(n, n, seed)
+    }
+
+    /// A permutation that visits the windows of `window` blocks in order,
+    /// so consecutive blocks are less than `2 * window` blocks apart.
+    pub(crate) fn windowed(n: u64, window: u64, seed: Seed) -> Self {
+        let window = window.clamp(1, n.max(1));
+        let mut rng = seed.rng();
+        let full = Affine::new(window, &mut rng);
+        let last = Affine::new(n % window, &mut rng);
+        Self {
+            n,
+            window,
+            full,
+            last,
+        }
+    }
+
+    /// The block to visit at step `i`.
+    pub(crate) fn nth(&self, i: u64) -> u64 {
+        let (w, j) = (i / self.window, i % self.window);
+        let start = w * self.window;
+        if start + self.window <= self.n {
+            // Rotate each window, so they don't all repeat the same order:
+            start + self.full.nth((j + w) % self.window)
+        } else {
+            start + self.last.nth(j)
+        }
+    }
+}
+
+/// An affine permutation of `0..n`.
+#[derive(Debug, Clone, Copy)]
+struct Affine {
+    n: u64,
+    a: u64,
+    b: u64,
+}
+
+impl Affine {
+    fn new(n: u64, rng: &mut impl Rng) -> Self {
+        if n <= 1 {
+            return Self { n, a: 1, b: 0 };
+        }
+        let mut a = rng.gen_range(1..n);
+        while gcd(a, n) != 1 {
+            a = a % (n - 1) + 1;
+        }
+        let b = rng.gen_range(0..n);
+        Self { n, a, b }
+    }
+
+    fn nth(&self, i: u64) -> u64 {
+        ((self.a as u128 * i as u128 + self.b as u128) % self.n.max(1) as u128) as u64
+    }
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[cfg(test)]
+mod test {
+    use super::BlockPermutation;
+
+    #[test]
+    fn visits_every_block_once() {
+        for n in [1, 2, 7, 64, 1000, 4097] {
+            let permutation = BlockPermutation::new(n, 42.into());
+            let mut visited: Vec<u64> = (0..n).map(|i| permutation.nth(i)).collect();
+            visited.sort();
+            assert_eq!(visited, (0..n).collect::<Vec<u64>>());
+        }
+    }
+
+    #[test]
+    fn bounds_jumps() {
+        for (n, window) in [(1, 1), (10, 1), (1000, 7), (4097, 64), (64, 100)] {
+            let permutation = BlockPermutation::windowed(n, window, 42.into());
+            let mut visited: Vec<u64> = (0..n).map(|i| permutation.nth(i)).collect();
+            assert!(visited.windows(2).all(|w| w[0].abs_diff(w[1]) < 2 * window));
+            visited.sort();
+            assert_eq!(visited, (0..n).collect::<Vec<u64>>());
+        }
+    }
+
+    #[test]
+    fn is_deterministic() {
+        let a = BlockPermutation::new(1 << 40, 7.into());
+        let b = BlockPermutation::new(1 << 40, 7.into());
+        assert_eq!(a.nth(12345), b.nth(12345));
+        assert_ne!(a.nth(1) - a.nth(0), 1);
+    }
+}
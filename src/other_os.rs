@@ -3,28 +3,39 @@ use std::{
     str::FromStr,
 };
 
-use crate::Args;
+use crate::{priority::IoPriority, Args};
 
 #[derive(Debug, Clone, Default)]
 pub(crate) struct DeviceMetadata {
     pub physical_block_size: Option<u64>,
+    pub serial_number: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct ValidDevice {
     pub path: PathBuf,
     pub partition: Option<u64>,
-    pub device: DeviceMetadata,
+    /// Metadata about the device under test, or `None` if the path is a regular file.
+    pub device: Option<DeviceMetadata>,
+    /// Character devices are only resolved to block devices on Linux.
+    pub char_device: Option<PathBuf>,
 }
 
 impl FromStr for ValidDevice {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let path = PathBuf::from(s);
+        let device = if path.is_file() {
+            None
+        } else {
+            Some(DeviceMetadata::default())
+        };
         Ok(Self {
-            path: PathBuf::from(s),
+            path,
             partition: None,
-            device: DeviceMetadata::default(),
+            device,
+            char_device: None,
         })
     }
 }
@@ -33,6 +44,7 @@ pub(crate) fn sanity_checks(
     args: &Args,
     _partition: Option<u64>,
     device_path: &Path,
+    _char_device: Option<&Path>,
     _device: &DeviceMetadata,
 ) -> anyhow::Result<()> {
     if args.i_know_what_im_doing_let_me_skip_sanity_checks {
@@ -41,3 +53,71 @@ pub(crate) fn sanity_checks(
         anyhow::bail!("I have no way to run sanity checks on this platform. Run with --i-know-what-im-doing-let-me-skip-sanity-checks if you want to destroy {:?} anyway.", device_path);
     }
 }
+
+/// NUMA binding is only supported on Linux, so this does nothing.
+pub(crate) fn bind_to_numa_node(_device: &DeviceMetadata) -> anyhow::Result<Option<()>> {
+    tracing::warn!("--numa is only supported on Linux, ignoring it");
+    Ok(None)
+}
+
+/// CPU affinity is only supported on Linux, so this does nothing.
+pub(crate) fn bind_to_cpus(_cpus: &[usize]) -> anyhow::Result<Option<()>> {
+    tracing::warn!("--cpu-affinity is only supported on Linux, ignoring it");
+    Ok(None)
+}
+
+pub(crate) fn preferred_io_size(_device: &DeviceMetadata) -> Option<(u64, &'static str)> {
+    None
+}
+
+/// The media type is only known on Linux, so no device counts as rotational.
+pub(crate) fn is_rotational(_device: &DeviceMetadata) -> bool {
+    false
+}
+
+/// USB bridges are only detected on Linux.
+pub(crate) fn usb_bridge(_device: &DeviceMetadata) -> Option<String> {
+    None
+}
+
+pub(crate) fn set_thread_priority(
+    io_priority: Option<IoPriority>,
+    nice: Option<i32>,
+) -> anyhow::Result<()> {
+    if io_priority.is_some() || nice.is_some() {
+        tracing::warn!("--io-priority and --nice are only supported on Linux, ignoring them");
+    }
+    Ok(())
+}
+
+/// Dropping the page cache is only supported on Linux, so this does nothing.
+pub(crate) fn drop_cache(_file: &std::fs::File, _offset: u64, _len: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+pub(crate) fn scsi_command(
+    _file: &std::fs::File,
+    _cdb: &[u8; 16],
+    _data: &mut [u8],
+) -> std::io::Result<Vec<u8>> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+pub(crate) fn rescan_capacity(_device: &DeviceMetadata) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Refuses to test the same device path twice in one invocation, even
+/// if it's spelled differently or through a symlink.
+pub(crate) fn check_overlaps(devices: &[ValidDevice]) -> anyhow::Result<()> {
+    let canonical = |path: &Path| std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    for (i, a) in devices.iter().enumerate() {
+        if devices[i + 1..]
+            .iter()
+            .any(|b| canonical(&a.path) == canonical(&b.path))
+        {
+            anyhow::bail!("{:?} was given more than once - testing it twice at once would corrupt both tests.", a.path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,256 @@
+//! Writing and verifying fixed stress patterns (--pattern-set).
+//!
+//! Besides the random-looking data of the main test, multi-pass runs can
+//! cycle through a few classic stress patterns, which exercise different
+//! failure modes of the media and the channel: stuck bits, and bits that
+//! flip when their neighbours hold the opposite value.
+//!
+//! Unlike the random data, the patterns are the same for every block, so
+//! they can't catch writes that land at the wrong offset. That's left to
+//! the last pass of the run, which always uses random data.
+
+use crate::{
+    device_error::{DeviceIoError, Operation},
+    events::ProgressEvents,
+    target::Target,
+    PROGRESS_STYLE,
+};
+use anyhow::Context;
+use serde::Serialize;
+use std::{fmt, fs::OpenOptions, path::Path};
+use tracing::{info_span, warn};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+/// A stress pattern, repeating every [PERIOD] bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Pattern {
+    /// 0x01, 0x02, 0x04, ... 0x80: a single set bit walking across bytes.
+    WalkingOnes,
+    /// 0xfe, 0xfd, 0xfb, ... 0x7f: a single clear bit walking across bytes.
+    WalkingZeros,
+    /// 0x55, 0xaa: every bit the opposite of its neighbours.
+    Checkerboard,
+    /// 0xaa, 0x55: the checkerboard with every bit flipped.
+    InverseCheckerboard,
+}
+
+/// The patterns that --pattern-set rotates through, in order.
+pub(crate) const PATTERN_SET: [Pattern; 4] = [
+    Pattern::WalkingOnes,
+    Pattern::WalkingZeros,
+    Pattern::Checkerboard,
+    Pattern::InverseCheckerboard,
+];
+
+/// Every pattern repeats after this many bytes.
+const PERIOD: usize = 8;
+
+impl Pattern {
+    /// The byte of the pattern at `offset`.
+    fn byte(self, offset: u64) -> u8 {
+        let walking = 1 << (offset % PERIOD as u64);
+        let checker = if offset.is_multiple_of(2) { 0x55 } else { 0xaa };
+        match self {
+            Pattern::WalkingOnes => walking,
+            Pattern::WalkingZeros => !walking,
+            Pattern::Checkerboard => checker,
+            Pattern::InverseCheckerboard => !checker,
+        }
+    }
+
+    /// Fills `buf` with the bytes of the pattern from `offset`.
+    pub(crate) fn fill(self, offset: u64, buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.byte(offset + i as u64);
+        }
+    }
+
+    /// A buffer of the pattern from offset 0, long enough that the bytes
+    /// of any block of up to `block_size` bytes are a slice of it.
+    fn template(self, block_size: usize) -> Vec<u8> {
+        (0..(block_size + PERIOD) as u64)
+            .map(|offset| self.byte(offset))
+            .collect()
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::WalkingOnes => write!(f, "walking-ones"),
+            Pattern::WalkingZeros => write!(f, "walking-zeros"),
+            Pattern::Checkerboard => write!(f, "checkerboard"),
+            Pattern::InverseCheckerboard => write!(f, "inverse-checkerboard"),
+        }
+    }
+}
+
+/// The bytes of the pattern in the block of `len` bytes at `offset`.
+fn block(template: &[u8], offset: u64, len: usize) -> &[u8] {
+    &template[(offset % PERIOD as u64) as usize..][..len]
+}
+
+/// Fills the first `capacity` bytes of the device (or all of it) with
+/// the pattern, and syncs it. Returns the number of bytes written.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity), fields(device = ?dev_path))]
+pub(crate) fn write_pattern(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    pattern: Pattern,
+) -> anyhow::Result<u64> {
+    let blockdev = OpenOptions::new()
+        .write(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for writing", dev_path))?;
+    write_pattern_to(&blockdev, buffer_size, capacity, pattern)
+}
+
+/// Like [write_pattern], but to any [Target].
+pub(crate) fn write_pattern_to(
+    target: &dyn Target,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    pattern: Pattern,
+) -> anyhow::Result<u64> {
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => target.len()?,
+    };
+
+    let bar_span = info_span!("writing pattern", %pattern);
+    bar_span.pb_set_style(&PROGRESS_STYLE);
+    bar_span.pb_set_length(capacity);
+    let _bar_span_handle = bar_span.enter();
+
+    let events = ProgressEvents::new("write", capacity, 0);
+    let template = pattern.template(buffer_size);
+    let mut offset = 0;
+    while offset < capacity {
+        let len = (capacity - offset).min(buffer_size as u64) as usize;
+        let written = target
+            .write_at(block(&template, offset, len), offset)
+            .map_err(|e| DeviceIoError::new(Operation::Write, offset, e))?;
+        if written == 0 {
+            anyhow::bail!(
+                "The device ended after {} bytes, before the capacity of {} bytes could be written",
+                offset,
+                capacity
+            );
+        }
+        offset += written as u64;
+        events.inc(written as u64);
+    }
+    target.sync().context("Syncing the device")?;
+    Ok(offset)
+}
+
+/// Reads back the first `capacity` bytes of the device (or all of it),
+/// comparing them against the pattern. Returns the number of bytes read,
+/// and the offsets of the blocks that didn't match.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity), fields(device = ?dev_path))]
+pub(crate) fn verify_pattern(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    pattern: Pattern,
+) -> anyhow::Result<(u64, Vec<u64>)> {
+    let blockdev = OpenOptions::new()
+        .read(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for reading", dev_path))?;
+    verify_pattern_in(&blockdev, buffer_size, capacity, pattern)
+}
+
+/// Like [verify_pattern], but on any [Target].
+pub(crate) fn verify_pattern_in(
+    target: &dyn Target,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    pattern: Pattern,
+) -> anyhow::Result<(u64, Vec<u64>)> {
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => target.len()?,
+    };
+
+    let bar_span = info_span!("verifying pattern", %pattern);
+    bar_span.pb_set_style(&PROGRESS_STYLE);
+    bar_span.pb_set_length(capacity);
+    let _bar_span_handle = bar_span.enter();
+
+    let events = ProgressEvents::new("read", capacity, 0);
+    let template = pattern.template(buffer_size);
+    let mut buf = vec![0; buffer_size];
+    let mut bad_offsets = Vec::new();
+    let mut offset = 0;
+    while offset < capacity {
+        let len = (capacity - offset).min(buffer_size as u64) as usize;
+        let read = target
+            .read_at(&mut buf[..len], offset)
+            .map_err(|e| DeviceIoError::new(Operation::Read, offset, e))?;
+        if read == 0 {
+            anyhow::bail!(
+                "The device ended after {} bytes, before the capacity of {} bytes could be verified",
+                offset,
+                capacity
+            );
+        }
+        if buf[..read] != *block(&template, offset, read) {
+            warn!(
+                event = "bad_block",
+                offset,
+                %pattern,
+                "Did not read back the exact pattern written"
+            );
+            bad_offsets.push(offset);
+        }
+        offset += read as u64;
+        events.inc(read as u64);
+    }
+    Ok((offset, bad_offsets))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::target::MemoryTarget;
+    use tracing_test::traced_test;
+
+    #[test]
+    fn generates_patterns() {
+        let bytes = |pattern: Pattern| pattern.template(8)[..10].to_vec();
+        assert_eq!(
+            bytes(Pattern::WalkingOnes),
+            [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x01, 0x02]
+        );
+        assert_eq!(bytes(Pattern::WalkingZeros)[7], 0x7f);
+        assert_eq!(bytes(Pattern::Checkerboard)[..3], [0x55, 0xaa, 0x55]);
+        assert_eq!(bytes(Pattern::InverseCheckerboard)[..3], [0xaa, 0x55, 0xaa]);
+        assert_eq!(Pattern::WalkingOnes.to_string(), "walking-ones");
+    }
+
+    #[traced_test]
+    #[test]
+    fn verifies_patterns() {
+        // A buffer size that isn't a multiple of the period:
+        let target = MemoryTarget::new(1000 * 10);
+        for pattern in PATTERN_SET {
+            let written = write_pattern_to(&target, 1000, None, pattern).expect("No io errors");
+            assert_eq!(written, 1000 * 10);
+            let (read, bad) =
+                verify_pattern_in(&target, 1000, None, pattern).expect("No io errors");
+            assert_eq!(read, 1000 * 10);
+            assert!(bad.is_empty(), "{}", pattern);
+        }
+        target.with_data(|data| data[3500] ^= 0x10);
+        let (_, bad) = verify_pattern_in(&target, 1000, None, Pattern::InverseCheckerboard)
+            .expect("No io errors");
+        assert_eq!(bad, vec![3000]);
+        let (_, bad) =
+            verify_pattern_in(&target, 1000, None, Pattern::Checkerboard).expect("No io errors");
+        assert_eq!(bad.len(), 10);
+        assert!(logs_contain("exact pattern written"));
+    }
+}
@@ -0,0 +1,133 @@
+//! Deciding on a device's verdict from what its test measured.
+//!
+//! The default policy is strict: a device is `Good` only if every block
+//! read back exactly as written, and a single bad block makes it `Bad`.
+//!
+//! With `--fail-threshold N`, a device needs at least N bad blocks to be
+//! `Bad`. A device with some bad blocks, but fewer than N, is `Uncertain`:
+//! it's reported, but doesn't fail the run. The default threshold is 1,
+//! so there is no `Uncertain` band unless you ask for one.
+//!
+//! A device whose read test was stopped at `--max-bad-blocks` (or at
+//! `--abort-on-first-bad`) is `Bad` regardless of the threshold.
+//!
+//! With `--min-throughput`, a device that read everything back correctly,
+//! but whose write or read phase was slower on average than the minimum,
+//! is `Slow` instead of `Good`, and fails the run like a bad one.
+
+use crate::{read_test::FailedReads, Args, Outcome, UncertainReason};
+
+/// What one pass of the test measured on a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Metrics {
+    /// The data was read back, and this many blocks didn't match.
+    ///
+    /// If the read was `aborted` at --max-bad-blocks, there may be more.
+    /// The `throughput` is that of the slowest sustained phase, in bytes
+    /// per second, if it was measured.
+    Verified {
+        bad_blocks: FailedReads,
+        aborted: bool,
+        throughput: Option<u64>,
+    },
+    /// The data was never read back (with --no-read-back).
+    Unverified,
+}
+
+/// The rules for turning [Metrics] into an [Outcome].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Policy {
+    /// The number of bad blocks at which a device is `Bad`.
+    pub fail_threshold: FailedReads,
+    /// The slowest sustained throughput of a `Good` device, in bytes per
+    /// second.
+    pub min_throughput: Option<u64>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            fail_threshold: 1,
+            min_throughput: None,
+        }
+    }
+}
+
+impl Policy {
+    pub(crate) fn from_args(args: &Args) -> Self {
+        Self {
+            fail_threshold: args.fail_threshold,
+            min_throughput: args.min_throughput,
+        }
+    }
+
+    /// Deterministically maps a device's metrics to its verdict.
+    pub(crate) fn decide(&self, metrics: Metrics) -> Outcome {
+        match metrics {
+            Metrics::Unverified => Outcome::Unverified,
+            Metrics::Verified {
+                bad_blocks: 0,
+                throughput: Some(throughput),
+                ..
+            } if self.min_throughput.is_some_and(|min| throughput < min) => Outcome::Slow,
+            Metrics::Verified { bad_blocks: 0, .. } => Outcome::Good,
+            Metrics::Verified {
+                bad_blocks,
+                aborted,
+                ..
+            } if aborted || bad_blocks >= self.fail_threshold => Outcome::Bad(bad_blocks),
+            Metrics::Verified { bad_blocks, .. } => {
+                Outcome::Uncertain(bad_blocks, UncertainReason::BelowThreshold)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decides() {
+        let verified = |bad_blocks| Metrics::Verified {
+            bad_blocks,
+            aborted: false,
+            throughput: None,
+        };
+        let strict = Policy::default();
+        assert_eq!(strict.decide(verified(0)), Outcome::Good);
+        assert_eq!(strict.decide(verified(1)), Outcome::Bad(1));
+        assert_eq!(strict.decide(Metrics::Unverified), Outcome::Unverified);
+
+        let lenient = Policy {
+            fail_threshold: 10,
+            ..Policy::default()
+        };
+        assert_eq!(lenient.decide(verified(0)), Outcome::Good);
+        assert_eq!(
+            lenient.decide(verified(9)),
+            Outcome::Uncertain(9, UncertainReason::BelowThreshold)
+        );
+        assert_eq!(lenient.decide(verified(10)), Outcome::Bad(10));
+        let aborted = Metrics::Verified {
+            bad_blocks: 5,
+            aborted: true,
+            throughput: None,
+        };
+        assert_eq!(lenient.decide(aborted), Outcome::Bad(5));
+
+        let picky = Policy {
+            min_throughput: Some(100),
+            ..Policy::default()
+        };
+        let measured = |bad_blocks, throughput| Metrics::Verified {
+            bad_blocks,
+            aborted: false,
+            throughput: Some(throughput),
+        };
+        assert_eq!(picky.decide(measured(0, 100)), Outcome::Good);
+        assert_eq!(picky.decide(measured(0, 99)), Outcome::Slow);
+        assert_eq!(picky.decide(measured(2, 99)), Outcome::Bad(2));
+        assert_eq!(picky.decide(verified(0)), Outcome::Good);
+    }
+}
@@ -0,0 +1,209 @@
+//! Imaging a device before the test, and restoring it afterwards (--preserve).
+//!
+//! The image is a plain copy of the device, written next to a manifest of
+//! its checksums. After the test, the image is written back and the
+//! device is verified against the manifest. The image is only removed
+//! once that verification succeeded.
+
+use crate::{
+    device_error::{DeviceIoError, Operation},
+    events::ProgressEvents,
+    manifest::{self, Manifest, RegionHasher},
+    PROGRESS_STYLE,
+};
+use anyhow::Context;
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+use tracing::{info, info_span};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+/// The path of the manifest that goes with an image.
+pub(crate) fn manifest_path(image_path: &Path) -> PathBuf {
+    image_path.with_extension("img.manifest")
+}
+
+/// Copies the first `capacity` bytes of the device (or all of it) to a
+/// new image file, returning the manifest of its contents.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity), fields(device = ?dev_path))]
+pub(crate) fn image(
+    dev_path: &Path,
+    image_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+) -> anyhow::Result<Manifest> {
+    let mut blockdev = OpenOptions::new()
+        .read(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for imaging", dev_path))?;
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => blockdev.seek(io::SeekFrom::End(0))?,
+    };
+    blockdev.seek(io::SeekFrom::Start(0))?;
+    let image_dir = image_path.parent().unwrap_or(Path::new("."));
+    check_free_space(image_dir, capacity)?;
+    let file = File::create_new(image_path).with_context(|| {
+        format!(
+            "Creating the image {:?} (if it is left over from an earlier run, restore or delete it first)",
+            image_path
+        )
+    })?;
+
+    let bar_span = info_span!("imaging");
+    bar_span.pb_set_style(&PROGRESS_STYLE);
+    bar_span.pb_set_length(capacity);
+    let _bar_span_handle = bar_span.enter();
+
+    let events = ProgressEvents::new("image", capacity, 0);
+    let mut hasher = RegionHasher::new(
+        BufWriter::with_capacity(buffer_size, &file),
+        manifest::REGION_SIZE,
+    );
+    let mut buf = vec![0; buffer_size];
+    let mut offset = 0;
+    let mut blockdev = blockdev.take(capacity);
+    loop {
+        let read = blockdev
+            .read(&mut buf)
+            .map_err(|e| DeviceIoError::new(Operation::Read, offset, e))?;
+        if read == 0 {
+            break;
+        }
+        hasher
+            .write_all(&buf[..read])
+            .with_context(|| format!("Writing the image {:?}", image_path))?;
+        offset += read as u64;
+        events.inc(read as u64);
+    }
+    if offset < capacity {
+        anyhow::bail!(
+            "The device ended after {} bytes, before {} bytes could be imaged",
+            offset,
+            capacity
+        );
+    }
+    // Dropping the BufWriter would swallow an error from its last write:
+    let (manifest, mut out) = hasher.finish();
+    out.flush()
+        .with_context(|| format!("Writing the image {:?}", image_path))?;
+    file.sync_all()
+        .with_context(|| format!("Syncing the image {:?}", image_path))?;
+    manifest.save(&manifest_path(image_path))?;
+    info!(image = ?image_path, bytes = offset, "imaged the device");
+    Ok(manifest)
+}
+
+/// Writes an image back to the device, and checks that the device then
+/// matches its manifest. The image is removed if it does.
+#[tracing::instrument(skip(dev_path, buffer_size, manifest), fields(device = ?dev_path))]
+pub(crate) fn restore(
+    dev_path: &Path,
+    image_path: &Path,
+    buffer_size: usize,
+    manifest: &Manifest,
+) -> anyhow::Result<()> {
+    let mut image =
+        File::open(image_path).with_context(|| format!("Opening the image {:?}", image_path))?;
+    let mut out = OpenOptions::new()
+        .write(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for restoring", dev_path))?;
+
+    let capacity = manifest.length();
+    let bar_span = info_span!("restoring");
+    bar_span.pb_set_style(&PROGRESS_STYLE);
+    bar_span.pb_set_length(capacity);
+    let bar_span_handle = bar_span.enter();
+
+    let events = ProgressEvents::new("restore", capacity, 0);
+    let mut buf = vec![0; buffer_size];
+    let mut offset = 0;
+    loop {
+        let read = image
+            .read(&mut buf)
+            .with_context(|| format!("Reading the image {:?}", image_path))?;
+        if read == 0 {
+            break;
+        }
+        out.write_all(&buf[..read])
+            .map_err(|e| DeviceIoError::new(Operation::Write, offset, e))?;
+        offset += read as u64;
+        events.inc(read as u64);
+    }
+    out.sync_all()
+        .map_err(|e| DeviceIoError::new(Operation::Write, offset, e))?;
+    drop(bar_span_handle);
+
+    match manifest::verify(dev_path, buffer_size, manifest).context("Verifying the restore")? {
+        Ok(()) => {}
+        Err(n) => anyhow::bail!(
+            "After restoring, {} regions of {:?} don't match the image. The image is kept at {:?}.",
+            n,
+            dev_path,
+            image_path
+        ),
+    }
+    fs::remove_file(image_path)?;
+    fs::remove_file(manifest_path(image_path))?;
+    info!(image = ?image_path, "restored the device from its image");
+    Ok(())
+}
+
+/// Fails if the filesystem holding `dir` has less than `needed` bytes free.
+fn check_free_space(dir: &Path, needed: u64) -> anyhow::Result<()> {
+    let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+    // SAFETY: statvfs only writes to the struct it's given.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_dir.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Checking the free space in {:?}", dir));
+    }
+    let available = stat.f_bavail as u64 * stat.f_frsize as u64;
+    if available < needed {
+        anyhow::bail!(
+            "{:?} only has {} bytes free, but the image needs {} bytes.",
+            dir,
+            available,
+            needed
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::sparse_file;
+    use tracing_test::traced_test;
+
+    #[traced_test]
+    #[test]
+    fn images_and_restores() {
+        let path = sparse_file("preserve", 0);
+        let contents: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        let image_path = path.with_extension("img");
+
+        let manifest = image(&path, &image_path, 4096, None).expect("No io errors");
+        assert_eq!(manifest.length(), contents.len() as u64);
+        assert!(image(&path, &image_path, 4096, None).is_err());
+
+        fs::write(&path, vec![0xff; contents.len()]).unwrap();
+        restore(&path, &image_path, 4096, &manifest).expect("No io errors");
+        assert_eq!(fs::read(&path).unwrap(), contents);
+        assert!(!image_path.exists());
+        assert!(!manifest_path(&image_path).exists());
+    }
+
+    #[test]
+    fn checks_free_space() {
+        let dir = std::env::temp_dir();
+        check_free_space(&dir, 1).unwrap();
+        assert!(check_free_space(&dir, u64::MAX).is_err());
+    }
+}
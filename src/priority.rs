@@ -0,0 +1,72 @@
+//! Running the test at a lower priority, on hosts with other work to do.
+//!
+//! The I/O priority doesn't carry over to buffered writes, which the
+//! kernel writes back on its own, so it only lowers the priority of the
+//! reads.
+
+use std::str::FromStr;
+
+/// An I/O scheduling class and level, like `ionice` takes.
+///
+/// Within a class, levels go from 0 (highest) to 7 (lowest).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IoPriority {
+    /// Only gets disk time when no one else wants it.
+    Idle,
+    BestEffort(u8),
+    /// Always first in line for the disk. Needs root.
+    Realtime(u8),
+}
+
+impl IoPriority {
+    /// The level that `ionice` uses when none is given.
+    const DEFAULT_LEVEL: u8 = 4;
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+
+    /// Parses `idle`, `best-effort[:LEVEL]` or `realtime[:LEVEL]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => {
+                let level: u8 = level
+                    .parse()
+                    .map_err(|e| format!("invalid level {:?}: {}", level, e))?;
+                if level > 7 {
+                    return Err(format!("level {} is not between 0 and 7", level));
+                }
+                (class, Some(level))
+            }
+            None => (s, None),
+        };
+        match (class, level) {
+            ("idle", None) => Ok(IoPriority::Idle),
+            ("idle", Some(_)) => Err("the idle class has no levels".to_string()),
+            ("best-effort", level) => {
+                Ok(IoPriority::BestEffort(level.unwrap_or(Self::DEFAULT_LEVEL)))
+            }
+            ("realtime", level) => Ok(IoPriority::Realtime(level.unwrap_or(Self::DEFAULT_LEVEL))),
+            _ => Err(format!(
+                "unknown class {:?}, expected idle, best-effort or realtime",
+                class
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::IoPriority;
+
+    #[test]
+    fn parses_io_priorities() {
+        assert_eq!("idle".parse(), Ok(IoPriority::Idle));
+        assert_eq!("best-effort".parse(), Ok(IoPriority::BestEffort(4)));
+        assert_eq!("best-effort:7".parse(), Ok(IoPriority::BestEffort(7)));
+        assert_eq!("realtime:0".parse(), Ok(IoPriority::Realtime(0)));
+        assert!("best-effort:8".parse::<IoPriority>().is_err());
+        assert!("idle:1".parse::<IoPriority>().is_err());
+        assert!("low".parse::<IoPriority>().is_err());
+    }
+}
@@ -1,78 +1,601 @@
 //! Running the "read back" portion of the test.
 
-use crate::{crypto::GarbageGenerator, PROGRESS_STYLE};
+use crate::{
+    block_log,
+    crypto::{GarbageGenerator, Seed},
+    deadline,
+    device_error::{DeviceIoError, Operation},
+    events::ProgressEvents,
+    idle,
+    manifest::{self, Manifest, RegionHasher},
+    target::{Cursor, Target},
+    PROGRESS_STYLE,
+};
 use anyhow::Context;
+use serde::Serialize;
 use std::{
-    fs::OpenOptions,
-    io::{self, BufReader, Seek},
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Seek},
     path::Path,
+    time::{Duration, Instant},
 };
-use tracing::{info_span, warn, Span};
+use tracing::{debug, info, info_span, warn};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
-type FailedReads = usize;
+pub(crate) type FailedReads = usize;
+
+/// The blocks that did not read back as they were written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BadBlocks {
+    pub count: FailedReads,
+    /// The offsets at which the bad blocks start, in order.
+    pub offsets: Vec<u64>,
+    /// The read was stopped early at `max_bad_blocks`, so `count` is a lower bound.
+    pub aborted: bool,
+}
 
-#[tracing::instrument(skip(buffer_size, seed))]
+/// Reads back the device until its end (or until `capacity` bytes, if
+/// given), comparing it against the garbage that the write test put there.
+///
+/// If a `manifest` is passed, it is filled with the checksums of the
+/// data that was read. If `max_bad_blocks` is given, reading stops once
+/// that many bad blocks have been found. If `transient` is passed (with
+/// --verify-twice), the bad blocks are read again, and those that read
+/// back correctly the second time are moved to it instead of counting as
+/// bad. Returns the number of bytes read, along with the bad blocks if
+/// there were any.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity, seed, manifest, max_bad_blocks, transient), fields(device = ?dev_path))]
 pub(crate) fn read_back(
     dev_path: &Path,
     buffer_size: usize,
-    seed: u64,
-) -> anyhow::Result<Result<(), FailedReads>> {
-    let mut blockdev = OpenOptions::new()
+    capacity: Option<u64>,
+    seed: Seed,
+    manifest: Option<&mut Manifest>,
+    max_bad_blocks: Option<FailedReads>,
+    transient: Option<&mut Vec<u64>>,
+) -> anyhow::Result<(u64, Result<(), BadBlocks>)> {
+    let blockdev = OpenOptions::new()
         .read(true)
         .open(dev_path)
         .with_context(|| format!("Opening the device {:?} for reading", dev_path))?;
-    let capacity = blockdev.seek(io::SeekFrom::End(0))?;
-    blockdev.seek(io::SeekFrom::Start(0))?;
+    read_back_from(
+        &blockdev,
+        buffer_size,
+        capacity,
+        seed,
+        manifest,
+        max_bad_blocks,
+        transient,
+    )
+}
+
+/// Like [read_back], but from any [Target].
+pub(crate) fn read_back_from(
+    target: &dyn Target,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+    manifest: Option<&mut Manifest>,
+    max_bad_blocks: Option<FailedReads>,
+    transient: Option<&mut Vec<u64>>,
+) -> anyhow::Result<(u64, Result<(), BadBlocks>)> {
+    // Without an explicit capacity, keep going until the device runs out:
+    let limit = capacity.unwrap_or(u64::MAX);
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => target.len()?,
+    };
+    let mut blockdev = Cursor::new(target, 0).take(limit);
 
     let bar_span = info_span!("reading back");
     bar_span.pb_set_style(&PROGRESS_STYLE);
     bar_span.pb_set_length(capacity);
     let _bar_span_handle = bar_span.enter();
 
+    let events = ProgressEvents::new("read", capacity, 0);
+    let _idle_watch = idle::Watch::start("read");
     let generator = GarbageGenerator::new(buffer_size, seed, |read| {
-        Span::current().pb_inc(read);
+        events.inc(read);
     });
-    let generator = BufReader::new(generator);
+    let generator = BufReader::with_capacity(buffer_size, generator);
     let mut compare = CompareWriter::new(generator);
-    io::copy(&mut blockdev, &mut compare)?;
-    if compare.mismatched > 0 {
-        return Ok(Err(compare.mismatched));
+    compare.max_bad_blocks = max_bad_blocks;
+    compare.block_size = Some(buffer_size);
+    let copied = match manifest {
+        Some(manifest) => {
+            let mut hasher = RegionHasher::new(&mut compare, manifest::REGION_SIZE);
+            let copied = io::copy(&mut blockdev, &mut hasher);
+            (*manifest, _) = hasher.finish();
+            copied
+        }
+        None => io::copy(&mut blockdev, &mut compare),
+    };
+    let copied = match copied {
+        Ok(copied) => copied,
+        Err(_) if compare.aborted => {
+            warn!(
+                offset = compare.current_offset,
+                bad_blocks = compare.mismatched,
+                "Found --max-bad-blocks bad blocks, stopping the read test early. There may be more."
+            );
+            compare.current_offset as u64
+        }
+        // Errors from generating the comparison data don't come from the OS:
+        Err(e) if e.raw_os_error().is_some() || deadline::exceeded() => {
+            let offset = compare.current_offset as u64;
+            block_log::record(
+                "read",
+                offset,
+                buffer_size as u64,
+                "error",
+                compare.block_latency + compare.last_write.elapsed(),
+                0,
+            );
+            return Err(DeviceIoError::new(Operation::Read, offset, e).into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if block_log::enabled() {
+        let last_block = copied / buffer_size as u64 * buffer_size as u64;
+        compare.log_block(last_block, true);
+    }
+    if !compare.aborted && limit != u64::MAX && copied < limit {
+        anyhow::bail!(
+            "The device ended after {} bytes, before the capacity of {} bytes could be verified",
+            copied,
+            limit
+        );
     }
-    Ok(Ok(()))
+    if let Some(transient) = transient {
+        reread_bad_blocks(target, buffer_size, copied, seed, &mut compare, transient)?;
+    }
+    Ok((copied, compare.into_result()))
+}
+
+/// Like [read_back], but reads the blocks from the end of the device back
+/// to its start (--reverse-read), which exercises the device's caching and
+/// seeking differently.
+///
+/// The data of each block only depends on its offset, so it verifies
+/// the same as a forward read. There is no manifest to fill, as the
+/// checksums are computed in order.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity, seed, max_bad_blocks, transient), fields(device = ?dev_path))]
+pub(crate) fn read_back_reversed(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+    max_bad_blocks: Option<FailedReads>,
+    transient: Option<&mut Vec<u64>>,
+) -> anyhow::Result<(u64, Result<(), BadBlocks>)> {
+    let blockdev = OpenOptions::new()
+        .read(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for reading", dev_path))?;
+    read_back_reversed_from(
+        &blockdev,
+        buffer_size,
+        capacity,
+        seed,
+        max_bad_blocks,
+        transient,
+    )
+}
+
+/// Like [read_back_reversed], but from any [Target].
+pub(crate) fn read_back_reversed_from(
+    target: &dyn Target,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+    max_bad_blocks: Option<FailedReads>,
+    transient: Option<&mut Vec<u64>>,
+) -> anyhow::Result<(u64, Result<(), BadBlocks>)> {
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => target.len()?,
+    };
+
+    let bar_span = info_span!("reading back in reverse");
+    bar_span.pb_set_style(&PROGRESS_STYLE);
+    bar_span.pb_set_length(capacity);
+    let _bar_span_handle = bar_span.enter();
+
+    let events = ProgressEvents::new("read", capacity, 0);
+    let _idle_watch = idle::Watch::start("read");
+    let mut generator = GarbageGenerator::new(buffer_size, seed, |_| {});
+    let (mut expected, mut actual) = (vec![0; buffer_size], vec![0; buffer_size]);
+    // The bad blocks are collected as by a forward read, so that they can
+    // be read again and reported the same way:
+    let mut compare = CompareWriter::new(io::empty());
+    let mut read = 0;
+    for block in (0..capacity.div_ceil(buffer_size as u64)).rev() {
+        let offset = block * buffer_size as u64;
+        let len = (capacity - offset).min(buffer_size as u64) as usize;
+        let started = Instant::now();
+        match Cursor::new(target, offset).read_exact(&mut actual[..len]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => anyhow::bail!(
+                "The device ended before offset {}, so the capacity of {} bytes could not be verified",
+                offset + len as u64,
+                capacity
+            ),
+            Err(e) => {
+                block_log::record("read", offset, len as u64, "error", started.elapsed(), 0);
+                return Err(DeviceIoError::new(Operation::Read, offset, e).into());
+            }
+        }
+        let latency = started.elapsed();
+        generator.seek(offset);
+        generator.fill(&mut expected[..len]);
+        let bad = expected[..len] != actual[..len];
+        let result = if bad { "bad" } else { "ok" };
+        block_log::record("read", offset, len as u64, result, latency, 0);
+        read += len as u64;
+        events.inc(len as u64);
+        if bad {
+            warn!(
+                event = "bad_block",
+                offset, "Did not read back the exact bytes written"
+            );
+            compare.mismatched += 1;
+            compare.bad_offsets.push(offset);
+            if max_bad_blocks.is_some_and(|max| compare.mismatched >= max) {
+                compare.aborted = true;
+                warn!(
+                    offset,
+                    bad_blocks = compare.mismatched,
+                    "Found --max-bad-blocks bad blocks, stopping the read test early. There may be more."
+                );
+                break;
+            }
+        }
+    }
+    compare.bad_offsets.reverse();
+    if let Some(transient) = transient {
+        reread_bad_blocks(target, buffer_size, capacity, seed, &mut compare, transient)?;
+    }
+    Ok((read, compare.into_result()))
+}
+
+/// How long to wait before reading the bad blocks again with --verify-twice.
+const REREAD_DELAY: Duration = Duration::from_secs(1);
+
+/// Reads the bad blocks found by `compare` again, after [REREAD_DELAY]
+/// and bypassing the cache, and moves those that read back correctly to
+/// `transient`. Only the blocks that fail twice stay bad.
+fn reread_bad_blocks<R: io::Read>(
+    target: &dyn Target,
+    buffer_size: usize,
+    read: u64,
+    seed: Seed,
+    compare: &mut CompareWriter<R>,
+    transient: &mut Vec<u64>,
+) -> anyhow::Result<()> {
+    if compare.bad_offsets.is_empty() {
+        return Ok(());
+    }
+    std::thread::sleep(REREAD_DELAY);
+    let mut generator = GarbageGenerator::new(buffer_size, seed, |_| {});
+    let (mut expected, mut actual) = (vec![0; buffer_size], vec![0; buffer_size]);
+    let mut persistent = Vec::new();
+    for offset in std::mem::take(&mut compare.bad_offsets) {
+        let len = (read - offset).min(buffer_size as u64) as usize;
+        if let Err(e) = target.drop_cache(offset, len as u64) {
+            debug!(offset, error = %e, "Could not drop the cache before reading again");
+        }
+        let started = Instant::now();
+        if let Err(e) = Cursor::new(target, offset).read_exact(&mut actual[..len]) {
+            block_log::record("read", offset, len as u64, "error", started.elapsed(), 1);
+            return Err(DeviceIoError::new(Operation::Read, offset, e).into());
+        }
+        let latency = started.elapsed();
+        generator.seek(offset);
+        generator.fill(&mut expected[..len]);
+        let reread_ok = expected[..len] == actual[..len];
+        let result = if reread_ok { "ok" } else { "bad" };
+        block_log::record("read", offset, len as u64, result, latency, 1);
+        if reread_ok {
+            warn!(
+                event = "transient_bad_block",
+                offset, "Read back correctly the second time, not counting it as bad"
+            );
+            transient.push(offset);
+        } else {
+            persistent.push(offset);
+        }
+    }
+    info!(
+        persistent = persistent.len(),
+        transient = transient.len(),
+        "Read the bad blocks again (--verify-twice)"
+    );
+    compare.mismatched = persistent.len();
+    compare.bad_offsets = persistent;
+    Ok(())
+}
+
+/// Opens the device for reading a few blocks of it, and finds out its
+/// capacity if none is given.
+fn open_for_sampling(
+    dev_path: &Path,
+    capacity: Option<u64>,
+    purpose: &str,
+) -> anyhow::Result<(File, u64)> {
+    let blockdev = OpenOptions::new()
+        .read(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for {}", dev_path, purpose))?;
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => blockdev.len()?,
+    };
+    Ok((blockdev, capacity))
+}
+
+/// Spins up the device before the test, by reading single buffers from
+/// across the whole device for `duration`.
+///
+/// Seeking between far-apart offsets keeps the heads moving, so the
+/// drive is fully spun up and settled by the time the timed test starts.
+pub(crate) fn warm_up(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    duration: Duration,
+) -> anyhow::Result<()> {
+    let (mut blockdev, capacity) = open_for_sampling(dev_path, capacity, "warming up")?;
+    let buffer_size_u64 = buffer_size as u64;
+    let blocks = (capacity / buffer_size_u64).max(1);
+    let mut buf = vec![0; buffer_size];
+    let deadline = Instant::now() + duration;
+    let mut reads = 0u64;
+    while Instant::now() < deadline {
+        // Alternate between the first and second half of the device:
+        let block = (reads / 2 * 7919 + (reads % 2) * (blocks / 2)) % blocks;
+        let offset = block * buffer_size_u64;
+        blockdev.seek(io::SeekFrom::Start(offset))?;
+        blockdev
+            .read(&mut buf)
+            .map_err(|e| DeviceIoError::new(Operation::Read, offset, e))?;
+        reads += 1;
+    }
+    debug!(reads, "warm-up finished");
+    Ok(())
+}
+
+/// What a device held before the test wrote to it, from a few samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum InitialState {
+    /// Every sample was zeroes: a new or trimmed device, or one that is
+    /// thinly provisioned and doesn't store unwritten blocks.
+    Zeroes,
+    /// Every sample was 0xff bytes, as erased flash often reads.
+    Erased,
+    /// Every sample held data: the device was used before.
+    Data,
+    /// Some samples were blank and some held data.
+    Mixed,
+    /// The device is smaller than a single buffer, so nothing was sampled.
+    Unsampled,
+}
+
+/// How many blocks [probe_initial_state] samples.
+const INITIAL_STATE_SAMPLES: u64 = 16;
+
+/// Reads a few blocks spread evenly across the device, to find out
+/// whether it holds anything yet.
+pub(crate) fn probe_initial_state(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+) -> anyhow::Result<InitialState> {
+    let (mut blockdev, capacity) = open_for_sampling(dev_path, capacity, "probing")?;
+    let buffer_size_u64 = buffer_size as u64;
+    let blocks = capacity / buffer_size_u64;
+    let mut buf = vec![0; buffer_size];
+    let (mut zeroes, mut erased, mut data) = (0, 0, 0);
+    for i in 0..INITIAL_STATE_SAMPLES.min(blocks) {
+        let offset = i * blocks / INITIAL_STATE_SAMPLES.min(blocks) * buffer_size_u64;
+        blockdev.seek(io::SeekFrom::Start(offset))?;
+        blockdev
+            .read_exact(&mut buf)
+            .map_err(|e| DeviceIoError::new(Operation::Read, offset, e))?;
+        if buf.iter().all(|&b| b == 0) {
+            zeroes += 1;
+        } else if buf.iter().all(|&b| b == 0xff) {
+            erased += 1;
+        } else {
+            data += 1;
+        }
+    }
+    Ok(match (zeroes, erased, data) {
+        (0, 0, 0) => InitialState::Unsampled,
+        (_, 0, 0) => InitialState::Zeroes,
+        (0, _, 0) => InitialState::Erased,
+        (0, 0, _) => InitialState::Data,
+        _ => InitialState::Mixed,
+    })
+}
+
+/// How many blocks [matches_stream] samples.
+const STREAM_SAMPLES: u64 = 8;
+
+/// Whether the device mostly holds the garbage generated from `seed`,
+/// judging by a few blocks spread across it.
+///
+/// This is for finding out whose data a device holds after it failed
+/// verification, so a few bad blocks among the samples are tolerated.
+pub(crate) fn matches_stream(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+) -> anyhow::Result<bool> {
+    let (read, bad) = verify_samples(dev_path, buffer_size, capacity, seed, STREAM_SAMPLES)?;
+    let samples = read / buffer_size as u64;
+    Ok((samples - bad.len() as u64) * 2 > samples)
+}
+
+/// Compares `samples` blocks spread evenly across the device against the
+/// garbage generated from `seed`.
+///
+/// Returns the number of bytes read, and the offsets of the blocks that
+/// didn't match.
+pub(crate) fn verify_samples(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+    samples: u64,
+) -> anyhow::Result<(u64, Vec<u64>)> {
+    let (mut blockdev, capacity) = open_for_sampling(dev_path, capacity, "reading")?;
+    let buffer_size_u64 = buffer_size as u64;
+    let blocks = capacity / buffer_size_u64;
+    let samples = samples.min(blocks);
+    let mut generator = GarbageGenerator::new(buffer_size, seed, |_| {});
+    let (mut expected, mut actual) = (vec![0; buffer_size], vec![0; buffer_size]);
+    let mut bad_offsets = Vec::new();
+    for i in 0..samples {
+        let offset = i * blocks / samples * buffer_size_u64;
+        blockdev.seek(io::SeekFrom::Start(offset))?;
+        blockdev
+            .read_exact(&mut actual)
+            .map_err(|e| DeviceIoError::new(Operation::Read, offset, e))?;
+        generator.seek(offset);
+        generator.fill(&mut expected);
+        if expected != actual {
+            bad_offsets.push(offset);
+        }
+    }
+    Ok((samples * buffer_size_u64, bad_offsets))
 }
 
 /// A struct that pretends to be [io::Write] by doing block-by-block comparisons against another reader.
 #[derive(Debug)]
-struct CompareWriter<R: io::Read> {
+pub(crate) struct CompareWriter<R: io::Read> {
     compare: R,
+    /// Reused across writes, so that verifying doesn't allocate per block.
+    expected: Vec<u8>,
     mismatched: usize,
+    /// The offsets at which the mismatched blocks start.
+    bad_offsets: Vec<u64>,
     current_offset: usize,
+    /// Fail any further writes once this many blocks mismatched.
+    max_bad_blocks: Option<FailedReads>,
+    /// Compare in blocks of this size, aligned to multiples of it. By
+    /// default, every write is compared as one block.
+    block_size: Option<usize>,
+    aborted: bool,
+    /// When the previous write returned, so that the time until the next
+    /// one is the time it took to read it (for the block log).
+    last_write: Instant,
+    /// The read time of the current block so far (for the block log).
+    block_latency: Duration,
 }
 
 impl<R: io::Read> CompareWriter<R> {
-    fn new(compare: R) -> Self {
+    pub(crate) fn new(compare: R) -> Self {
         Self {
             compare,
+            expected: Vec::new(),
             mismatched: 0,
+            bad_offsets: Vec::new(),
             current_offset: 0,
+            max_bad_blocks: None,
+            block_size: None,
+            aborted: false,
+            last_write: Instant::now(),
+            block_latency: Duration::ZERO,
         }
     }
+
+    /// Logs the outcome of the block at `offset` to the block log, if the
+    /// block is done (or `force`d, for the last and partial block).
+    fn log_block(&mut self, offset: u64, force: bool) {
+        let Some(block_size) = self.block_size else {
+            return;
+        };
+        let end = self.current_offset as u64;
+        let length = (end - offset).min(block_size as u64);
+        if length == 0 || (length < block_size as u64 && !force) {
+            return;
+        }
+        let result = match self.bad_offsets.last() == Some(&offset) {
+            true => "bad",
+            false => "ok",
+        };
+        block_log::record("read", offset, length, result, self.block_latency, 0);
+        self.block_latency = Duration::ZERO;
+    }
+
+    fn into_result(self) -> Result<(), BadBlocks> {
+        if self.mismatched == 0 {
+            return Ok(());
+        }
+        Err(BadBlocks {
+            count: self.mismatched,
+            offsets: self.bad_offsets,
+            aborted: self.aborted,
+        })
+    }
+
+    /// The offsets of the blocks that didn't match, in order.
+    pub(crate) fn bad_offsets(&self) -> &[u64] {
+        &self.bad_offsets
+    }
 }
 
 impl<R: io::Read> io::Write for CompareWriter<R> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut read = Vec::with_capacity(buf.len());
-        read.resize(buf.len(), 0);
-        self.compare.read_exact(&mut read)?;
-        self.current_offset += buf.len();
-        if &read != buf {
-            warn!(
-                offset = self.current_offset,
-                "Did not read back the exact bytes written"
-            );
-            self.mismatched += 1;
+        self.expected.resize(buf.len(), 0);
+        let logging = block_log::enabled();
+        let read_time = match logging {
+            true => self.last_write.elapsed(),
+            false => Duration::ZERO,
+        };
+        self.compare.read_exact(&mut self.expected)?;
+        let buf_offset = self.current_offset;
+        let mut start = 0;
+        while start < buf.len() {
+            // Writes don't have to line up with blocks:
+            let (block_offset, end) = match self.block_size {
+                Some(block_size) => {
+                    let block_offset = (buf_offset + start) / block_size * block_size;
+                    let end = (block_offset + block_size - buf_offset).min(buf.len());
+                    (block_offset as u64, end)
+                }
+                None => (buf_offset as u64, buf.len()),
+            };
+            let mismatched = self.expected[start..end] != buf[start..end];
+            let new_bad = mismatched && self.bad_offsets.last() != Some(&block_offset);
+            if new_bad {
+                warn!(
+                    event = "bad_block",
+                    offset = block_offset,
+                    "Did not read back the exact bytes written"
+                );
+                self.mismatched += 1;
+                self.bad_offsets.push(block_offset);
+            }
+            self.current_offset = buf_offset + end;
+            if logging {
+                let share = (end - start) as f64 / buf.len() as f64;
+                self.block_latency += read_time.mul_f64(share);
+                self.log_block(block_offset, false);
+            }
+            start = end;
+            if new_bad
+                && self
+                    .max_bad_blocks
+                    .is_some_and(|max| self.mismatched >= max)
+            {
+                self.aborted = true;
+                return Err(io::Error::other("reached the maximum number of bad blocks"));
+            }
         }
+        self.last_write = Instant::now();
         Ok(buf.len())
     }
 
@@ -83,8 +606,21 @@ impl<R: io::Read> io::Write for CompareWriter<R> {
 
 #[cfg(test)]
 mod test {
-    use super::CompareWriter;
-    use std::io;
+    use super::{
+        probe_initial_state, read_back, read_back_from, read_back_reversed_from, warm_up,
+        CompareWriter, InitialState,
+    };
+    use crate::{
+        target::{MemoryTarget, Target},
+        test_util::sparse_file,
+        write_test::{write, write_to},
+    };
+    use std::{
+        fs,
+        io::{self, Seek, Write},
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
     use tracing_test::traced_test;
 
     #[traced_test]
@@ -98,6 +634,9 @@ mod test {
         let mut compare = CompareWriter::new(io::Cursor::new(input));
         io::copy(&mut read_back, &mut compare).expect("No io errors");
         assert_eq!(compare.mismatched, 1);
+        assert_eq!(compare.bad_offsets().len(), 1);
+        let bad = compare.bad_offsets()[0];
+        assert!(bad <= 1024 * 512 && 1024 * 512 < bad + 8192);
     }
 
     #[traced_test]
@@ -110,4 +649,189 @@ mod test {
         io::copy(&mut read_back, &mut compare).expect("No io errors");
         assert_eq!(compare.mismatched, 0);
     }
+
+    #[traced_test]
+    #[test]
+    fn verifies_single_block() {
+        let path = sparse_file("read-single-block", 0);
+        write(&path, 4096, Some(4096), 1.into(), 0, None, None).expect("No io errors");
+        let (_, result) =
+            read_back(&path, 4096, Some(4096), 1.into(), None, None, None).expect("No io errors");
+        assert_eq!(result, Ok(()));
+
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(io::SeekFrom::Start(4095)).unwrap();
+        file.write_all(&[0]).unwrap();
+        drop(file);
+        let (_, result) =
+            read_back(&path, 4096, Some(4096), 1.into(), None, None, None).expect("No io errors");
+        assert_eq!(result.map_err(|bad| bad.count), Err(1));
+    }
+
+    #[traced_test]
+    #[test]
+    fn compares_aligned_blocks() {
+        let expected = vec![1; 12288];
+        let mut compare = CompareWriter::new(&expected[..]);
+        compare.block_size = Some(3072);
+        let mut actual = expected.clone();
+        actual[3071] = 0;
+        actual[3072] = 0;
+        actual[8191] = 0;
+        actual[8192] = 0;
+        for chunk in actual.chunks(4096) {
+            compare.write_all(chunk).unwrap();
+        }
+        assert_eq!(compare.bad_offsets(), [0, 3072, 6144]);
+    }
+
+    #[traced_test]
+    #[test]
+    fn stops_at_max_bad_blocks() {
+        let path = sparse_file("read-max-bad", 0);
+        write(&path, 4096, Some(65536), 1.into(), 0, None, None).expect("No io errors");
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        for offset in [0, 20000, 40000] {
+            file.seek(io::SeekFrom::Start(offset)).unwrap();
+            file.write_all(&[0xff]).unwrap();
+        }
+        drop(file);
+
+        let bad = read_back(&path, 4096, Some(65536), 1.into(), None, None, None)
+            .expect("No io errors")
+            .1
+            .unwrap_err();
+        assert_eq!(bad.count, 3);
+        assert!(!bad.aborted);
+        let bad = read_back(&path, 4096, Some(65536), 1.into(), None, Some(2), None)
+            .expect("No io errors")
+            .1
+            .unwrap_err();
+        assert_eq!(bad.count, 2);
+        assert_eq!(bad.offsets.len(), 2);
+        assert_eq!(bad.offsets[0], 0);
+        assert!(bad.aborted);
+    }
+
+    /// A target where one byte reads back corrupted, but only the first time.
+    #[derive(Debug)]
+    struct Glitchy(MemoryTarget, u64, AtomicBool);
+
+    impl Target for Glitchy {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let n = self.0.read_at(buf, offset)?;
+            if (offset..offset + n as u64).contains(&self.1)
+                && !self.2.swap(true, Ordering::Relaxed)
+            {
+                buf[(self.1 - offset) as usize] ^= 1;
+            }
+            Ok(n)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+            self.0.write_at(buf, offset)
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            self.0.len()
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[traced_test]
+    #[test]
+    fn verifies_twice() {
+        let target = Glitchy(
+            MemoryTarget::new(4096 * 16),
+            4096 * 3 + 100,
+            AtomicBool::new(false),
+        );
+        write_to(&target, 4096, None, 1.into(), 0, None, None).expect("No io errors");
+        target.0.with_data(|data| data[4096 * 9] ^= 1);
+        let mut transient = Vec::new();
+        let bad = read_back_from(
+            &target,
+            4096,
+            None,
+            1.into(),
+            None,
+            None,
+            Some(&mut transient),
+        )
+        .expect("No io errors")
+        .1
+        .unwrap_err();
+        assert_eq!(bad.count, 1);
+        assert_eq!(bad.offsets, vec![4096 * 9]);
+        assert_eq!(transient, vec![4096 * 3]);
+        assert!(logs_contain("Read back correctly the second time"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn reads_back_in_reverse() {
+        let target = MemoryTarget::new(4096 * 16 + 100);
+        write_to(&target, 4096, None, 1.into(), 0, None, None).expect("No io errors");
+        let (read, result) = read_back_reversed_from(&target, 4096, None, 1.into(), None, None)
+            .expect("No io errors");
+        assert_eq!(read, 4096 * 16 + 100);
+        assert_eq!(result, Ok(()));
+
+        target.with_data(|data| {
+            data[4096 * 2] ^= 1;
+            data[4096 * 11] ^= 1;
+            data[4096 * 16 + 50] ^= 1;
+        });
+        let bad = read_back_reversed_from(&target, 4096, None, 1.into(), None, None)
+            .expect("No io errors")
+            .1
+            .unwrap_err();
+        assert_eq!(bad.offsets, vec![4096 * 2, 4096 * 11, 4096 * 16]);
+        // Going backwards, the first bad blocks are the last ones:
+        let bad = read_back_reversed_from(&target, 4096, None, 1.into(), Some(2), None)
+            .expect("No io errors")
+            .1
+            .unwrap_err();
+        assert_eq!(bad.offsets, vec![4096 * 11, 4096 * 16]);
+        assert!(bad.aborted);
+
+        let err = read_back_reversed_from(&target, 4096, Some(4096 * 20), 1.into(), None, None)
+            .unwrap_err();
+        assert!(err.to_string().contains("The device ended"));
+    }
+
+    #[test]
+    fn probes_initial_state() {
+        let path = sparse_file("probe", 65536);
+        let probe = || probe_initial_state(&path, 4096, None).expect("No io errors");
+        assert_eq!(probe(), InitialState::Zeroes);
+        fs::write(&path, vec![0xff; 65536]).unwrap();
+        assert_eq!(probe(), InitialState::Erased);
+        write(&path, 4096, Some(65536), 1.into(), 0, None, None).expect("No io errors");
+        assert_eq!(probe(), InitialState::Data);
+        fs::write(&path, vec![0; 65536]).unwrap();
+        write(&path, 4096, Some(8192), 1.into(), 0, None, None).expect("No io errors");
+        assert_eq!(probe(), InitialState::Mixed);
+        let small = probe_initial_state(&path, 4096, Some(1000)).expect("No io errors");
+        assert_eq!(small, InitialState::Unsampled);
+    }
+
+    #[traced_test]
+    #[test]
+    fn warms_up() {
+        let path = sparse_file("warmup", 65536);
+        warm_up(&path, 4096, None, Duration::from_millis(10)).expect("No io errors");
+        assert!(logs_contain("warm-up finished"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn short_device_is_an_error() {
+        let path = sparse_file("read-short", 0);
+        write(&path, 4096, Some(2048), 1.into(), 0, None, None).expect("No io errors");
+        assert!(read_back(&path, 4096, Some(4096), 1.into(), None, None, None).is_err());
+    }
 }
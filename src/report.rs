@@ -0,0 +1,713 @@
+//! Reporting the results of a test run.
+
+use crate::{
+    blank::ResidualData,
+    build_info::BuildInfo,
+    crypto::{Seed, SeedSource},
+    deadline::Progress,
+    estimate::CapacityEstimate,
+    fraud::CapacityReport,
+    health::Health,
+    hpa::Capacity,
+    pattern::Pattern,
+    read_test::InitialState,
+    speed_class::SpeedClassReport,
+    zones::ZoneReport,
+    DeviceResult, Outcome, PhaseTiming, UncertainReason,
+};
+use anyhow::Context;
+use serde::Serialize;
+use std::{
+    fmt, fs,
+    path::Path,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+/// Everything we found out about one device during its test.
+#[derive(Debug, Serialize)]
+pub(crate) struct DeviceReport {
+    pub device: PathBuf,
+    /// The friendly name given with --label.
+    pub label: Option<String>,
+    pub serial_number: Option<String>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    /// Why the result is "uncertain", if it is.
+    pub uncertain_reason: Option<UncertainReason>,
+    /// The read test stopped at --max-bad-blocks, so `bad_blocks` is a lower bound.
+    pub bad_blocks_lower_bound: bool,
+    pub bad_block_offsets: Vec<u64>,
+    /// When the write phase started and finished, in seconds since the Unix epoch.
+    pub write_started: Option<f64>,
+    pub write_finished: Option<f64>,
+    pub write_seconds: Option<f64>,
+    pub write_bytes_per_second: Option<f64>,
+    /// When the read phase started and finished, in seconds since the Unix epoch.
+    pub read_started: Option<f64>,
+    pub read_finished: Option<f64>,
+    pub read_seconds: Option<f64>,
+    pub read_bytes_per_second: Option<f64>,
+    #[serde(skip)]
+    pub write: Option<PhaseTiming>,
+    #[serde(skip)]
+    pub read: Option<PhaseTiming>,
+    /// What the device held before the test, with --probe-initial-state.
+    pub initial_state: Option<InitialState>,
+    /// What the bad blocks say about the real capacity of the device.
+    pub capacity: Option<CapacityReport>,
+    /// Roughly where on the platters the bad blocks are (an approximation),
+    /// for hard disks only.
+    pub bad_block_zones: Option<ZoneReport>,
+    /// The data left on a device that should be blank, with --verify-blank.
+    pub residual_data: Option<ResidualData>,
+    /// The blocks that read back correctly only the second time, with
+    /// --verify-twice. These don't count as bad blocks.
+    pub transient_bad_block_offsets: Option<Vec<u64>>,
+    /// The stress pattern the result is from, with --pattern-set, if the
+    /// device failed under one.
+    pub pattern: Option<Pattern>,
+    /// The speed classes the device appears to meet, with --speed-class
+    /// (an approximation).
+    pub speed_class: Option<SpeedClassReport>,
+    /// What the test of the device panicked with, if it did.
+    pub panic_message: Option<String>,
+    /// The error that stopped the test of the device, if any.
+    pub error_message: Option<String>,
+    /// What a few probes say about the capacity, with --estimate-only.
+    pub capacity_estimate: Option<CapacityEstimate>,
+    /// The accessible, native and factory capacities of an ATA drive,
+    /// which differ if it hides some of its capacity.
+    pub ata_capacity: Option<Capacity>,
+    /// How far the test got, if it ran out of --max-runtime-per-device.
+    pub timed_out_at: Option<Progress>,
+    pub health: Option<Health>,
+    /// The seed of the run, to pass back with --seed to verify the data
+    /// again (along with --key-by-serial, if it was used).
+    pub seed: Option<Seed>,
+    pub seed_source: Option<SeedSource>,
+    /// The build of disk-spinner that tested the device.
+    pub build: BuildInfo,
+}
+
+impl DeviceReport {
+    pub(crate) fn new(
+        device: PathBuf,
+        label: Option<String>,
+        serial_number: Option<String>,
+        result: DeviceResult,
+    ) -> Self {
+        let DeviceResult {
+            outcome,
+            bad_offsets,
+            aborted_early,
+            write,
+            read,
+            initial_state,
+            capacity,
+            bad_block_zones,
+            residual_data,
+            transient_offsets,
+            pattern,
+            speed_class,
+            panic_message,
+            error_message,
+            capacity_estimate,
+            seed,
+            ata_capacity,
+            timed_out_at,
+        } = result;
+        let health = Health::score(&outcome, transient_offsets.as_ref().map_or(0, Vec::len));
+        let uncertain_reason = match outcome {
+            Outcome::Uncertain(_, reason) => Some(reason),
+            _ => None,
+        };
+        Self {
+            device,
+            label,
+            serial_number,
+            outcome,
+            uncertain_reason,
+            bad_blocks_lower_bound: aborted_early,
+            bad_block_offsets: bad_offsets,
+            write_started: write.map(|t| unix_seconds(t.started)),
+            write_finished: write.map(|t| unix_seconds(t.finished())),
+            write_seconds: write.map(|t| t.elapsed.as_secs_f64()),
+            write_bytes_per_second: write.map(|t| t.bytes_per_second()),
+            read_started: read.map(|t| unix_seconds(t.started)),
+            read_finished: read.map(|t| unix_seconds(t.finished())),
+            read_seconds: read.map(|t| t.elapsed.as_secs_f64()),
+            read_bytes_per_second: read.map(|t| t.bytes_per_second()),
+            write,
+            read,
+            initial_state,
+            capacity,
+            bad_block_zones,
+            residual_data,
+            transient_bad_block_offsets: transient_offsets,
+            pattern,
+            speed_class,
+            panic_message,
+            error_message,
+            capacity_estimate,
+            ata_capacity,
+            timed_out_at,
+            health,
+            seed,
+            seed_source: None,
+            build: BuildInfo::current(),
+        }
+    }
+}
+
+/// Prints a table summarizing each device's results to stdout.
+pub(crate) fn print_summary(reports: &[DeviceReport]) {
+    let width = |header: &str, column: &dyn Fn(&DeviceReport) -> String| {
+        reports
+            .iter()
+            .map(|r| column(r).len())
+            .chain([header.len()])
+            .max()
+            .unwrap_or(0)
+    };
+    let device = |r: &DeviceReport| r.device.to_string_lossy().into_owned();
+    let label = |r: &DeviceReport| r.label.clone().unwrap_or_else(|| "-".to_string());
+    let serial = |r: &DeviceReport| r.serial_number.clone().unwrap_or_else(|| "-".to_string());
+    let write = |r: &DeviceReport| format_timing(r.write);
+    let read = |r: &DeviceReport| format_timing(r.read);
+    let initial_state = |r: &DeviceReport| match r.initial_state {
+        Some(InitialState::Zeroes) => "zeroes".to_string(),
+        Some(InitialState::Erased) => "erased".to_string(),
+        Some(InitialState::Data) => "data".to_string(),
+        Some(InitialState::Mixed) => "mixed".to_string(),
+        Some(InitialState::Unsampled) => "unsampled".to_string(),
+        None => "-".to_string(),
+    };
+    let (device_width, label_width, serial_width, write_width, read_width) = (
+        width("DEVICE", &device),
+        width("LABEL", &label),
+        width("SERIAL", &serial),
+        width("WRITE", &write),
+        width("READ", &read),
+    );
+    println!(
+        "{:device_width$}  {:label_width$}  {:serial_width$}  {:13}  {:10}  {:>10}  {:write_width$}  {:read_width$}  {:>5}  VERDICT",
+        "DEVICE", "LABEL", "SERIAL", "INITIAL STATE", "RESULT", "BAD BLOCKS", "WRITE", "READ", "SCORE"
+    );
+    for report in reports {
+        let (result, bad_blocks) = result_column(report);
+        let (score, verdict) = score_column(report);
+        println!(
+            "{:device_width$}  {:label_width$}  {:serial_width$}  {:13}  {:10}  {:>10}  {:write_width$}  {:read_width$}  {:>5}  {}",
+            device(report),
+            label(report),
+            serial(report),
+            initial_state(report),
+            result,
+            bad_blocks,
+            write(report),
+            read(report),
+            score,
+            verdict
+        );
+    }
+    for report in reports {
+        let Some(capacity) = report.capacity.filter(|c| c.is_fake()) else {
+            continue;
+        };
+        print!(
+            "{}: FAKE CAPACITY - claims {}, but only {} appear usable",
+            device(report),
+            indicatif::BinaryBytes(capacity.claimed_capacity),
+            indicatif::BinaryBytes(capacity.usable_capacity)
+        );
+        match capacity.wraparound_offset {
+            Some(offset) => println!(
+                " (writes wrap around, overwriting the start, at offset {})",
+                offset
+            ),
+            None => println!(" (writes past that are lost)"),
+        }
+    }
+    for report in reports {
+        let Some(residual) = &report.residual_data else {
+            continue;
+        };
+        match residual.regions.first() {
+            None => println!("{}: blank", device(report)),
+            Some((offset, _)) => println!(
+                "{}: NOT BLANK - {} of data left in {}{} regions, the first at offset {}",
+                device(report),
+                indicatif::BinaryBytes(residual.bytes),
+                if residual.regions_truncated {
+                    "over "
+                } else {
+                    ""
+                },
+                residual.regions.len(),
+                offset
+            ),
+        }
+    }
+    for report in reports {
+        let Some(pattern) = report.pattern else {
+            continue;
+        };
+        println!(
+            "{}: failed under the {} stress pattern (--pattern-set)",
+            device(report),
+            pattern
+        );
+    }
+    for report in reports {
+        let Some(estimate) = &report.capacity_estimate else {
+            continue;
+        };
+        println!(
+            "{}: capacity fraud {} - {} of {} probes failed, at most {} of the claimed {} appear usable",
+            device(report),
+            estimate.fraud_likelihood,
+            estimate.failed_offsets.len(),
+            estimate.probes,
+            indicatif::BinaryBytes(estimate.usable_capacity),
+            indicatif::BinaryBytes(estimate.claimed_capacity)
+        );
+    }
+    for report in reports {
+        let Some(message) = &report.panic_message else {
+            continue;
+        };
+        println!(
+            "{}: THE TEST PANICKED ({}), so the result is unknown",
+            device(report),
+            message
+        );
+    }
+    for report in reports {
+        let Some(message) = &report.error_message else {
+            continue;
+        };
+        println!(
+            "{}: THE TEST STOPPED ON AN ERROR ({}), so the result is unknown",
+            device(report),
+            message
+        );
+    }
+    for report in reports {
+        let Some(speed_class) = &report.speed_class else {
+            continue;
+        };
+        println!(
+            "{}: meets speed classes {} (approximate), fails {}",
+            device(report),
+            none_if_empty(&speed_class.meets),
+            none_if_empty(&speed_class.fails)
+        );
+    }
+    for report in reports {
+        let Some(transient) = &report.transient_bad_block_offsets else {
+            continue;
+        };
+        println!(
+            "{}: {} persistent bad blocks, {} transient glitches that read back correctly the second time",
+            device(report),
+            report.bad_block_offsets.len(),
+            transient.len()
+        );
+    }
+    for report in reports {
+        let Some(zones) = report.bad_block_zones else {
+            continue;
+        };
+        print!(
+            "{}: bad blocks by zone (approximate) - outer {}, middle {}, inner {}",
+            device(report),
+            zones.outer,
+            zones.middle,
+            zones.inner
+        );
+        match zones.concentrated_in {
+            Some(zone) => println!(", concentrated in the {}", zone),
+            None => println!(),
+        }
+    }
+}
+
+/// The RESULT and BAD BLOCKS columns of a device's row.
+fn result_column(report: &DeviceReport) -> (&'static str, String) {
+    match &report.outcome {
+        Outcome::Good => ("good", "0".to_string()),
+        Outcome::Bad(n) if report.bad_blocks_lower_bound => ("BAD", format!(">={}", n)),
+        Outcome::Bad(n) => ("BAD", n.to_string()),
+        Outcome::Uncertain(n, _) => ("uncertain", n.to_string()),
+        Outcome::Unverified => ("unverified", "-".to_string()),
+        Outcome::Slow => ("SLOW", "0".to_string()),
+    }
+}
+
+/// The SCORE and VERDICT columns of a device's row.
+fn score_column(report: &DeviceReport) -> (String, &'static str) {
+    match &report.health {
+        Some(health) => (health.score.to_string(), health.verdict),
+        None => (
+            "-".to_string(),
+            "not scored, the data was not fully verified",
+        ),
+    }
+}
+
+/// The totals across all devices in a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BatchTotals {
+    pub devices: usize,
+    pub bytes_written: u64,
+    pub bytes_verified: u64,
+    pub bad_blocks: u64,
+    pub wall_clock: Duration,
+}
+
+impl BatchTotals {
+    pub(crate) fn new(reports: &[DeviceReport], wall_clock: Duration) -> Self {
+        let bytes = |timing: Option<PhaseTiming>| timing.map_or(0, |t| t.bytes);
+        Self {
+            devices: reports.len(),
+            bytes_written: reports.iter().map(|r| bytes(r.write)).sum(),
+            bytes_verified: reports.iter().map(|r| bytes(r.read)).sum(),
+            bad_blocks: reports
+                .iter()
+                .map(|r| match r.outcome {
+                    Outcome::Bad(n) | Outcome::Uncertain(n, _) => n as u64,
+                    Outcome::Good | Outcome::Unverified | Outcome::Slow => 0,
+                })
+                .sum(),
+            wall_clock,
+        }
+    }
+
+    /// All bytes written and verified, per second of wall-clock time.
+    pub(crate) fn bytes_per_second(&self) -> f64 {
+        (self.bytes_written + self.bytes_verified) as f64 / self.wall_clock.as_secs_f64()
+    }
+
+    /// Prints the totals as a single line, to go under [print_summary].
+    pub(crate) fn print(&self) {
+        println!("{}", self);
+    }
+}
+
+impl fmt::Display for BatchTotals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "TOTAL: {} devices, {} written, {} verified, {} bad blocks in {} (aggregate {}/s)",
+            self.devices,
+            indicatif::BinaryBytes(self.bytes_written),
+            indicatif::BinaryBytes(self.bytes_verified),
+            self.bad_blocks,
+            indicatif::FormattedDuration(self.wall_clock),
+            indicatif::BinaryBytes(self.bytes_per_second() as u64)
+        )
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Formats how long a phase took and its throughput, like "01:02:03 at 180.00 MiB/s".
+fn format_timing(timing: Option<PhaseTiming>) -> String {
+    match timing {
+        Some(timing) => format!(
+            "{} at {}/s",
+            indicatif::FormattedDuration(timing.elapsed),
+            indicatif::BinaryBytes(timing.bytes_per_second() as u64)
+        ),
+        None => "-".to_string(),
+    }
+}
+
+/// Joins a list of names, or says "none" if there are none.
+fn none_if_empty(names: &[&str]) -> String {
+    match names.is_empty() {
+        true => "none".to_string(),
+        false => names.join(", "),
+    }
+}
+
+/// A file that the results of a run are written to, in addition to the
+/// summary on the terminal. Any number of them can be written at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Output {
+    /// Everything in the [DeviceReport]s, as JSON (--json-report).
+    Json(PathBuf),
+    /// The summary table and totals, as a standalone HTML page (--html-report).
+    Html(PathBuf),
+    /// A line of CSV per device with its result and throughput (--stats-csv).
+    StatsCsv(PathBuf),
+}
+
+impl Output {
+    pub(crate) fn path(&self) -> &Path {
+        match self {
+            Output::Json(path) | Output::Html(path) | Output::StatsCsv(path) => path,
+        }
+    }
+
+    pub(crate) fn write(
+        &self,
+        reports: &[DeviceReport],
+        totals: &BatchTotals,
+    ) -> anyhow::Result<()> {
+        match self {
+            Output::Json(path) => write_json(path, reports),
+            Output::Html(path) => write_html(path, reports, totals),
+            Output::StatsCsv(path) => write_stats_csv(path, reports),
+        }
+    }
+}
+
+/// Writes the device reports to a JSON file.
+pub(crate) fn write_json(path: &Path, reports: &[DeviceReport]) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(reports)?;
+    fs::write(path, json).with_context(|| format!("Writing JSON report {:?}", path))
+}
+
+/// Writes the summary table and the totals to an HTML file.
+pub(crate) fn write_html(
+    path: &Path,
+    reports: &[DeviceReport],
+    totals: &BatchTotals,
+) -> anyhow::Result<()> {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>disk-spinner report</title>\n</head>\n<body>\n<table>\n<tr><th>Device</th><th>Label</th><th>Serial</th><th>Result</th><th>Bad blocks</th><th>Write</th><th>Read</th><th>Score</th><th>Verdict</th></tr>\n",
+    );
+    for report in reports {
+        let (result, bad_blocks) = result_column(report);
+        let (score, verdict) = score_column(report);
+        let cells = [
+            report.device.to_string_lossy().into_owned(),
+            report.label.clone().unwrap_or_else(|| "-".to_string()),
+            report
+                .serial_number
+                .clone()
+                .unwrap_or_else(|| "-".to_string()),
+            result.to_string(),
+            bad_blocks,
+            format_timing(report.write),
+            format_timing(report.read),
+            score,
+            verdict.to_string(),
+        ];
+        html.push_str("<tr>");
+        for cell in cells {
+            html.push_str(&format!("<td>{}</td>", escape_html(&cell)));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str(&format!(
+        "</table>\n<p>{}</p>\n</body>\n</html>\n",
+        escape_html(&totals.to_string())
+    ));
+    fs::write(path, html).with_context(|| format!("Writing HTML report {:?}", path))
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const STATS_CSV_HEADER: &str = "device,label,serial_number,result,bad_blocks,write_seconds,write_bytes_per_second,read_seconds,read_bytes_per_second,health_score\n";
+
+/// Writes a line of CSV per device with its result and throughput.
+pub(crate) fn write_stats_csv(path: &Path, reports: &[DeviceReport]) -> anyhow::Result<()> {
+    let optional = |value: Option<f64>| value.map_or(String::new(), |v| v.to_string());
+    let mut csv = String::from(STATS_CSV_HEADER);
+    for report in reports {
+        let bad_blocks = match report.outcome {
+            Outcome::Bad(n) | Outcome::Uncertain(n, _) => n.to_string(),
+            Outcome::Good | Outcome::Slow => "0".to_string(),
+            Outcome::Unverified => String::new(),
+        };
+        let fields = [
+            escape_csv(&report.device.to_string_lossy()),
+            escape_csv(report.label.as_deref().unwrap_or_default()),
+            escape_csv(report.serial_number.as_deref().unwrap_or_default()),
+            result_column(report).0.to_lowercase(),
+            bad_blocks,
+            optional(report.write_seconds),
+            optional(report.write_bytes_per_second),
+            optional(report.read_seconds),
+            optional(report.read_bytes_per_second),
+            report
+                .health
+                .as_ref()
+                .map_or(String::new(), |h| h.score.to_string()),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    fs::write(path, csv).with_context(|| format!("Writing stats CSV {:?}", path))
+}
+
+/// Quotes a CSV field if it needs it.
+fn escape_csv(field: &str) -> String {
+    match field.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn serializes() {
+        let reports = [
+            DeviceReport {
+                seed: Some(Seed::Long([0xab; 32])),
+                seed_source: Some(SeedSource::OsRandom),
+                ..DeviceReport::new(
+                    PathBuf::from("/dev/sda"),
+                    Some("bay3".to_string()),
+                    Some("ZL2ABC".to_string()),
+                    Outcome::Good.into(),
+                )
+            },
+            DeviceReport::new(
+                PathBuf::from("/dev/sdb"),
+                None,
+                None,
+                DeviceResult {
+                    outcome: Outcome::Bad(12),
+                    bad_offsets: vec![0, 4096],
+                    aborted_early: true,
+                    write: Some(PhaseTiming {
+                        started: SystemTime::UNIX_EPOCH,
+                        elapsed: std::time::Duration::from_secs(2),
+                        bytes: 1000,
+                    }),
+                    read: None,
+                    initial_state: Some(InitialState::Zeroes),
+                    capacity: Some(CapacityReport::analyze(8192, 4096, &[0, 4096])),
+                    bad_block_zones: ZoneReport::of(8192, &[0, 4096]),
+                    residual_data: None,
+                    transient_offsets: Some(vec![8192]),
+                    pattern: Some(Pattern::Checkerboard),
+                    speed_class: Some(SpeedClassReport::new(
+                        12e6,
+                        crate::speed_class::RandomIops {
+                            read: 2000.0,
+                            write: 300.0,
+                        },
+                    )),
+                    panic_message: None,
+                    error_message: None,
+                    capacity_estimate: None,
+                    seed: None,
+                    ata_capacity: Some(Capacity {
+                        accessible: 1000,
+                        native: 1500,
+                        factory: None,
+                    }),
+                    timed_out_at: None,
+                },
+            ),
+            DeviceReport::new(
+                PathBuf::from("/dev/sdc"),
+                None,
+                None,
+                DeviceResult {
+                    timed_out_at: Some(Progress {
+                        bytes_written: 8192,
+                        bytes_read: 0,
+                        offset_reached: 8192,
+                    }),
+                    ..Outcome::Uncertain(0, UncertainReason::Timeout).into()
+                },
+            ),
+        ];
+        let json = serde_json::to_value(&reports).unwrap();
+        assert_eq!(json[0]["device"], "/dev/sda");
+        assert_eq!(json[0]["label"], "bay3");
+        assert_eq!(json[0]["serial_number"], "ZL2ABC");
+        assert_eq!(json[0]["result"], "good");
+        assert_eq!(json[0]["health"]["score"], 100);
+        assert_eq!(json[1]["result"], "bad");
+        assert_eq!(json[1]["bad_blocks"], 12);
+        assert_eq!(json[1]["bad_blocks_lower_bound"], true);
+        assert_eq!(json[1]["ata_capacity"]["native"], 1500);
+        assert_eq!(json[1]["ata_capacity"]["factory"], serde_json::Value::Null);
+        assert_eq!(json[0]["ata_capacity"], serde_json::Value::Null);
+        assert_eq!(json[2]["timed_out_at"]["bytes_written"], 8192);
+        assert_eq!(json[1]["timed_out_at"], serde_json::Value::Null);
+        assert_eq!(json[1]["bad_block_offsets"][1], 4096);
+        assert_eq!(json[1]["write_finished"], 2.0);
+        assert_eq!(json[1]["write_seconds"], 2.0);
+        assert_eq!(json[1]["write_bytes_per_second"], 500.0);
+        assert_eq!(json[1]["read_seconds"], serde_json::Value::Null);
+        assert_eq!(json[0]["initial_state"], serde_json::Value::Null);
+        assert_eq!(json[1]["initial_state"], "zeroes");
+        assert_eq!(json[1]["capacity"]["claimed_capacity"], 8192);
+        assert_eq!(json[1]["capacity"]["usable_capacity"], 0);
+        assert_eq!(json[0]["bad_block_zones"], serde_json::Value::Null);
+        assert_eq!(json[1]["bad_block_zones"]["outer"], 1);
+        assert_eq!(json[1]["bad_block_zones"]["middle"], 1);
+        assert_eq!(json[1]["health"]["verdict"], "return it");
+        assert_eq!(
+            json[0]["transient_bad_block_offsets"],
+            serde_json::Value::Null
+        );
+        assert_eq!(json[1]["transient_bad_block_offsets"][0], 8192);
+        assert_eq!(json[0]["pattern"], serde_json::Value::Null);
+        assert_eq!(json[1]["pattern"], "checkerboard");
+        assert_eq!(json[0]["speed_class"], serde_json::Value::Null);
+        assert_eq!(json[0]["panic_message"], serde_json::Value::Null);
+        assert_eq!(json[1]["speed_class"]["meets"][3], "Class 10");
+        assert_eq!(json[1]["speed_class"]["random_write_iops"], 300);
+        assert_eq!(json[0]["uncertain_reason"], serde_json::Value::Null);
+        assert_eq!(json[2]["result"], "uncertain");
+        assert_eq!(json[2]["bad_blocks"], 0);
+        assert_eq!(json[2]["uncertain_reason"], "timeout");
+        assert_eq!(json[2]["health"], serde_json::Value::Null);
+        assert_eq!(json[0]["seed"], format!("0x{}", "ab".repeat(32)));
+        assert_eq!(json[0]["seed_source"], "os_random");
+        assert_eq!(json[1]["seed"], serde_json::Value::Null);
+        assert_eq!(json[0]["build"]["version"], env!("CARGO_PKG_VERSION"));
+        assert!(json[0]["build"]["git_commit"].is_string());
+
+        let totals = BatchTotals::new(&reports, std::time::Duration::from_secs(4));
+        assert_eq!(totals.devices, 3);
+        assert_eq!(totals.bytes_written, 1000);
+        assert_eq!(totals.bytes_verified, 0);
+        assert_eq!(totals.bad_blocks, 12);
+        assert_eq!(totals.bytes_per_second(), 250.0);
+
+        let dir = temp_dir("report");
+        let outputs = [
+            Output::Json(dir.join("report.json")),
+            Output::Html(dir.join("report.html")),
+            Output::StatsCsv(dir.join("stats.csv")),
+        ];
+        for output in &outputs {
+            output.write(&reports, &totals).expect("No io errors");
+        }
+        let json = fs::read_to_string(outputs[0].path()).unwrap();
+        assert!(json.contains("\"serial_number\": \"ZL2ABC\""));
+        let html = fs::read_to_string(outputs[1].path()).unwrap();
+        assert!(html.contains("<td>/dev/sdb</td><td>-</td><td>-</td><td>BAD</td><td>&gt;=12</td>"));
+        assert!(html.contains("<p>TOTAL: 3 devices"));
+        let csv = fs::read_to_string(outputs[2].path()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1], "/dev/sda,bay3,ZL2ABC,good,0,,,,,100");
+        assert_eq!(lines[2], "/dev/sdb,,,bad,12,2,500,,,30");
+        assert_eq!(lines[3], "/dev/sdc,,,uncertain,0,,,,,");
+        assert_eq!(escape_csv("bay \"3\", top"), "\"bay \"\"3\"\", top\"");
+    }
+}
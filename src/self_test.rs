@@ -0,0 +1,140 @@
+//! Checking that disk-spinner's data generator and verifier work
+//! together, entirely in memory.
+//!
+//! For a few block sizes and seeds, this generates garbage, checks that
+//! it is deterministic and can be regenerated from any offset, and then
+//! flips bits in some of its blocks to check that the verifier flags
+//! exactly those blocks, at the right offsets.
+
+use crate::{
+    crypto::{derive_seed, GarbageGenerator, Seed},
+    read_test::CompareWriter,
+};
+use std::io::{BufReader, Read, Write};
+use tracing::info;
+
+/// How many blocks of garbage each case generates.
+const BLOCKS: usize = 32;
+
+const BLOCK_SIZES: [usize; 3] = [512, 4096, 8192];
+
+const SEEDS: [&str; 4] = [
+    "0",
+    "1",
+    "18446744073709551615",
+    "0xfedcba9876543210fedcba9876543210fedcba9876543210fedcba9876543210",
+];
+
+/// Runs the self-test, returning an error if anything didn't behave as expected.
+pub(crate) fn run() -> anyhow::Result<()> {
+    for block_size in BLOCK_SIZES {
+        for seed in SEEDS {
+            let seed: Seed = seed.parse()?;
+            check(block_size, seed)?;
+            info!(block_size, %seed, "self-test case passed");
+        }
+    }
+    info!("self-test passed: the generator and verifier round-trip as expected");
+    Ok(())
+}
+
+/// Generates `len` bytes of garbage from the start of the stream.
+fn generate(block_size: usize, seed: Seed, len: usize) -> anyhow::Result<Vec<u8>> {
+    let generator = GarbageGenerator::new(block_size, seed, |_| {});
+    let mut data = vec![0; len];
+    BufReader::with_capacity(block_size, generator).read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Verifies `data` block by block, returning the offsets of the bad blocks.
+fn verify(block_size: usize, seed: Seed, data: &[u8]) -> anyhow::Result<Vec<u64>> {
+    let generator = GarbageGenerator::new(block_size, seed, |_| {});
+    let mut compare = CompareWriter::new(BufReader::with_capacity(block_size, generator));
+    for block in data.chunks(block_size) {
+        compare.write_all(block)?;
+    }
+    Ok(compare.bad_offsets().to_vec())
+}
+
+fn check(block_size: usize, seed: Seed) -> anyhow::Result<()> {
+    let len = block_size * BLOCKS;
+    let data = generate(block_size, seed, len)?;
+
+    if generate(block_size, seed, len)? != data {
+        anyhow::bail!("seed {}: the generator is not deterministic", seed);
+    }
+    if generate(block_size, derive_seed(seed, 1), len)? == data {
+        anyhow::bail!(
+            "seeds {} and {} generate the same data",
+            seed,
+            derive_seed(seed, 1)
+        );
+    }
+    if data.iter().all(|&b| b == 0) {
+        anyhow::bail!("seed {}: the generator only produces zeroes", seed);
+    }
+
+    // Random-order writes and resumed runs regenerate data from an offset:
+    let mut generator = GarbageGenerator::new(block_size, seed, |_| {});
+    for block in [BLOCKS - 1, 0, BLOCKS / 2, 1] {
+        let offset = block * block_size;
+        let mut buf = vec![0; block_size];
+        generator.seek(offset as u64);
+        generator.fill(&mut buf);
+        if buf != data[offset..offset + block_size] {
+            anyhow::bail!(
+                "seed {}: seeking to offset {} generates different data",
+                seed,
+                offset
+            );
+        }
+    }
+
+    let bad = verify(block_size, seed, &data)?;
+    if !bad.is_empty() {
+        anyhow::bail!(
+            "seed {}, block size {}: intact data was flagged at offsets {:?}",
+            seed,
+            block_size,
+            bad
+        );
+    }
+
+    // Flip one bit in the first, last and some in-between blocks, at
+    // different positions within each block:
+    let flipped_blocks = [0, 1, BLOCKS / 3, BLOCKS - 2, BLOCKS - 1];
+    let mut corrupted = data.clone();
+    for (i, &block) in flipped_blocks.iter().enumerate() {
+        let within = [0, block_size - 1, block_size / 2, 1, block_size / 3][i];
+        corrupted[block * block_size + within] ^= 1 << (i % 8);
+    }
+    let expected: Vec<u64> = flipped_blocks
+        .iter()
+        .map(|&block| (block * block_size) as u64)
+        .collect();
+    // The verifier warns about each bad block, which is the point here:
+    let quiet = tracing::Dispatch::none();
+    let bad = tracing::dispatcher::with_default(&quiet, || verify(block_size, seed, &corrupted))?;
+    if bad != expected {
+        anyhow::bail!(
+            "seed {}, block size {}: flipped bits at offsets {:?}, but the verifier flagged {:?}",
+            seed,
+            block_size,
+            expected,
+            bad
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::run;
+    use tracing_test::traced_test;
+
+    #[traced_test]
+    #[test]
+    fn passes() {
+        run().expect("self-test failed");
+    }
+}
@@ -0,0 +1,241 @@
+//! Checking which SD card speed classes a device meets (--speed-class).
+//!
+//! Cards are sold with speed class (Class 10, U3, V30) and application
+//! performance class (A1, A2) markings, which promise a minimum
+//! sequential write speed and, for the A classes, a minimum number of
+//! random 4 KiB reads and writes per second. Fake and worn out cards often
+//! fall well short of their markings.
+//!
+//! This is an APPROXIMATION of the official SD Association methodology,
+//! which measures through the card's own protocol with specific
+//! allocation units, fragmentation and timing rules. Here, the sequential
+//! speed is that of the write test, through the OS and its caches, and the
+//! random I/Os are a fixed number spread over the whole device. A card
+//! failing a class by a wide margin is worth a closer look, but neither a
+//! pass nor a fail is what the standard's own test would certify.
+
+use crate::{
+    crypto::Seed,
+    device_error::{DeviceIoError, Operation},
+    target::Target,
+};
+use anyhow::Context;
+use rand::{Rng, RngCore};
+use serde::Serialize;
+use std::{fs::OpenOptions, path::Path, time::Instant};
+use tracing::info;
+
+/// The size of the random I/Os, as in the application performance classes.
+const RANDOM_IO_SIZE: u64 = 4096;
+
+/// How many random reads and how many random writes are timed.
+const RANDOM_IOS: usize = 2048;
+
+/// What a speed class requires, in bytes and I/Os per second.
+#[derive(Debug, Clone, Copy)]
+struct Requirement {
+    name: &'static str,
+    sequential_write: f64,
+    random_read_iops: f64,
+    random_write_iops: f64,
+}
+
+const fn class(
+    name: &'static str,
+    mb_per_second: f64,
+    read_iops: f64,
+    write_iops: f64,
+) -> Requirement {
+    Requirement {
+        name,
+        sequential_write: mb_per_second * 1_000_000.0,
+        random_read_iops: read_iops,
+        random_write_iops: write_iops,
+    }
+}
+
+/// The classes, from the SD Association's published minimums.
+const CLASSES: [Requirement; 13] = [
+    class("Class 2", 2.0, 0.0, 0.0),
+    class("Class 4", 4.0, 0.0, 0.0),
+    class("Class 6", 6.0, 0.0, 0.0),
+    class("Class 10", 10.0, 0.0, 0.0),
+    class("U1", 10.0, 0.0, 0.0),
+    class("U3", 30.0, 0.0, 0.0),
+    class("V6", 6.0, 0.0, 0.0),
+    class("V10", 10.0, 0.0, 0.0),
+    class("V30", 30.0, 0.0, 0.0),
+    class("V60", 60.0, 0.0, 0.0),
+    class("V90", 90.0, 0.0, 0.0),
+    class("A1", 10.0, 1500.0, 500.0),
+    class("A2", 10.0, 4000.0, 2000.0),
+];
+
+/// How many random 4 KiB I/Os per second a device managed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RandomIops {
+    pub read: f64,
+    pub write: f64,
+}
+
+/// Times random 4 KiB writes and then reads over the first `capacity`
+/// bytes of the device (or all of it). This overwrites data all over the
+/// device, so it has to run before the write test.
+#[tracing::instrument(skip(dev_path, capacity, seed), fields(device = ?dev_path))]
+pub(crate) fn measure_random_iops(
+    dev_path: &Path,
+    capacity: Option<u64>,
+    seed: Seed,
+) -> anyhow::Result<RandomIops> {
+    let blockdev = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for writing", dev_path))?;
+    measure_random_iops_on(&blockdev, capacity, seed)
+}
+
+/// Like [measure_random_iops], but on any [Target].
+pub(crate) fn measure_random_iops_on(
+    target: &dyn Target,
+    capacity: Option<u64>,
+    seed: Seed,
+) -> anyhow::Result<RandomIops> {
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => target.len()?,
+    };
+    let blocks = capacity / RANDOM_IO_SIZE;
+    anyhow::ensure!(
+        blocks > 0,
+        "The device is too small to measure random I/O on"
+    );
+    let mut rng = seed.rng();
+    let offsets: Vec<u64> = (0..RANDOM_IOS)
+        .map(|_| rng.gen_range(0..blocks) * RANDOM_IO_SIZE)
+        .collect();
+    let mut buf = vec![0; RANDOM_IO_SIZE as usize];
+    rng.fill_bytes(&mut buf);
+
+    // The writes land in the OS cache, so the sync that flushes them to
+    // the device is part of the time they take.
+    let started = Instant::now();
+    for &offset in &offsets {
+        write_all_at(target, &buf, offset)
+            .map_err(|e| DeviceIoError::new(Operation::Write, offset, e))?;
+    }
+    target.sync().context("Syncing the random writes")?;
+    let write = RANDOM_IOS as f64 / started.elapsed().as_secs_f64().max(f64::MIN_POSITIVE);
+
+    if let Err(e) = target.drop_cache(0, capacity) {
+        tracing::debug!(error = %e, "Could not drop the cache before the random reads");
+    }
+    let started = Instant::now();
+    for &offset in offsets.iter().rev() {
+        let mut read = 0;
+        while read < buf.len() {
+            match target.read_at(&mut buf[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) => return Err(DeviceIoError::new(Operation::Read, offset, e).into()),
+            }
+        }
+    }
+    let read = RANDOM_IOS as f64 / started.elapsed().as_secs_f64().max(f64::MIN_POSITIVE);
+    Ok(RandomIops { read, write })
+}
+
+fn write_all_at(target: &dyn Target, mut buf: &[u8], mut offset: u64) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        let n = target.write_at(buf, offset)?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+/// Which speed classes a device appears to meet, and what it measured
+/// (rounded down to whole bytes and I/Os per second).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct SpeedClassReport {
+    pub sequential_write_bytes_per_second: u64,
+    pub random_read_iops: u64,
+    pub random_write_iops: u64,
+    pub meets: Vec<&'static str>,
+    pub fails: Vec<&'static str>,
+}
+
+impl SpeedClassReport {
+    /// Checks the write test's speed and the random I/O rates against the
+    /// requirements of each class.
+    pub(crate) fn new(sequential_write: f64, iops: RandomIops) -> Self {
+        let (meets, fails): (Vec<&Requirement>, Vec<_>) = CLASSES.iter().partition(|class| {
+            sequential_write >= class.sequential_write
+                && iops.read >= class.random_read_iops
+                && iops.write >= class.random_write_iops
+        });
+        Self {
+            sequential_write_bytes_per_second: sequential_write as u64,
+            random_read_iops: iops.read as u64,
+            random_write_iops: iops.write as u64,
+            meets: meets.into_iter().map(|class| class.name).collect(),
+            fails: fails.into_iter().map(|class| class.name).collect(),
+        }
+    }
+
+    /// Logs the classes the device meets and fails.
+    pub(crate) fn log(&self, dev_path: &Path) {
+        info!(
+            device = ?dev_path,
+            sequential_write_bytes_per_second = self.sequential_write_bytes_per_second,
+            random_read_iops = self.random_read_iops,
+            random_write_iops = self.random_write_iops,
+            meets = %self.meets.join(", "),
+            fails = %self.fails.join(", "),
+            "Measured speed classes (an approximation of the official test)"
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::target::MemoryTarget;
+
+    #[test]
+    fn checks_classes() {
+        let slow = SpeedClassReport::new(
+            12e6,
+            RandomIops {
+                read: 2000.0,
+                write: 300.0,
+            },
+        );
+        assert_eq!(
+            slow.meets,
+            ["Class 2", "Class 4", "Class 6", "Class 10", "U1", "V6", "V10"]
+        );
+        assert_eq!(slow.fails, ["U3", "V30", "V60", "V90", "A1", "A2"]);
+
+        let fast = SpeedClassReport::new(
+            95e6,
+            RandomIops {
+                read: 4500.0,
+                write: 2500.0,
+            },
+        );
+        assert!(fast.fails.is_empty());
+    }
+
+    #[test]
+    fn measures_random_iops() {
+        let target = MemoryTarget::new(4096 * 64);
+        let iops = measure_random_iops_on(&target, None, 1.into()).expect("No io errors");
+        assert!(iops.read > 0.0 && iops.write > 0.0);
+        assert!(target.with_data(|data| data.iter().any(|&b| b != 0)));
+        assert!(measure_random_iops_on(&target, Some(100), 1.into()).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Sending the results of a run to syslog (--syslog).
+//!
+//! Each device's verdict and the batch totals go to the local syslog
+//! daemon as one message each, with the `user` facility and a severity
+//! that follows the verdict, so that they can be filtered or forwarded to
+//! a remote log server like any other message. This talks to the syslog
+//! socket directly rather than through journald, so it works on systems
+//! without systemd too.
+//!
+//! Syslog is best effort: failing to reach it only logs a warning.
+
+use crate::{
+    report::{BatchTotals, DeviceReport},
+    Outcome,
+};
+use std::{io, os::unix::net::UnixDatagram, path::Path};
+use tracing::{debug, warn};
+
+/// Where the local syslog daemon listens, in order of preference.
+const SOCKETS: [&str; 2] = ["/dev/log", "/var/run/syslog"];
+
+/// The `user` facility, from syslog.h.
+const FACILITY: u8 = 1;
+
+/// Syslog severities, from syslog.h.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+}
+
+impl Severity {
+    fn of(outcome: &Outcome) -> Self {
+        match outcome {
+            Outcome::Good => Severity::Info,
+            Outcome::Bad(_) | Outcome::Slow => Severity::Error,
+            Outcome::Uncertain(..) => Severity::Warning,
+            Outcome::Unverified => Severity::Notice,
+        }
+    }
+}
+
+/// Sends each device's verdict and the batch totals to syslog, warning
+/// (but not failing) if that doesn't work.
+pub(crate) fn send_summary(reports: &[DeviceReport], totals: &BatchTotals) {
+    let socket = SOCKETS
+        .iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no syslog socket found"))
+        .and_then(connect);
+    let result = socket.and_then(|socket| send_to(&socket, reports, totals));
+    match result {
+        Ok(()) => debug!("Sent the results to syslog"),
+        Err(e) => warn!(error = %e, "Could not send the results to syslog (--syslog)"),
+    }
+}
+
+fn connect(path: &Path) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+fn send_to(
+    socket: &UnixDatagram,
+    reports: &[DeviceReport],
+    totals: &BatchTotals,
+) -> io::Result<()> {
+    for report in reports {
+        send(
+            socket,
+            Severity::of(&report.outcome),
+            &device_message(report),
+        )?;
+    }
+    let severity = reports
+        .iter()
+        .map(|r| Severity::of(&r.outcome))
+        .min()
+        .unwrap_or(Severity::Info);
+    send(socket, severity, &totals.to_string())
+}
+
+/// Sends a single message, in the format local syslog daemons expect.
+fn send(socket: &UnixDatagram, severity: Severity, message: &str) -> io::Result<()> {
+    let priority = FACILITY * 8 + severity as u8;
+    let line = format!(
+        "<{}>disk-spinner[{}]: {}",
+        priority,
+        std::process::id(),
+        message
+    );
+    socket.send(line.as_bytes()).map(|_| ())
+}
+
+/// Describes a device's verdict on one line.
+fn device_message(report: &DeviceReport) -> String {
+    let mut message = report.device.to_string_lossy().into_owned();
+    if let Some(label) = &report.label {
+        message += &format!(" ({})", label);
+    }
+    if let Some(serial) = &report.serial_number {
+        message += &format!(" serial {}", serial);
+    }
+    message += &match &report.outcome {
+        Outcome::Good => ": good".to_string(),
+        Outcome::Bad(n) => format!(": BAD, {} bad blocks", n),
+        Outcome::Uncertain(n, reason) => format!(": uncertain ({:?}), {} bad blocks", reason, n),
+        Outcome::Unverified => ": unverified".to_string(),
+        Outcome::Slow => ": SLOW, below --min-throughput".to_string(),
+    };
+    if let Some(health) = &report.health {
+        message += &format!(", health score {} - {}", health.score, health.verdict);
+    }
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DeviceResult, UncertainReason};
+    use std::{path::PathBuf, time::Duration};
+
+    #[test]
+    fn sends_summary() {
+        let dir = crate::test_util::temp_dir("syslog");
+        let path = dir.join("socket");
+        let server = UnixDatagram::bind(&path).unwrap();
+        let reports = [
+            DeviceReport::new(
+                PathBuf::from("/dev/sda"),
+                Some("bay3".to_string()),
+                Some("ZL2ABC".to_string()),
+                Outcome::Good.into(),
+            ),
+            DeviceReport::new(
+                PathBuf::from("/dev/sdb"),
+                None,
+                None,
+                DeviceResult::from(Outcome::Uncertain(2, UncertainReason::BelowThreshold)),
+            ),
+        ];
+        let totals = BatchTotals::new(&reports, Duration::from_secs(1));
+        send_to(&connect(&path).unwrap(), &reports, &totals).expect("No io errors");
+
+        let mut buf = [0; 1024];
+        let mut receive = || {
+            let n = server.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        };
+        let good = receive();
+        assert!(good.starts_with("<14>disk-spinner["), "{}", good);
+        assert!(
+            good.ends_with("]: /dev/sda (bay3) serial ZL2ABC: good, health score 100 - healthy")
+        );
+        let uncertain = receive();
+        assert!(uncertain.starts_with("<12>"));
+        assert!(uncertain.contains("/dev/sdb: uncertain (BelowThreshold), 2 bad blocks"));
+        let total = receive();
+        assert!(total.starts_with("<12>"));
+        assert!(total.contains("TOTAL: 2 devices"));
+    }
+}
@@ -0,0 +1,199 @@
+//! What the test writes its data to and reads it back from.
+//!
+//! The write and read tests only need to read and write at given offsets,
+//! so they work against any [Target]. Devices and regular files are
+//! [File]s; [MemoryTarget] is a fixed-size target in memory, for tests.
+
+use crate::{deadline, idle};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Seek},
+    os::unix::fs::FileExt,
+};
+
+#[cfg(test)]
+use std::sync::Mutex;
+
+/// Offset-addressed storage that the test can run against.
+pub(crate) trait Target: fmt::Debug {
+    /// Reads into `buf` from `offset`, returning how many bytes were read.
+    /// Returns 0 at the end of the target.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Writes `buf` at `offset`, returning how many bytes were written.
+    /// Fails with ENOSPC at the end of a target that can't grow.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize>;
+
+    /// The current size of the target in bytes.
+    fn len(&self) -> io::Result<u64>;
+
+    /// Makes sure everything written so far is durably stored.
+    fn sync(&self) -> io::Result<()>;
+
+    /// Forgets any cached copy of `len` bytes from `offset`, so that they
+    /// are read again from the storage itself. Targets without a cache
+    /// don't need to do anything.
+    fn drop_cache(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Target for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        deadline::check()?;
+        idle::io_starting();
+        let read = FileExt::read_at(self, buf, offset);
+        idle::io_finished();
+        if let Ok(n) = read {
+            deadline::read(offset, n);
+        }
+        read
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        deadline::check()?;
+        idle::io_starting();
+        let written = FileExt::write_at(self, buf, offset);
+        idle::io_finished();
+        if let Ok(n) = written {
+            deadline::wrote(offset, n);
+        }
+        written
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        // The metadata of a block device has a length of 0, so seek instead:
+        let mut file = self;
+        file.seek(io::SeekFrom::End(0))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        idle::io_starting();
+        let synced = self.sync_data();
+        idle::io_finished();
+        synced
+    }
+
+    fn drop_cache(&self, offset: u64, len: u64) -> io::Result<()> {
+        crate::drop_cache(self, offset, len)
+    }
+}
+
+/// A target that keeps its data in memory, and can't grow.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct MemoryTarget {
+    data: Mutex<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl MemoryTarget {
+    /// A target of `len` zero bytes.
+    pub(crate) fn new(len: usize) -> Self {
+        Self {
+            data: Mutex::new(vec![0; len]),
+        }
+    }
+
+    /// Runs `f` on the target's data, e.g. to corrupt it.
+    pub(crate) fn with_data<T>(&self, f: impl FnOnce(&mut [u8]) -> T) -> T {
+        f(&mut self.data.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+impl Target for MemoryTarget {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut data = self.data.lock().unwrap();
+        let start = offset as usize;
+        if start >= data.len() && !buf.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+        let n = buf.len().min(data.len() - start);
+        data[start..start + n].copy_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.lock().unwrap().len() as u64)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads or writes a [Target] sequentially, from some offset on.
+#[derive(Debug)]
+pub(crate) struct Cursor<'a> {
+    target: &'a dyn Target,
+    offset: u64,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(target: &'a dyn Target, offset: u64) -> Self {
+        Self { target, offset }
+    }
+}
+
+impl io::Read for Cursor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.target.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl io::Write for Cursor<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.target.write_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        read_test::read_back_from,
+        write_test::{write_shuffled_to, write_to},
+    };
+    use tracing_test::traced_test;
+
+    #[traced_test]
+    #[test]
+    fn runs_in_memory() {
+        let target = MemoryTarget::new(65536);
+        let (written, _) =
+            write_to(&target, 4096, None, 1.into(), 0, None, None).expect("No io errors");
+        assert_eq!(written, 65536);
+        let (read, result) =
+            read_back_from(&target, 4096, None, 1.into(), None, None, None).expect("No io errors");
+        assert_eq!(read, 65536);
+        assert!(result.is_ok());
+
+        target.with_data(|data| data[8192 + 5] ^= 1);
+        let (_, result) =
+            read_back_from(&target, 4096, None, 1.into(), None, None, None).expect("No io errors");
+        assert_eq!(result.unwrap_err().offsets, vec![8192]);
+
+        write_shuffled_to(&target, 4096, None, 2.into(), None).expect("No io errors");
+        let (_, result) =
+            read_back_from(&target, 4096, None, 2.into(), None, None, None).expect("No io errors");
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,57 @@
+//! Helpers shared between tests.
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// A file or directory in the temp directory that is removed when
+/// dropped, even if the test that uses it fails.
+#[derive(Debug)]
+pub(crate) struct TempFile(PathBuf);
+
+impl Deref for TempFile {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempFile {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // The test may have removed it already:
+        if fs::remove_file(&self.0).is_err() {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("disk-spinner-test-{}-{}", std::process::id(), name))
+}
+
+/// Creates a sparse file of the given length in the temp directory.
+pub(crate) fn sparse_file(name: &str, len: u64) -> TempFile {
+    let path = temp_path(name);
+    fs::File::create(&path)
+        .and_then(|f| f.set_len(len))
+        .expect("Creating sparse test file");
+    TempFile(path)
+}
+
+/// Creates an empty directory in the temp directory, for tests that need
+/// to create files (or sockets) of their own.
+pub(crate) fn temp_dir(name: &str) -> TempFile {
+    let path = temp_path(name);
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir(&path).expect("Creating test directory");
+    TempFile(path)
+}
@@ -0,0 +1,168 @@
+//! Parsing byte sizes with human-readable units on the command line.
+//!
+//! Sizes are a number followed by an optional unit. As with `dd`, units
+//! are binary unless they end in "B" without an "i":
+//!
+//! * `K`, `KiB` = 1024; `KB` = 1000
+//! * `M`, `MiB` = 1024²; `MB` = 1000²
+//! * `G`, `GiB` = 1024³; `GB` = 1000³
+//! * `T`, `TiB` = 1024⁴; `TB` = 1000⁴
+//!
+//! Units are case-insensitive, and a bare number (or `B`) means bytes.
+//!
+//! Durations are a number followed by `s`, `m`, `h` or `d`, and a bare
+//! number means seconds. Percentages are a number, with an optional `%`.
+//! CPU lists are comma-separated CPUs and ranges, like `0-3,8`.
+
+use std::time::Duration;
+
+/// Parses a size in bytes, e.g. `4K`, `1MiB` or `2GB`.
+pub(crate) fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    if number.is_empty() {
+        return Err(format!("{:?} does not start with a number", s));
+    }
+    let number: u64 = number
+        .parse()
+        .map_err(|e| format!("invalid number {:?}: {}", number, e))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => {
+            return Err(format!(
+                "unknown unit {:?} (use e.g. K, KiB, KB, M, MiB, MB, G, GiB, GB)",
+                unit
+            ))
+        }
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("{:?} is too large", s))
+}
+
+/// Parses a size in bytes that needs to fit in memory, like a buffer size.
+pub(crate) fn parse_buffer_size(s: &str) -> Result<usize, String> {
+    let size = parse_bytes(s)?;
+    size.try_into()
+        .map_err(|_| format!("{:?} is too large for a buffer", s))
+}
+
+/// Parses a duration, e.g. `90s`, `30m` or `12h`.
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|e| format!("invalid number {:?}: {}", number, e))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit {:?} (use s, m, h or d)", unit)),
+    };
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("{:?} is too long", s))
+}
+
+/// Parses a percentage above 0 and up to 100, e.g. `1`, `0.5` or `5%`.
+pub(crate) fn parse_percent(s: &str) -> Result<f64, String> {
+    let number = s.trim().trim_end_matches('%');
+    let percent: f64 = number
+        .parse()
+        .map_err(|e| format!("invalid number {:?}: {}", number, e))?;
+    if !(percent > 0.0 && percent <= 100.0) {
+        return Err(format!("{:?} is not above 0 and up to 100", s));
+    }
+    Ok(percent)
+}
+
+/// Parses a CPU list in the sysfs format, like "0-3,8-11,16".
+pub(crate) fn parse_cpu_list(list: &str) -> Result<Vec<usize>, String> {
+    let parse = |cpu: &str| {
+        cpu.parse::<usize>()
+            .map_err(|e| format!("invalid CPU {:?}: {}", cpu, e))
+    };
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => cpus.extend(parse(first)?..=parse(last)?),
+            None => cpus.push(parse(range)?),
+        }
+    }
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_bytes, parse_cpu_list, parse_duration, parse_percent};
+    use std::time::Duration;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_bytes("4096"), Ok(4096));
+        assert_eq!(parse_bytes("512B"), Ok(512));
+        assert_eq!(parse_bytes("4K"), Ok(4096));
+        assert_eq!(parse_bytes("4k"), Ok(4096));
+        assert_eq!(parse_bytes("1MiB"), Ok(1024 * 1024));
+        assert_eq!(parse_bytes("2GB"), Ok(2_000_000_000));
+        assert_eq!(parse_bytes("18TiB"), Ok(18 << 40));
+        assert_eq!(parse_bytes("1 M"), Ok(1024 * 1024));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(parse_bytes("").is_err());
+        assert!(parse_bytes("K").is_err());
+        assert!(parse_bytes("-1").is_err());
+        assert!(parse_bytes("1.5G").is_err());
+        assert!(parse_bytes("4 bananas").is_err());
+        assert!(parse_bytes("99999999999T").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("12H"), Ok(Duration::from_secs(12 * 3600)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::from_secs(2 * 86400)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("1.5h").is_err());
+    }
+
+    #[test]
+    fn parses_percentages() {
+        assert_eq!(parse_percent("1"), Ok(1.0));
+        assert_eq!(parse_percent("0.5%"), Ok(0.5));
+        assert_eq!(parse_percent("100"), Ok(100.0));
+        assert!(parse_percent("0").is_err());
+        assert!(parse_percent("101").is_err());
+        assert!(parse_percent("NaN").is_err());
+        assert!(parse_percent("").is_err());
+    }
+
+    #[test]
+    fn parses_cpu_lists() {
+        assert_eq!(parse_cpu_list("0\n").unwrap(), vec![0]);
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11").unwrap(),
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(parse_cpu_list("").unwrap(), Vec::<usize>::new());
+        assert!(parse_cpu_list("0-x").is_err());
+    }
+}
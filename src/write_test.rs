@@ -1,43 +1,365 @@
 //! Running the "write" portion of the test.
 
-use crate::{crypto::GarbageGenerator, PROGRESS_STYLE};
+use crate::{
+    checkpoint::{Checkpoint, CheckpointWriter},
+    crypto::{GarbageGenerator, Seed},
+    device_error::{DeviceIoError, Operation},
+    events::ProgressEvents,
+    idle,
+    order::BlockPermutation,
+    target::{Cursor, Target},
+    PROGRESS_STYLE,
+};
 use anyhow::Context;
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
 use std::{
     fs::OpenOptions,
-    io::{self, BufReader, Seek},
-    path::Path,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
 };
-use tracing::{info_span, Span};
+use tracing::{info_span, warn};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
-#[tracing::instrument(skip(buffer_size, seed))]
-pub(crate) fn write(dev_path: &Path, buffer_size: usize, seed: u64) -> anyhow::Result<()> {
-    let mut out = OpenOptions::new()
+/// Writes garbage to the device until it is full, or until `capacity`
+/// bytes are written if a capacity is given.
+///
+/// Writing begins at the offset `start`, which is non-zero when resuming
+/// an earlier run. If a `checkpoint` path is given, progress is saved
+/// there periodically. With `sanity_reads`, that percentage of the
+/// written buffers is read back right away (see [SanityReads]). Returns
+/// the number of bytes written, and the [SanityFailure] that stopped the
+/// write early, if any.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity, seed, checkpoint, sanity_reads), fields(device = ?dev_path))]
+pub(crate) fn write(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+    start: u64,
+    checkpoint: Option<PathBuf>,
+    sanity_reads: Option<f64>,
+) -> anyhow::Result<(u64, Option<SanityFailure>)> {
+    let out = OpenOptions::new()
+        .read(sanity_reads.is_some())
         .write(true)
         .open(dev_path)
         .with_context(|| format!("Opening the device {:?} for writing", dev_path))?;
-    let capacity = out.seek(io::SeekFrom::End(0))?;
-    out.seek(io::SeekFrom::Start(0))?;
+    write_to(
+        &out,
+        buffer_size,
+        capacity,
+        seed,
+        start,
+        checkpoint,
+        sanity_reads,
+    )
+}
+
+/// Like [write], but to any [Target].
+pub(crate) fn write_to(
+    target: &dyn Target,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+    start: u64,
+    checkpoint: Option<PathBuf>,
+    sanity_reads: Option<f64>,
+) -> anyhow::Result<(u64, Option<SanityFailure>)> {
+    // Without an explicit capacity, keep going until the device runs out:
+    let limit = capacity.unwrap_or(u64::MAX);
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => target.len()?,
+    };
 
     let bar_span = info_span!("writing");
     bar_span.pb_set_style(&PROGRESS_STYLE);
     bar_span.pb_set_length(capacity);
+    bar_span.pb_set_position(start);
     let _bar_span_handle = bar_span.enter();
 
-    let generator = GarbageGenerator::new(buffer_size, seed, |read| {
-        Span::current().pb_inc(read);
+    let events = ProgressEvents::new("write", capacity, start);
+    let _idle_watch = idle::Watch::start("write");
+    let mut generator = GarbageGenerator::new(buffer_size, seed, |read| {
+        events.inc(read);
     });
-    let mut generator = BufReader::new(generator);
-    match io::copy(&mut generator, &mut out) {
-        Ok(_) => Ok(()),
+    generator.seek(start);
+    let mut generator =
+        BufReader::with_capacity(buffer_size, generator).take(limit.saturating_sub(start));
+    let state = Checkpoint {
+        seed,
+        capacity,
+        buffer_size,
+        offset: start,
+        device_capacity: target.len().ok(),
+    };
+    let mut out = CheckpointWriter::new(target, checkpoint, state);
+    let mut sanity_reads = sanity_reads.map(|percent| SanityReads::new(target, seed, percent));
+    let copied = match &mut sanity_reads {
+        Some(sanity_reads) => io::copy(&mut generator, &mut sanity_reads.wrap(&mut out)),
+        None => io::copy(&mut generator, &mut out),
+    };
+    if let Some(offset) = sanity_reads.and_then(|s| s.mismatch) {
+        warn!(
+            event = "bad_block",
+            offset, "Did not read back as written right after writing it (--sanity-reads)"
+        );
+        return Ok((out.offset() - start, Some(SanityFailure { offset })));
+    }
+    match copied {
+        Ok(_) => {}
         Err(e) if e.raw_os_error() == Some(28) => {
-            // "disk full", meaning we're done:
-            Ok(())
+            // "disk full", meaning we're done.
         }
         Err(e) if e.kind() == io::ErrorKind::WriteZero => {
-            // "disk full" on macOS, meaning we're done:
+            // "disk full" on macOS, meaning we're done.
+        }
+        Err(e) => return Err(DeviceIoError::new(Operation::Write, out.offset(), e).into()),
+    }
+    if limit != u64::MAX && out.offset() < limit {
+        anyhow::bail!(
+            "The device filled up after {} bytes, before the capacity of {} bytes was written",
+            out.offset(),
+            limit
+        );
+    }
+    out.save().context("Saving the final checkpoint")?;
+    Ok((out.offset() - start, None))
+}
+
+/// A buffer that did not read back as written right after writing it,
+/// which stops the write test early (see [SanityReads]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SanityFailure {
+    /// The offset at which the bad buffer starts.
+    pub offset: u64,
+}
+
+/// Reads back a random fraction of the buffers written to a target, right
+/// after writing them, failing on the first that doesn't match.
+///
+/// This catches grossly broken devices early, but the reads may well be
+/// served from a cache, so it is NOT a substitute for the read-back test.
+pub(crate) struct SanityReads<'a> {
+    target: &'a dyn Target,
+    rng: ChaCha8Rng,
+    fraction: f64,
+    buf: Vec<u8>,
+    /// The offset of the first buffer that didn't read back as written.
+    mismatch: Option<u64>,
+}
+
+impl<'a> SanityReads<'a> {
+    pub(crate) fn new(target: &'a dyn Target, seed: Seed, percent: f64) -> Self {
+        Self {
+            target,
+            rng: seed.rng(),
+            fraction: percent / 100.0,
+            buf: Vec::new(),
+            mismatch: None,
+        }
+    }
+
+    fn wrap<'s, 'w>(
+        &'s mut self,
+        out: &'s mut CheckpointWriter<'w>,
+    ) -> SanityReadsWriter<'s, 'a, 'w> {
+        SanityReadsWriter { reads: self, out }
+    }
+}
+
+/// The [io::Write] that does the [SanityReads] for a [CheckpointWriter].
+struct SanityReadsWriter<'s, 'a, 'w> {
+    reads: &'s mut SanityReads<'a>,
+    out: &'s mut CheckpointWriter<'w>,
+}
+
+impl Write for SanityReadsWriter<'_, '_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let offset = self.out.offset();
+        let written = self.out.write(buf)?;
+        let reads = &mut *self.reads;
+        if !reads.rng.gen_bool(reads.fraction) {
+            return Ok(written);
+        }
+        reads.buf.resize(written, 0);
+        Cursor::new(reads.target, offset)
+            .read_exact(&mut reads.buf)
+            .map_err(|e| io::Error::new(e.kind(), format!("sanity read: {}", e)))?;
+        if reads.buf != buf[..written] {
+            reads.mismatch = Some(offset);
+            return Err(io::Error::other("sanity read mismatch"));
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Writes garbage to every block of the device, in a shuffled order
+/// derived from the seed.
+///
+/// Each block gets the same data as it would with [write], so the
+/// device can be read back sequentially afterwards. If `max_jump` is
+/// given, consecutive writes are at most that many bytes apart. Returns
+/// the number of bytes written.
+#[tracing::instrument(skip(dev_path, buffer_size, capacity, seed, max_jump), fields(device = ?dev_path))]
+pub(crate) fn write_shuffled(
+    dev_path: &Path,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+    max_jump: Option<u64>,
+) -> anyhow::Result<u64> {
+    let out = OpenOptions::new()
+        .write(true)
+        .open(dev_path)
+        .with_context(|| format!("Opening the device {:?} for writing", dev_path))?;
+    write_shuffled_to(&out, buffer_size, capacity, seed, max_jump)
+        .with_context(|| format!("Writing to {:?} in random order", dev_path))
+}
+
+/// Like [write_shuffled], but to any [Target].
+pub(crate) fn write_shuffled_to(
+    out: &dyn Target,
+    buffer_size: usize,
+    capacity: Option<u64>,
+    seed: Seed,
+    max_jump: Option<u64>,
+) -> anyhow::Result<u64> {
+    let capacity = match capacity {
+        Some(capacity) => capacity,
+        None => out.len()?,
+    };
+    if capacity == 0 {
+        anyhow::bail!("Could not determine the capacity to shuffle its blocks - pass --capacity.");
+    }
+    let block_size = buffer_size as u64;
+    if max_jump.is_some_and(|max_jump| max_jump < block_size) {
+        anyhow::bail!(
+            "--max-offset-jump must be at least the buffer size of {} bytes.",
+            block_size
+        );
+    }
+
+    let bar_span = info_span!("writing in random order");
+    bar_span.pb_set_style(&PROGRESS_STYLE);
+    bar_span.pb_set_length(capacity);
+    let _bar_span_handle = bar_span.enter();
+
+    let blocks = capacity.div_ceil(block_size);
+    let permutation = match max_jump {
+        // Blocks in neighbouring windows are less than two windows apart:
+        Some(max_jump) => {
+            BlockPermutation::windowed(blocks, (max_jump / block_size).div_ceil(2), seed)
+        }
+        None => BlockPermutation::new(blocks, seed),
+    };
+    let events = ProgressEvents::new("write", capacity, 0);
+    let _idle_watch = idle::Watch::start("write");
+    let mut generator = GarbageGenerator::new(buffer_size, seed, |_| {});
+    let mut buf = vec![0; buffer_size];
+    for i in 0..blocks {
+        let offset = permutation.nth(i) * block_size;
+        let buf = &mut buf[..(capacity - offset).min(block_size) as usize];
+        generator.seek(offset);
+        generator.fill(buf);
+        Cursor::new(out, offset)
+            .write_all(buf)
+            .map_err(|e| DeviceIoError::new(Operation::Write, offset, e))?;
+        events.inc(buf.len() as u64);
+    }
+    Ok(capacity)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write, write_shuffled, write_to, SanityFailure};
+    use crate::{
+        crypto::GarbageGenerator,
+        target::{MemoryTarget, Target},
+        test_util::sparse_file,
+    };
+    use std::{fs, io, io::Read};
+    use tracing_test::traced_test;
+
+    #[traced_test]
+    #[test]
+    fn writes_single_block() {
+        let path = sparse_file("write-single-block", 0);
+        write(&path, 4096, Some(4096), 1.into(), 0, None, None).expect("No io errors");
+        let written = fs::read(&path).unwrap();
+        assert_eq!(written.len(), 4096);
+
+        let mut expected = vec![0; 4096];
+        GarbageGenerator::new(4096, 1.into(), |_| {})
+            .read_exact(&mut expected)
+            .unwrap();
+        assert_eq!(written, expected);
+    }
+
+    #[traced_test]
+    #[test]
+    fn writes_partial_last_block() {
+        let path = sparse_file("write-partial-block", 0);
+        write(&path, 4096, Some(4096 + 512), 1.into(), 0, None, None).expect("No io errors");
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4096 + 512);
+    }
+
+    #[traced_test]
+    #[test]
+    fn shuffled_matches_sequential() {
+        let sequential = sparse_file("write-sequential", 0);
+        let shuffled = sparse_file("write-shuffled", 0);
+        let capacity = 4096 * 37 + 100;
+        write(&sequential, 4096, Some(capacity), 1.into(), 0, None, None).expect("No io errors");
+        write_shuffled(&shuffled, 4096, Some(capacity), 1.into(), None).expect("No io errors");
+        assert_eq!(fs::read(&sequential).unwrap(), fs::read(&shuffled).unwrap());
+        fs::write(&shuffled, vec![0; capacity as usize]).unwrap();
+        write_shuffled(&shuffled, 4096, Some(capacity), 1.into(), Some(4096 * 5))
+            .expect("No io errors");
+        assert_eq!(fs::read(&sequential).unwrap(), fs::read(&shuffled).unwrap());
+        assert!(write_shuffled(&shuffled, 4096, Some(capacity), 1.into(), Some(512)).is_err());
+    }
+
+    /// A fake drive that silently drops writes past `.1`.
+    #[derive(Debug)]
+    struct DropsWrites(MemoryTarget, u64);
+
+    impl Target for DropsWrites {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.0.read_at(buf, offset)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+            match offset >= self.1 {
+                true => Ok(buf.len()),
+                false => self.0.write_at(buf, offset),
+            }
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            self.0.len()
+        }
+
+        fn sync(&self) -> io::Result<()> {
             Ok(())
         }
-        Err(e) => anyhow::bail!("io Error {:?}: kind {:?}", e, e.kind()),
+    }
+
+    #[traced_test]
+    #[test]
+    fn does_sanity_reads() {
+        let good = MemoryTarget::new(65536);
+        write_to(&good, 4096, Some(65536), 1.into(), 0, None, Some(100.0)).expect("No io errors");
+        let fake = DropsWrites(MemoryTarget::new(65536), 32768);
+        let (_, result) = write_to(&fake, 4096, Some(65536), 1.into(), 0, None, Some(100.0))
+            .expect("No io errors");
+        assert_eq!(result, Some(SanityFailure { offset: 32768 }));
+        // Without sanity reads, only the read-back test notices:
+        write_to(&fake, 4096, Some(65536), 1.into(), 0, None, None).expect("No io errors");
     }
 }
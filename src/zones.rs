@@ -0,0 +1,93 @@
+//! Estimating where on the platters of a hard disk its bad blocks are.
+//!
+//! Hard disks usually map their lowest LBAs to the outer tracks and their
+//! highest to the inner ones, so the offset of a bad block says roughly
+//! how far from the spindle it is. This is an APPROXIMATION: it assumes a
+//! simple linear outer-to-inner layout, while real disks have zones of
+//! different densities, several platters and surfaces, and remapped
+//! sectors. It says nothing at all about solid-state media.
+//!
+//! Still, bad blocks piling up in one third of the disk point more at the
+//! media (or a head) in that area than at the disk as a whole.
+
+use serde::Serialize;
+use std::fmt;
+
+/// The smallest number of bad blocks worth calling "concentrated".
+const MIN_CONCENTRATED: u64 = 3;
+
+/// A third of the disk, by estimated distance from the spindle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Zone {
+    Outer,
+    Middle,
+    Inner,
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Zone::Outer => write!(f, "outer third"),
+            Zone::Middle => write!(f, "middle third"),
+            Zone::Inner => write!(f, "inner third"),
+        }
+    }
+}
+
+/// How the bad blocks of a device are spread over its (estimated) zones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct ZoneReport {
+    pub outer: u64,
+    pub middle: u64,
+    pub inner: u64,
+    /// The zone holding at least two thirds of the bad blocks, if any.
+    pub concentrated_in: Option<Zone>,
+}
+
+impl ZoneReport {
+    /// Spreads the offsets of bad blocks over the zones of a device of
+    /// `capacity` bytes. Returns None if there are no bad blocks.
+    pub(crate) fn of(capacity: u64, bad_offsets: &[u64]) -> Option<Self> {
+        if bad_offsets.is_empty() || capacity == 0 {
+            return None;
+        }
+        let mut counts = [0; 3];
+        for &offset in bad_offsets {
+            let zone = (offset as u128 * 3 / capacity as u128).min(2);
+            counts[zone as usize] += 1;
+        }
+        let total = bad_offsets.len() as u64;
+        let concentrated_in = [Zone::Outer, Zone::Middle, Zone::Inner]
+            .into_iter()
+            .zip(counts)
+            .find(|&(_, n)| total >= MIN_CONCENTRATED && n * 3 >= total * 2)
+            .map(|(zone, _)| zone);
+        Some(Self {
+            outer: counts[0],
+            middle: counts[1],
+            inner: counts[2],
+            concentrated_in,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Zone, ZoneReport};
+
+    #[test]
+    fn spreads_over_zones() {
+        assert_eq!(ZoneReport::of(300, &[]), None);
+
+        let inner = ZoneReport::of(300, &[10, 250, 260, 299]).unwrap();
+        assert_eq!((inner.outer, inner.middle, inner.inner), (1, 0, 3));
+        assert_eq!(inner.concentrated_in, Some(Zone::Inner));
+
+        let spread = ZoneReport::of(300, &[10, 150, 250]).unwrap();
+        assert_eq!(spread.concentrated_in, None);
+
+        let few = ZoneReport::of(300, &[10, 20]).unwrap();
+        assert_eq!(few.concentrated_in, None);
+    }
+}